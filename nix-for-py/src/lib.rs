@@ -102,7 +102,8 @@ mod nix_for_py {
       store_params: store_params.unwrap_or_default(),
       lookup_path: lookup_path.unwrap_or_default(),
       stack_size: stack_size.unwrap_or(64 * 1024 * 1024),
-      flake_settings: None
+      flake_settings: None,
+      ..NixSettings::default()
     };
     let eval_state = nix_settings.with_store(store)?;
     Ok(PyEvalState(Arc::new(Mutex::new(Box::leak(Box::new(eval_state))))))
@@ -36,18 +36,18 @@ impl PyEvalState {
     nix_term_to_py(py, term)
   }
 
-  // pub fn eval_flake(&self, py:Python<'_>, flake_path: &str) -> anyhow::Result<PyObject> {
-  //   let term = self.lock().eval_flake(flake_path)?;
-  //   nix_term_to_py(py, term)
-  // }
+  pub fn eval_flake(&self, py:Python<'_>, flake_path: &str) -> anyhow::Result<PyObject> {
+    let term = self.lock().eval_flake(flake_path)?;
+    nix_term_to_py(py, term)
+  }
 
   pub fn get_setting(&self, key: &str) -> Option<String> {
     self.lock().settings.get_setting(key)
   }
 
-  pub fn eval_attr_from_file(&self, file: std::path::PathBuf, accessor_path: Vec<String>) -> anyhow::Result<String> {
-    self.lock().eval_attr_from_file(file, accessor_path)
-      .map_err(|e| e.into())
+  pub fn eval_attr_from_file(&self, py: Python<'_>, file: std::path::PathBuf, accessor_path: Vec<String>) -> anyhow::Result<PyObject> {
+    let term = self.lock().eval_attr_from_file(file, accessor_path)?;
+    nix_term_to_py(py, term)
   }
 
   pub fn builtins(&self, py:Python<'_>) -> anyhow::Result<PyObject> {
@@ -0,0 +1,73 @@
+//! Compares the time of cold evaluations of the eval cache with each tracing backend,
+//! against evaluating the same file without the cache.
+//!
+//! Run with `cargo bench --features eval-cache --bench eval_cache_tracers`.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use nix_for_rust::eval::NixEvalState;
+use nix_for_rust::eval_cache::{CacheLocation, EvalCacheSettings, TracerBackend};
+use nix_for_rust::settings::NixSettings;
+
+/// Number of files imported by the evaluated file.
+const IMPORTED_FILES: usize = 200;
+const RUNS: u32 = 10;
+
+/// Writes a chain of files importing each other, returning the first one.
+fn write_files(dir: &Path) -> Result<PathBuf> {
+  std::fs::create_dir_all(dir)?;
+  for i in 1..IMPORTED_FILES {
+    std::fs::write(dir.join(format!("{i}.nix")), format!("{i} + import ./{}.nix", i + 1))?;
+  }
+  std::fs::write(dir.join(format!("{IMPORTED_FILES}.nix")), "{ value = 0; }.value")?;
+  let main = dir.join("main.nix");
+  std::fs::write(&main, "{ sum = import ./1.nix; }")?;
+  Ok(main)
+}
+
+/// Average time of `f`, called after changing `main` so that nothing is cached.
+fn bench<F: FnMut() -> Result<()>>(main: &Path, mut f: F) -> Result<Duration> {
+  let mut total = Duration::ZERO;
+  for run in 0..RUNS {
+    std::fs::write(main, format!("# run {run} {:?}\n{{ sum = import ./1.nix; }}", Instant::now()))?;
+    let start = Instant::now();
+    f()?;
+    total += start.elapsed();
+  }
+  Ok(total / RUNS)
+}
+
+/// State caching evaluations in memory, to leave the cache of the user untouched.
+fn state(tracer: TracerBackend) -> Result<NixEvalState> {
+  let cache = EvalCacheSettings::new()
+    .with_tracer(tracer)
+    .with_location(CacheLocation::InMemory);
+  NixSettings::default()
+    .with_eval_cache(cache)
+    .with_default_store()
+}
+
+fn main() -> Result<()> {
+  let dir = tempfile::tempdir()?;
+  let main = write_files(dir.path())?;
+
+  let uncached = state(TracerBackend::default())?;
+  let duration = bench(&main, || {
+    uncached.eval_file(&main)?.get("sum")?;
+    Ok(())
+  })?;
+  println!("without cache: {duration:?}");
+
+  for tracer in [TracerBackend::Ptrace, TracerBackend::Seccomp] {
+    let state = state(tracer)?;
+    let duration = bench(&main, || {
+      state.eval_attr_from_file(&main, ["sum"])?;
+      Ok(())
+    })?;
+    println!("{tracer:?} tracer: {duration:?}");
+  }
+
+  Ok(())
+}
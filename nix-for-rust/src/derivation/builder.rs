@@ -0,0 +1,278 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use anyhow::{Context, Result};
+use crate::store::NixStore;
+use super::{Derivation, DerivationOutput, InputDrv};
+
+/// Length of the hash part of a store path's file name.
+const HASH_LEN: usize = 32;
+
+/// Outcome of running a derivation's builder with [`LocalBuilder`].
+#[derive(Debug)]
+pub struct LocalBuildResult {
+  pub exit_status: ExitStatus,
+  /// Interleaved stdout and stderr of the builder.
+  pub log: String,
+  /// Output names and their store paths, registered in the store only if the builder succeeded.
+  pub outputs: HashMap<String, String>,
+  /// Build directory, when it was kept with [`LocalBuilder::keep_build_dir`].
+  pub build_dir: Option<PathBuf>
+}
+
+impl LocalBuildResult {
+  pub fn success(&self) -> bool {
+    self.exit_status.success()
+  }
+}
+
+/// Builds derivations by running their builder directly, without a sandbox or a nix daemon,
+/// and registers their outputs in `store`, which is usually a chroot store like `local?root=/tmp/store`.
+///
+/// This is meant for tests of simple derivations whose inputs are already valid in `store`: since no
+/// mount namespace is used, the builder, its inputs and its outputs are all accessed through their
+/// real location in the filesystem (see [`NixStore::real_path`]), and store paths in the builder's
+/// arguments and environment are rewritten to those locations.
+pub struct LocalBuilder<'store> {
+  store: &'store NixStore,
+  keep_build_dir: bool,
+  extra_env: HashMap<String, String>
+}
+
+fn hash_part(path: &str) -> Option<&str> {
+  Path::new(path)
+    .file_name()?
+    .to_str()?
+    .get(..HASH_LEN)
+}
+
+/// Finds which of the `candidates` (keyed by their hash part) are referenced inside `path`.
+fn scan_references(path: &Path, candidates: &HashMap<&str, &str>, found: &mut HashSet<String>) -> Result<()> {
+  let metadata = std::fs::symlink_metadata(path)?;
+  let contents = if metadata.is_dir() {
+    for entry in std::fs::read_dir(path)? {
+      scan_references(&entry?.path(), candidates, found)?;
+    }
+    return Ok(());
+  } else if metadata.is_symlink() {
+    std::fs::read_link(path)?.into_os_string().into_encoded_bytes()
+  } else {
+    std::fs::read(path)?
+  };
+  for window in contents.windows(HASH_LEN) {
+    if let Some(reference) = std::str::from_utf8(window).ok().and_then(|hash| candidates.get(hash)) {
+      found.insert(reference.to_string());
+    }
+  }
+  Ok(())
+}
+
+impl<'store> LocalBuilder<'store> {
+  pub fn new(store: &'store NixStore) -> Self {
+    LocalBuilder { store, keep_build_dir: false, extra_env: HashMap::new() }
+  }
+
+  /// Don't delete the build directory after the builder exits.
+  pub fn keep_build_dir(mut self, keep: bool) -> Self {
+    self.keep_build_dir = keep;
+    self
+  }
+
+  /// Sets an extra environment variable for the builder, overriding the derivation's.
+  pub fn with_env(mut self, key: &str, val: &str) -> Self {
+    self.extra_env.insert(key.to_string(), val.to_string());
+    self
+  }
+
+  /// Paths of the derivation outputs.
+  fn output_paths(drv: &Derivation) -> Result<HashMap<String, String>> {
+    drv.outputs
+      .iter()
+      .map(|(name, output)| match output {
+        DerivationOutput::InputAddressed { path } | DerivationOutput::CAFixed { path, .. } => {
+          Ok((name.clone(), path.path.to_string_lossy().to_string()))
+        }
+        _ => anyhow::bail!("Output '{name}' of '{}' has no known path, which is not supported by the local builder", drv.name)
+      })
+      .collect()
+  }
+
+  /// Store paths the derivation depends on, which must all be valid in the store.
+  fn input_paths(&self, drv: &Derivation) -> Result<Vec<String>> {
+    let mut inputs: Vec<String> = drv.input_srcs.iter().cloned().collect();
+    for (drv_path, input) in drv.input_drvs.iter() {
+      let input_drv = self.store.parse_derivation(drv_path)?;
+      let outputs = Self::output_paths(&input_drv)?;
+      let InputDrv::Paths(names) = input else {
+        anyhow::bail!("Dynamic derivations are not supported by the local builder");
+      };
+      for name in names {
+        let path = outputs
+          .get(name)
+          .ok_or_else(|| anyhow::format_err!("Derivation '{drv_path}' has no output '{name}'"))?;
+        inputs.push(path.clone());
+      }
+    }
+    for input in inputs.iter() {
+      if !self.store.is_valid_path(&self.store.parse_path(input)?)? {
+        anyhow::bail!("Input '{input}' is not valid in the store");
+      }
+    }
+    Ok(inputs)
+  }
+
+  /// Maps each store path in `paths` to its real location, skipping the ones that are the same.
+  fn real_paths<'a, I: IntoIterator<Item=&'a String>>(&self, paths: I) -> Result<Vec<(String, String)>> {
+    let mut rewrites = Vec::new();
+    for path in paths {
+      let real_path = self.store.real_path(&self.store.parse_path(path)?)?.to_string_lossy().to_string();
+      if real_path != *path {
+        rewrites.push((path.clone(), real_path));
+      }
+    }
+    Ok(rewrites)
+  }
+
+  /// Replaces every store path in `rewrites` by its real location.
+  fn rewrite(s: &str, rewrites: &[(String, String)]) -> String {
+    rewrites
+      .iter()
+      .fold(s.to_string(), |s, (path, real_path)| s.replace(path, real_path))
+  }
+
+  fn builder_env(&self, drv: &Derivation, build_dir: &Path, outputs: &HashMap<String, String>, rewrites: &[(String, String)]) -> Result<HashMap<String, String>> {
+    let build_top = build_dir.to_string_lossy().to_string();
+    let mut env: HashMap<String, String> = [
+      ("PATH", "/path-not-set"),
+      ("HOME", "/homeless-shelter"),
+      ("NIX_BUILD_CORES", "1"),
+      ("NIX_LOG_FD", "2"),
+      ("TERM", "xterm-256color"),
+    ].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    for var in ["NIX_BUILD_TOP", "TMPDIR", "TEMPDIR", "TMP", "TEMP"] {
+      env.insert(var.to_string(), build_top.clone());
+    }
+    env.insert("NIX_STORE".to_string(), self.store.store_dir()?.to_string_lossy().to_string());
+    if let Some(attrs) = drv.structured_attrs()? {
+      attrs.write_attrs_files(build_dir, outputs)?;
+      env.insert("NIX_ATTRS_JSON_FILE".to_string(), format!("{build_top}/.attrs.json"));
+      env.insert("NIX_ATTRS_SH_FILE".to_string(), format!("{build_top}/.attrs.sh"));
+    } else {
+      let pass_as_file: HashSet<&str> = drv.env
+        .get("passAsFile")
+        .map(|names| names.split_whitespace().collect())
+        .unwrap_or_default();
+      for (key, val) in drv.env.iter() {
+        if pass_as_file.contains(key.as_str()) {
+          let file = build_dir.join(format!(".attr-{key}"));
+          std::fs::write(&file, Self::rewrite(val, rewrites))?;
+          env.insert(format!("{key}Path"), file.to_string_lossy().to_string());
+        } else {
+          env.insert(key.clone(), Self::rewrite(val, rewrites));
+        }
+      }
+      env.extend(outputs.iter().map(|(name, path)| (name.clone(), path.clone())));
+    }
+    env.extend(self.extra_env.clone());
+    Ok(env)
+  }
+
+  /// Registers the outputs as valid paths through `nix-store --register-validity`,
+  /// since the C API has no way of adding paths to a store.
+  fn register_outputs(&self, drv: &Derivation, inputs: &[String], outputs: &HashMap<String, String>) -> Result<()> {
+    let candidates: HashMap<&str, &str> = inputs
+      .iter()
+      .chain(outputs.values())
+      .filter_map(|path| hash_part(path).map(|hash| (hash, path.as_str())))
+      .collect();
+    let mut registration = String::new();
+    for path in outputs.values() {
+      let real_path = self.store.real_path(&self.store.parse_path(path)?)?;
+      let mut references = HashSet::new();
+      scan_references(&real_path, &candidates, &mut references)
+        .with_context(|| format!("while scanning references of '{path}'"))?;
+      registration.push_str(&format!("{path}\n\n{}\n", references.len()));
+      for reference in references {
+        registration.push_str(&reference);
+        registration.push('\n');
+      }
+    }
+    let mut child = Command::new("nix-store")
+      .args(["--store", &self.store.uri()?, "--register-validity"])
+      .stdin(Stdio::piped())
+      .spawn()
+      .context("while spawning nix-store")?;
+    // stdin is dropped before waiting, so that nix-store sees the end of its input.
+    let written = match child.stdin.take() {
+      Some(mut stdin) => stdin.write_all(registration.as_bytes()).context("while writing to nix-store"),
+      None => Err(anyhow::format_err!("Could not open nix-store stdin"))
+    };
+    let status = child.wait()?;
+    written?;
+    if !status.success() {
+      anyhow::bail!("Could not register the outputs of '{}'", drv.name);
+    }
+    Ok(())
+  }
+
+  /// Runs the derivation's builder in a temporary directory, registering its outputs if it succeeds.
+  pub fn build(&self, drv: &Derivation) -> Result<LocalBuildResult> {
+    let inputs = self.input_paths(drv)?;
+    let outputs = Self::output_paths(drv)?;
+    let rewrites = self.real_paths(outputs.values().chain(inputs.iter()))?;
+    // the builder writes to the real location of each output.
+    let real_outputs: HashMap<String, String> = outputs
+      .iter()
+      .map(|(name, path)| {
+        let path = self.store.parse_path(path)?;
+        if self.store.is_valid_path(&path)? {
+          anyhow::bail!("Output '{name}' of '{}' is already valid", drv.name);
+        }
+        let real_path = self.store.real_path(&path)?;
+        if std::fs::symlink_metadata(&real_path).is_ok() {
+          if real_path.is_dir() {
+            std::fs::remove_dir_all(&real_path)?;
+          } else {
+            std::fs::remove_file(&real_path)?;
+          }
+        }
+        Ok((name.clone(), real_path.to_string_lossy().to_string()))
+      })
+      .collect::<Result<_>>()?;
+    let build_dir = tempfile::Builder::new()
+      .prefix(&format!("nix-build-{}-", drv.name))
+      .tempdir()?;
+    let env = self.builder_env(drv, build_dir.path(), &real_outputs, &rewrites)?;
+    let builder = Self::rewrite(&drv.builder.to_string_lossy(), &rewrites);
+    let args: Vec<String> = drv.args.iter().map(|arg| Self::rewrite(arg, &rewrites)).collect();
+    let mut log_file = tempfile::tempfile()?;
+    let exit_status = Command::new(&builder)
+      .args(&args)
+      .env_clear()
+      .envs(env)
+      .current_dir(build_dir.path())
+      .stdin(Stdio::null())
+      .stdout(log_file.try_clone()?)
+      .stderr(log_file.try_clone()?)
+      .status()
+      .with_context(|| format!("while running builder '{}'", drv.builder.display()))?;
+    let mut log = String::new();
+    log_file.rewind()?;
+    log_file.read_to_string(&mut log)?;
+    let build_dir = if self.keep_build_dir {
+      Some(build_dir.into_path())
+    } else {
+      None
+    };
+    if exit_status.success() {
+      for (name, path) in real_outputs.iter() {
+        if std::fs::symlink_metadata(path).is_err() {
+          anyhow::bail!("Builder of '{}' failed to produce output '{name}' at '{path}'", drv.name);
+        }
+      }
+      self.register_outputs(drv, &inputs, &outputs)?;
+    }
+    Ok(LocalBuildResult { exit_status, log, outputs, build_dir })
+  }
+}
@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::store::NixStore;
+use super::{Derivation, DerivationOutput, InputDrv};
+
+/// A single difference between two values of a derivation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change<T> {
+  Added(T),
+  Removed(T),
+  Changed { old: T, new: T }
+}
+
+/// Difference between two input derivations that share the same name.
+#[derive(Debug)]
+pub enum InputDrvDiff {
+  Added(String),
+  Removed(String),
+  Changed {
+    old_path: String,
+    new_path: String,
+    /// Differences in the requested outputs of the input derivation.
+    outputs: Option<Change<InputDrv>>,
+    /// Recursive difference between both input derivations, or `None`
+    /// if this pair of derivations was already compared somewhere else in the tree.
+    diff: Option<Box<DerivationDiff>>
+  }
+}
+
+/// Tree of differences between two derivations.
+///
+/// Input derivations and sources are matched by their name (the store path without the hash),
+/// and input derivations that changed are compared recursively, so that the leaves of the tree
+/// point to the root cause of a rebuild. Several inputs can share a name, so they are kept
+/// as lists sorted by name rather than maps.
+#[derive(Debug, Default)]
+pub struct DerivationDiff {
+  pub name: Option<Change<String>>,
+  pub platform: Option<Change<String>>,
+  pub builder: Option<Change<PathBuf>>,
+  pub args: Option<Change<Vec<String>>>,
+  pub outputs: BTreeMap<String, Change<String>>,
+  pub input_srcs: Vec<(String, Change<String>)>,
+  pub input_drvs: Vec<(String, InputDrvDiff)>,
+  pub env: BTreeMap<String, Change<String>>
+}
+
+impl DerivationDiff {
+  /// Whether both derivations are equal.
+  pub fn is_empty(&self) -> bool {
+    self.name.is_none()
+      && self.platform.is_none()
+      && self.builder.is_none()
+      && self.args.is_none()
+      && self.outputs.is_empty()
+      && self.input_srcs.is_empty()
+      && self.input_drvs.is_empty()
+      && self.env.is_empty()
+  }
+
+  fn fmt_indented(&self, f: &mut std::fmt::Formatter<'_>, depth: usize) -> std::fmt::Result {
+    let indent = "  ".repeat(depth);
+    if let Some(change) = &self.name {
+      writeln!(f, "{indent}name: {}", fmt_change(change, |s| s.clone()))?;
+    }
+    if let Some(change) = &self.platform {
+      writeln!(f, "{indent}platform: {}", fmt_change(change, |s| s.clone()))?;
+    }
+    if let Some(change) = &self.builder {
+      writeln!(f, "{indent}builder: {}", fmt_change(change, |p| p.display().to_string()))?;
+    }
+    if let Some(change) = &self.args {
+      writeln!(f, "{indent}args: {}", fmt_change(change, |args| format!("{args:?}")))?;
+    }
+    for (name, change) in self.outputs.iter() {
+      writeln!(f, "{indent}output '{name}': {}", fmt_change(change, |s| s.clone()))?;
+    }
+    for (name, change) in self.input_srcs.iter() {
+      writeln!(f, "{indent}source '{name}': {}", fmt_change(change, |s| s.clone()))?;
+    }
+    for (name, change) in self.env.iter() {
+      writeln!(f, "{indent}env '{name}': {}", fmt_change(change, |s| s.clone()))?;
+    }
+    for (name, input) in self.input_drvs.iter() {
+      match input {
+        InputDrvDiff::Added(path) => writeln!(f, "{indent}input '{name}': added '{path}'")?,
+        InputDrvDiff::Removed(path) => writeln!(f, "{indent}input '{name}': removed '{path}'")?,
+        InputDrvDiff::Changed { old_path, new_path, outputs, diff } => {
+          writeln!(f, "{indent}input '{name}': '{old_path}' → '{new_path}'")?;
+          if let Some(change) = outputs {
+            writeln!(f, "{indent}  requested outputs: {}", fmt_change(change, |i| format!("{i:?}")))?;
+          }
+          match diff {
+            Some(diff) => diff.fmt_indented(f, depth + 1)?,
+            None => writeln!(f, "{indent}  (already compared above)")?
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Display for DerivationDiff {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.is_empty() {
+      return writeln!(f, "derivations are equal");
+    }
+    self.fmt_indented(f, 0)
+  }
+}
+
+fn fmt_change<T, F: Fn(&T) -> String>(change: &Change<T>, show: F) -> String {
+  match change {
+    Change::Added(new) => format!("added '{}'", show(new)),
+    Change::Removed(old) => format!("removed '{}'", show(old)),
+    Change::Changed { old, new } => format!("'{}' → '{}'", show(old), show(new))
+  }
+}
+
+fn compare<T: PartialEq + Clone>(old: &T, new: &T) -> Option<Change<T>> {
+  (old != new).then(|| Change::Changed { old: old.clone(), new: new.clone() })
+}
+
+fn compare_maps<K, V>(old: &HashMap<K, V>, new: &HashMap<K, V>) -> BTreeMap<K, Change<V>>
+where K: Ord + Clone + std::hash::Hash, V: PartialEq + Clone {
+  let mut changes = BTreeMap::new();
+  for (key, old_val) in old.iter() {
+    match new.get(key) {
+      None => { changes.insert(key.clone(), Change::Removed(old_val.clone())); },
+      Some(new_val) => if let Some(change) = compare(old_val, new_val) {
+        changes.insert(key.clone(), change);
+      }
+    }
+  }
+  for (key, new_val) in new.iter() {
+    if !old.contains_key(key) {
+      changes.insert(key.clone(), Change::Added(new_val.clone()));
+    }
+  }
+  changes
+}
+
+/// Name of a store path, that is, its file name without the hash part.
+fn store_path_name(path: &str) -> String {
+  let file_name = Path::new(path)
+    .file_name()
+    .map(|f| f.to_string_lossy().to_string())
+    .unwrap_or_else(|| path.to_string());
+  match file_name.split_once('-') {
+    Some((_hash, name)) => name.to_string(),
+    None => file_name
+  }
+}
+
+/// Changes between the store paths `old` and `new`, matched by name. Paths present in both are unchanged.
+/// When several paths that changed share a name, they are paired in sorted order, and the leftover ones
+/// are reported as added or removed.
+fn compare_by_name<'a, I: IntoIterator<Item=&'a String>>(old: I, new: I) -> Vec<(String, Change<String>)> {
+  let old: HashSet<&String> = old.into_iter().collect();
+  let new: HashSet<&String> = new.into_iter().collect();
+  let mut by_name: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+  for path in old.difference(&new) {
+    by_name.entry(store_path_name(path)).or_default().0.push(path.to_string());
+  }
+  for path in new.difference(&old) {
+    by_name.entry(store_path_name(path)).or_default().1.push(path.to_string());
+  }
+  let mut changes = Vec::new();
+  for (name, (mut removed, mut added)) in by_name {
+    removed.sort();
+    added.sort();
+    let paired = removed.len().min(added.len());
+    let extra_removed = removed.split_off(paired);
+    let extra_added = added.split_off(paired);
+    for (old, new) in removed.into_iter().zip(added) {
+      changes.push((name.clone(), Change::Changed { old, new }));
+    }
+    changes.extend(extra_removed.into_iter().map(|old| (name.clone(), Change::Removed(old))));
+    changes.extend(extra_added.into_iter().map(|new| (name.clone(), Change::Added(new))));
+  }
+  changes
+}
+
+fn output_repr(output: &DerivationOutput) -> String {
+  match output {
+    DerivationOutput::Deferred => "<deferred>".to_string(),
+    DerivationOutput::InputAddressed { path } => path.path.display().to_string(),
+    DerivationOutput::Impure { method, hash_algo } => format!("<impure {method:?}:{hash_algo:?}>"),
+    DerivationOutput::CAFixed { method, hash_algo, hash, .. } => format!("<fixed {method:?}:{hash_algo:?}:{hash}>"),
+    DerivationOutput::CAFloating { method, hash_algo } => format!("<floating {method:?}:{hash_algo:?}>"),
+  }
+}
+
+fn diff_rec(a: &Derivation, b: &Derivation, store: &NixStore, visited: &mut HashSet<(String, String)>) -> Result<DerivationDiff> {
+  let outputs = |drv: &Derivation| drv.outputs
+    .iter()
+    .map(|(name, out)| (name.clone(), output_repr(out)))
+    .collect::<HashMap<_, _>>();
+  let mut diff = DerivationDiff {
+    name: compare(&a.name, &b.name),
+    platform: compare(&a.platform, &b.platform),
+    builder: compare(&a.builder, &b.builder),
+    args: compare(&a.args, &b.args),
+    outputs: compare_maps(&outputs(a), &outputs(b)),
+    input_srcs: compare_by_name(&a.input_srcs, &b.input_srcs),
+    input_drvs: Vec::new(),
+    env: compare_maps(&a.env, &b.env),
+  };
+  for (name, change) in compare_by_name(a.input_drvs.keys(), b.input_drvs.keys()) {
+    let input_diff = match change {
+      Change::Added(path) => InputDrvDiff::Added(path),
+      Change::Removed(path) => InputDrvDiff::Removed(path),
+      Change::Changed { old, new } => {
+        let outputs = compare(&a.input_drvs[&old], &b.input_drvs[&new]);
+        let diff = if visited.insert((old.clone(), new.clone())) {
+          let old_drv = store.parse_derivation(&old)?;
+          let new_drv = store.parse_derivation(&new)?;
+          Some(Box::new(diff_rec(&old_drv, &new_drv, store, visited)?))
+        } else {
+          None
+        };
+        InputDrvDiff::Changed { old_path: old, new_path: new, outputs, diff }
+      }
+    };
+    diff.input_drvs.push((name, input_diff));
+  }
+  // Same input derivation path, but requesting different outputs from it.
+  for (path, old_outputs) in a.input_drvs.iter() {
+    if let Some(new_outputs) = b.input_drvs.get(path) {
+      if let Some(outputs) = compare(old_outputs, new_outputs) {
+        diff.input_drvs.push((store_path_name(path), InputDrvDiff::Changed {
+          old_path: path.clone(),
+          new_path: path.clone(),
+          outputs: Some(outputs),
+          diff: Some(Box::default())
+        }));
+      }
+    }
+  }
+  diff.input_drvs.sort_by(|(a, _), (b, _)| a.cmp(b));
+  Ok(diff)
+}
+
+/// Compares two parsed derivations, recursing through their input derivations
+/// to find the root cause of their differences.
+pub fn diff(a: &Derivation, b: &Derivation, store: &NixStore) -> Result<DerivationDiff> {
+  diff_rec(a, b, store, &mut HashSet::new())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn paths(paths: &[&str]) -> Vec<String> {
+    paths.iter().map(|p| p.to_string()).collect()
+  }
+
+  #[test]
+  fn inputs_sharing_a_name_are_all_compared() {
+    let old = paths(&["/nix/store/aaa-source", "/nix/store/bbb-source", "/nix/store/ccc-hook.drv"]);
+    let new = paths(&["/nix/store/aaa-source", "/nix/store/ddd-source", "/nix/store/eee-source"]);
+    assert_eq!(compare_by_name(&old, &new), vec![
+      ("hook.drv".to_string(), Change::Removed("/nix/store/ccc-hook.drv".to_string())),
+      ("source".to_string(), Change::Changed {
+        old: "/nix/store/bbb-source".to_string(),
+        new: "/nix/store/ddd-source".to_string()
+      }),
+      ("source".to_string(), Change::Added("/nix/store/eee-source".to_string())),
+    ]);
+  }
+}
@@ -13,9 +13,20 @@ use nom::character::complete::{char, none_of};
 use nom::sequence::delimited;
 use nom::{Finish, IResult, Parser};
 
+mod diff;
+pub use diff::{diff, Change, DerivationDiff, InputDrvDiff};
+mod structured_attrs;
+pub use structured_attrs::{OutputChecks, StructuredAttrs};
+mod plan;
+pub use plan::{BuildPlan, BuildStatus, DerivationGraph, DerivationNode, NodeOutput};
+#[cfg(feature="local-builder")]
+mod builder;
+#[cfg(feature="local-builder")]
+pub use builder::{LocalBuildResult, LocalBuilder};
+
 type ParseRes<'s, T> = IResult<&'s str, T, VerboseError<&'s str>>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HashAlgorithm {
   Md5,
   Sha1,
@@ -33,9 +44,19 @@ impl HashAlgorithm {
       _ => None
     }
   }
+
+  /// Name of the algorithm as used by `outputHashAlgo`.
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      HashAlgorithm::Md5 => "md5",
+      HashAlgorithm::Sha1 => "sha1",
+      HashAlgorithm::Sha256 => "sha256",
+      HashAlgorithm::Sha512 => "sha512",
+    }
+  }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ContentAddressedMethod {
   NixArchive,
   Git,
@@ -54,7 +75,17 @@ impl ContentAddressedMethod {
     } else {
       (s, ContentAddressedMethod::Flat)
     }
-  } 
+  }
+
+  /// Name of the method as used by `outputHashMode`.
+  pub fn as_output_hash_mode(&self) -> &'static str {
+    match self {
+      ContentAddressedMethod::NixArchive => "recursive",
+      ContentAddressedMethod::Git => "git",
+      ContentAddressedMethod::Text => "text",
+      ContentAddressedMethod::Flat => "flat",
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -68,6 +99,7 @@ pub enum DerivationOutput<'store> {
     hash_algo: HashAlgorithm
   },
   CAFixed {
+    path: NixStorePath<'store>,
     method: ContentAddressedMethod,
     hash_algo: HashAlgorithm,
     hash: String
@@ -78,7 +110,7 @@ pub enum DerivationOutput<'store> {
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum InputDrv {
   Paths(HashSet<String>),
   Map(HashMap<String, InputDrv>),
@@ -129,7 +161,8 @@ impl<'store> DerivationOutput<'store> {
         return Ok(DerivationOutput::Impure { method, hash_algo });
       } else if !hash.is_empty() {
         // TODO: validate path
-        return Ok(DerivationOutput::CAFixed { method, hash_algo, hash: hash.to_string()});
+        let path = store.parse_path(path)?;
+        return Ok(DerivationOutput::CAFixed { path, method, hash_algo, hash: hash.to_string()});
       } else {
         if !path.is_empty() {
           anyhow::bail!("Impure derivation output should not specify output path");
@@ -246,7 +279,7 @@ fn parse_derivation<'store, 'src>(store: &'store NixStore, name: String, input:
 impl NixStore {
   pub fn parse_derivation(&self, drv_path: &str) -> Result<Derivation> {
     let drv_path = self.parse_path(drv_path)?;
-    let content = std::fs::read_to_string(&drv_path.path)?;
+    let content = std::fs::read_to_string(self.real_path(&drv_path)?)?;
     let drv_name = drv_path.name()?;
     let name = drv_name
       .strip_suffix(".drv")
@@ -258,20 +291,111 @@ impl NixStore {
   }
 }
 
-impl<'store, 'state> ToNix<'state> for Derivation<'store> {
-  fn to_nix(self, eval_state: &'state NixEvalState) -> NixResult<NixTerm<'state>> {
-    let mut args: HashMap<&str, NixTerm> = self.env
+/// Nix function that calls `builtins.derivation` with the attributes of a parsed derivation.
+///
+/// Strings coming from a `.drv` file have no context, so the inputs of the
+/// original derivation are re-attached to the `builder` attribute through `builtins.appendContext`.
+/// Derivations with `__structuredAttrs` keep their attributes in the `__json` env variable.
+const REBUILD_DERIVATION: &str = r#"
+env: args: context:
+  let
+    attrs = if env ? __json
+      then builtins.fromJSON env.__json // { __structuredAttrs = true; }
+      else env;
+  in builtins.derivation (attrs // {
+    inherit args;
+    builder = builtins.appendContext attrs.builder context;
+  })
+"#;
+
+/// Env variables that `builtins.derivation` reads as booleans.
+const BOOLEAN_ATTRS: [&str; 3] = ["__structuredAttrs", "__contentAddressed", "__impure"];
+
+impl<'store> Derivation<'store> {
+  /// Whether the derivation was created with `__structuredAttrs = true`.
+  pub fn has_structured_attrs(&self) -> bool {
+    self.env.contains_key("__json")
+  }
+
+  /// String context that reproduces `input_drvs` and `input_srcs`, in the format of `builtins.getContext`.
+  fn context<'state>(&self, eval_state: &'state NixEvalState) -> NixResult<NixAttrSet<'state>> {
+    let drvs = self.input_drvs
+      .iter()
+      .map(|(path, input)| {
+        let outputs: Vec<&String> = match input {
+          InputDrv::Paths(outputs) => outputs.iter().collect(),
+          InputDrv::Map(map) => map.keys().collect(),
+        };
+        let outputs = outputs.into_iter().collect_to_nix::<NixList>(eval_state)?;
+        let elem = [("outputs", outputs)].into_iter().collect_to_nix::<NixAttrSet>(eval_state)?;
+        Ok((path.as_str(), NixTerm::from(elem)))
+      });
+    let srcs = self.input_srcs
+      .iter()
+      .map(|path| {
+        let elem = [("path", true)].into_iter().collect_to_nix::<NixAttrSet>(eval_state)?;
+        Ok((path.as_str(), NixTerm::from(elem)))
+      });
+    drvs.chain(srcs)
+      .collect::<NixResult<Vec<_>>>()?
+      .into_iter()
+      .collect_to_nix(eval_state)
+  }
+
+  /// Attributes given to `builtins.derivation` in the non-structured case.
+  fn env_attrs<'state>(&self, eval_state: &'state NixEvalState) -> NixResult<NixAttrSet<'state>> {
+    let mut env: HashMap<&str, NixTerm> = self.env
       .iter()
-      .map(|(k, v)| (k.as_str(), v.into()))
+      .filter(|(k, _)| !self.outputs.contains_key(*k))
+      .map(|(k, v)| {
+        let term = if BOOLEAN_ATTRS.contains(&k.as_str()) {
+          NixTerm::Bool(v == "1")
+        } else {
+          v.into()
+        };
+        (k.as_str(), term)
+      })
       .collect();
-    args.insert("name", self.name.into());
-    args.insert("builder", self.builder.into());
-    args.insert("system", self.platform.into());
-    args.insert("args", self.args.into_iter().collect_to_nix::<NixList>(eval_state)?.into());
-    args.insert("outputs", self.outputs.keys().collect_to_nix::<NixList>(eval_state)?.into());
-    let derivation = eval_state.eval_string("builtins.derivation", std::env::current_dir()
-      .expect("Could not get cwd"))
-      .expect("builtins.derivation should never fail");
-    derivation.call_with(args.into_iter().collect_to_nix::<NixAttrSet>(eval_state)?)
+    if let Some(outputs) = self.env.get("outputs") {
+      let outputs: Vec<&str> = outputs.split_whitespace().collect();
+      let outputs = outputs.into_iter().collect_to_nix::<NixList>(eval_state)?;
+      env.insert("outputs", outputs.into());
+    }
+    let content_addressed = self.outputs
+      .values()
+      .find_map(|output| match output {
+        DerivationOutput::CAFixed { method, hash_algo, hash, .. } => Some((method, hash_algo, Some(hash))),
+        DerivationOutput::CAFloating { method, hash_algo }
+        | DerivationOutput::Impure { method, hash_algo } => Some((method, hash_algo, None)),
+        _ => None
+      });
+    if let Some((method, hash_algo, hash)) = content_addressed {
+      env.entry("outputHashMode").or_insert(method.as_output_hash_mode().into());
+      env.entry("outputHashAlgo").or_insert(hash_algo.as_str().into());
+      if let Some(hash) = hash {
+        env.entry("outputHash").or_insert(hash.into());
+      }
+    }
+    env.entry("name").or_insert(self.name.as_str().into());
+    env.entry("system").or_insert(self.platform.as_str().into());
+    env.entry("builder").or_insert(self.builder.to_string_lossy().as_ref().into());
+    env.into_iter().collect_to_nix(eval_state)
+  }
+}
+
+impl<'store, 'state> ToNix<'state> for Derivation<'store> {
+  fn to_nix(self, eval_state: &'state NixEvalState) -> NixResult<NixTerm<'state>> {
+    let env = if self.has_structured_attrs() {
+      [("__json", &self.env["__json"])].into_iter().collect_to_nix::<NixAttrSet>(eval_state)?
+    } else {
+      self.env_attrs(eval_state)?
+    };
+    let args = self.args.iter().collect_to_nix::<NixList>(eval_state)?;
+    let context = self.context(eval_state)?;
+    eval_state
+      .eval_expr(REBUILD_DERIVATION, Path::new("/"))?
+      .call_with(env)?
+      .call_with(args)?
+      .call_with(context)
   }
 }
@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use anyhow::Result;
+use crate::store::NixStore;
+use super::{Derivation, DerivationOutput, InputDrv};
+
+/// Whether outputs of a derivation are already present in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStatus {
+  /// The outputs are valid paths in the store.
+  Valid,
+  /// At least one of the output paths is known and missing from the store.
+  Missing,
+  /// Output paths are not known in advance (content-addressed floating, impure or deferred outputs).
+  Unknown
+}
+
+/// An output of a [`DerivationNode`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeOutput {
+  /// Path of the output, when known.
+  pub path: Option<String>,
+  pub status: BuildStatus
+}
+
+/// A derivation in the [`DerivationGraph`].
+#[derive(Debug, Clone)]
+pub struct DerivationNode {
+  pub name: String,
+  /// Status of all the outputs together.
+  pub status: BuildStatus,
+  pub outputs: BTreeMap<String, NodeOutput>,
+  /// Paths of the input derivations, with the names of the outputs that are used.
+  pub inputs: BTreeMap<String, BTreeSet<String>>
+}
+
+/// Every derivation reachable from a top-level derivation through `input_drvs`, keyed by `.drv` path.
+#[derive(Debug, Clone, Default)]
+pub struct DerivationGraph {
+  pub root: String,
+  pub nodes: BTreeMap<String, DerivationNode>
+}
+
+/// What needs to happen for all the outputs of a top-level derivation to be realised.
+///
+/// Only the outputs of input derivations that are used are needed, and inputs of derivations whose needed outputs
+/// are valid aren't needed at all, so they don't show up in the plan, although they are still part of
+/// [`graph`][BuildPlan::graph].
+#[derive(Debug, Clone, Default)]
+pub struct BuildPlan {
+  /// Derivations with missing needed outputs, ordered so that inputs come before the derivations that use them.
+  pub to_build: Vec<String>,
+  /// Needed derivations whose needed outputs are already valid.
+  pub already_valid: Vec<String>,
+  /// Needed derivations whose needed output paths cannot be known before building.
+  pub unknown: Vec<String>,
+  pub graph: DerivationGraph
+}
+
+impl DerivationNode {
+  fn new(drv: &Derivation, store: &NixStore) -> Result<Self> {
+    let mut outputs = BTreeMap::new();
+    for (name, output) in drv.outputs.iter() {
+      let output = match output {
+        DerivationOutput::InputAddressed { path } | DerivationOutput::CAFixed { path, .. } => {
+          let status = if store.is_valid_path(path)? { BuildStatus::Valid } else { BuildStatus::Missing };
+          NodeOutput { path: Some(path.path.to_string_lossy().to_string()), status }
+        }
+        _ => NodeOutput { path: None, status: BuildStatus::Unknown }
+      };
+      outputs.insert(name.clone(), output);
+    }
+    let inputs = drv.input_drvs
+      .iter()
+      .map(|(path, input)| {
+        let outputs = match input {
+          InputDrv::Paths(outputs) => outputs.iter().cloned().collect(),
+          InputDrv::Map(map) => map.keys().cloned().collect()
+        };
+        (path.clone(), outputs)
+      })
+      .collect();
+    let mut node = DerivationNode { name: drv.name.clone(), status: BuildStatus::Valid, outputs, inputs };
+    node.status = node.status_of(node.outputs.keys());
+    Ok(node)
+  }
+
+  /// Combined status of the outputs `names`: missing if any is missing, otherwise unknown if any is unknown.
+  /// Outputs that the derivation doesn't have are considered missing.
+  pub fn status_of<'a, I: IntoIterator<Item=&'a String>>(&self, names: I) -> BuildStatus {
+    names
+      .into_iter()
+      .map(|name| self.outputs.get(name).map(|output| output.status).unwrap_or(BuildStatus::Missing))
+      .fold(BuildStatus::Valid, |status, output| match (status, output) {
+        (BuildStatus::Missing, _) | (_, BuildStatus::Missing) => BuildStatus::Missing,
+        (BuildStatus::Unknown, _) | (_, BuildStatus::Unknown) => BuildStatus::Unknown,
+        _ => BuildStatus::Valid
+      })
+  }
+}
+
+impl DerivationGraph {
+  /// Renders the graph in graphviz's DOT format, with edges going from a derivation to its inputs,
+  /// labelled with the outputs that are used.
+  pub fn to_dot(&self) -> String {
+    let mut dot = String::from("digraph derivations {\n  node [shape=box];\n");
+    for (path, node) in self.nodes.iter() {
+      let color = match node.status {
+        BuildStatus::Valid => "green",
+        BuildStatus::Missing => "red",
+        BuildStatus::Unknown => "gray",
+      };
+      dot.push_str(&format!("  {path:?} [label={:?}, color={color}];\n", node.name));
+    }
+    for (path, node) in self.nodes.iter() {
+      for (input, outputs) in node.inputs.iter() {
+        let outputs = outputs.iter().map(String::as_str).collect::<Vec<_>>().join(",");
+        dot.push_str(&format!("  {path:?} -> {input:?} [label={outputs:?}];\n"));
+      }
+    }
+    dot.push_str("}\n");
+    dot
+  }
+
+  /// Computes which derivations need to be built before all the outputs of the root can be realised.
+  pub fn build_plan(self) -> BuildPlan {
+    let mut order = Vec::new();
+    self.postorder(&self.root, &mut HashSet::new(), &mut order);
+    let mut needed: HashMap<&str, BTreeSet<String>> = HashMap::new();
+    if let Some(root) = self.nodes.get(&self.root) {
+      needed.insert(&self.root, root.outputs.keys().cloned().collect());
+    }
+    let mut plan = BuildPlan::default();
+    // every derivation using a node comes before it, so the outputs it needs are all known when it is reached.
+    for &path in order.iter().rev() {
+      let Some(outputs) = needed.remove(path) else {
+        continue;
+      };
+      let node = &self.nodes[path];
+      let status = node.status_of(&outputs);
+      let list = match status {
+        BuildStatus::Valid => &mut plan.already_valid,
+        BuildStatus::Missing => &mut plan.to_build,
+        BuildStatus::Unknown => &mut plan.unknown
+      };
+      list.push(path.to_string());
+      if status != BuildStatus::Valid {
+        for (input, input_outputs) in node.inputs.iter() {
+          needed.entry(input).or_default().extend(input_outputs.iter().cloned());
+        }
+      }
+    }
+    for list in [&mut plan.to_build, &mut plan.already_valid, &mut plan.unknown] {
+      list.reverse();
+    }
+    plan.graph = self;
+    plan
+  }
+
+  /// Derivations reachable from `path`, each one after all of its inputs.
+  fn postorder<'a>(&'a self, path: &'a str, visited: &mut HashSet<&'a str>, order: &mut Vec<&'a str>) {
+    let Some(node) = self.nodes.get(path) else {
+      return;
+    };
+    if !visited.insert(path) {
+      return;
+    }
+    for input in node.inputs.keys() {
+      self.postorder(input, visited, order);
+    }
+    order.push(path);
+  }
+}
+
+impl NixStore {
+  /// Walks the input derivations of `drv_path`, returning the whole derivation graph.
+  pub fn derivation_graph(&self, drv_path: &str) -> Result<DerivationGraph> {
+    let mut graph = DerivationGraph { root: drv_path.to_string(), nodes: BTreeMap::new() };
+    let mut pending = vec![drv_path.to_string()];
+    while let Some(path) = pending.pop() {
+      if graph.nodes.contains_key(&path) {
+        continue;
+      }
+      let drv = self.parse_derivation(&path)?;
+      let node = DerivationNode::new(&drv, self)?;
+      pending.extend(node.inputs.keys().filter(|i| !graph.nodes.contains_key(*i)).cloned());
+      graph.nodes.insert(path, node);
+    }
+    Ok(graph)
+  }
+
+  /// Computes which derivations need to be built before all the outputs of `drv_path` can be realised.
+  pub fn build_plan(&self, drv_path: &str) -> Result<BuildPlan> {
+    Ok(self.derivation_graph(drv_path)?.build_plan())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Node whose outputs are given as pairs of names and statuses, and inputs as pairs of paths and output names.
+  fn node(outputs: &[(&str, BuildStatus)], inputs: &[(&str, &[&str])]) -> DerivationNode {
+    let outputs: BTreeMap<String, NodeOutput> = outputs
+      .iter()
+      .map(|(name, status)| (name.to_string(), NodeOutput { path: None, status: *status }))
+      .collect();
+    let inputs = inputs
+      .iter()
+      .map(|(path, outputs)| (path.to_string(), outputs.iter().map(|o| o.to_string()).collect()))
+      .collect();
+    let mut node = DerivationNode { name: String::new(), status: BuildStatus::Valid, outputs, inputs };
+    node.status = node.status_of(node.outputs.keys());
+    node
+  }
+
+  fn graph(root: &str, nodes: Vec<(&str, DerivationNode)>) -> DerivationGraph {
+    DerivationGraph {
+      root: root.to_string(),
+      nodes: nodes.into_iter().map(|(path, node)| (path.to_string(), node)).collect()
+    }
+  }
+
+  #[test]
+  fn only_requested_outputs_are_needed() {
+    use BuildStatus::*;
+    let plan = graph("app", vec![
+      ("app", node(&[("out", Missing)], &[("lib", &["out"])])),
+      ("lib", node(&[("out", Valid), ("doc", Missing)], &[("compiler", &["out"])])),
+      ("compiler", node(&[("out", Missing)], &[]))
+    ]).build_plan();
+    assert_eq!(plan.graph.nodes["lib"].status, Missing);
+    assert_eq!(plan.to_build, vec!["app"]);
+    assert_eq!(plan.already_valid, vec!["lib"]);
+    assert!(plan.unknown.is_empty());
+  }
+
+  #[test]
+  fn outputs_requested_by_any_dependent_are_needed() {
+    use BuildStatus::*;
+    let plan = graph("app", vec![
+      ("app", node(&[("out", Missing)], &[("lib", &["out"]), ("docs", &["out"])])),
+      ("docs", node(&[("out", Missing)], &[("lib", &["doc"])])),
+      ("lib", node(&[("out", Valid), ("doc", Missing)], &[("compiler", &["out"])])),
+      ("compiler", node(&[("out", Missing)], &[]))
+    ]).build_plan();
+    assert_eq!(plan.to_build, vec!["compiler", "lib", "docs", "app"]);
+  }
+
+  #[test]
+  fn inputs_come_before_their_dependents() {
+    use BuildStatus::*;
+    let plan = graph("top", vec![
+      ("top", node(&[("out", Missing)], &[("a", &["out"]), ("b", &["out"])])),
+      ("a", node(&[("out", Missing)], &[("c", &["out"])])),
+      ("b", node(&[("out", Unknown)], &[("c", &["out"]), ("d", &["out"])])),
+      ("c", node(&[("out", Missing)], &[])),
+      ("d", node(&[("out", Valid)], &[("e", &["out"])])),
+      ("e", node(&[("out", Missing)], &[]))
+    ]).build_plan();
+    let position = |path: &str| plan.to_build.iter().position(|p| p == path).unwrap();
+    assert!(position("c") < position("a") && position("a") < position("top"));
+    assert_eq!(plan.unknown, vec!["b"]);
+    assert_eq!(plan.already_valid, vec!["d"]);
+    assert!(!plan.to_build.contains(&"e".to_string()));
+  }
+
+  #[test]
+  fn dot_edges_are_labelled_with_outputs() {
+    use BuildStatus::*;
+    let dot = graph("app", vec![
+      ("app", node(&[("out", Missing)], &[("lib", &["dev", "out"])])),
+      ("lib", node(&[("out", Valid), ("dev", Valid)], &[]))
+    ]).to_dot();
+    assert_eq!(dot, concat!(
+      "digraph derivations {\n",
+      "  node [shape=box];\n",
+      "  \"app\" [label=\"\", color=red];\n",
+      "  \"lib\" [label=\"\", color=green];\n",
+      "  \"app\" -> \"lib\" [label=\"dev,out\"];\n",
+      "}\n"
+    ));
+  }
+}
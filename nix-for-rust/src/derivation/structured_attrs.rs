@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::Result;
+use serde_json::{Map, Value};
+use super::Derivation;
+
+/// Reference checks of a single output, as given by `outputChecks.<output>`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OutputChecks {
+  pub max_size: Option<u64>,
+  pub max_closure_size: Option<u64>,
+  pub allowed_references: Option<Vec<String>>,
+  pub allowed_requisites: Option<Vec<String>>,
+  pub disallowed_references: Vec<String>,
+  pub disallowed_requisites: Vec<String>,
+  pub ignore_self_refs: bool
+}
+
+/// Attributes of a derivation built with `__structuredAttrs = true`,
+/// parsed from its `__json` env variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructuredAttrs {
+  pub attrs: Map<String, Value>
+}
+
+fn string_list(value: &Value, attr: &str) -> Result<Vec<String>> {
+  let Value::Array(values) = value else {
+    anyhow::bail!("Attribute '{attr}' should be a list of strings");
+  };
+  values
+    .iter()
+    .map(|v| v
+      .as_str()
+      .map(String::from)
+      .ok_or_else(|| anyhow::format_err!("Attribute '{attr}' should be a list of strings")))
+    .collect()
+}
+
+fn shell_escape(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Shell representation of a json value, mirroring what nix writes in `.attrs.sh`.
+fn shell_simple_value(value: &Value) -> Option<String> {
+  match value {
+    Value::String(s) => Some(shell_escape(s)),
+    Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+      (Some(i), _) => Some(i.to_string()),
+      (None, Some(f)) if f.fract() == 0.0 => Some((f as i64).to_string()),
+      _ => None
+    },
+    Value::Null => Some("''".to_string()),
+    Value::Bool(b) => Some(if *b { "1" } else { "" }.to_string()),
+    Value::Array(_) | Value::Object(_) => None
+  }
+}
+
+fn is_shell_var_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl StructuredAttrs {
+  pub fn parse(json: &str) -> Result<Self> {
+    match serde_json::from_str(json)? {
+      Value::Object(attrs) => Ok(StructuredAttrs { attrs }),
+      _ => anyhow::bail!("Structured attributes should be a JSON object")
+    }
+  }
+
+  pub fn get(&self, attr: &str) -> Option<&Value> {
+    self.attrs.get(attr)
+  }
+
+  /// Parsed `outputChecks` attribute, keyed by output name.
+  pub fn output_checks(&self) -> Result<HashMap<String, OutputChecks>> {
+    let Some(checks) = self.get("outputChecks") else {
+      return Ok(HashMap::new());
+    };
+    let Value::Object(checks) = checks else {
+      anyhow::bail!("Attribute 'outputChecks' should be an attribute set");
+    };
+    checks
+      .iter()
+      .map(|(output, check)| {
+        let Value::Object(check) = check else {
+          anyhow::bail!("Attribute 'outputChecks.{output}' should be an attribute set");
+        };
+        let size = |attr: &str| check.get(attr).map(|v| v
+          .as_u64()
+          .ok_or_else(|| anyhow::format_err!("Attribute '{attr}' should be an integer"))).transpose();
+        let list = |attr: &str| check.get(attr).map(|v| string_list(v, attr)).transpose();
+        let checks = OutputChecks {
+          max_size: size("maxSize")?,
+          max_closure_size: size("maxClosureSize")?,
+          allowed_references: list("allowedReferences")?,
+          allowed_requisites: list("allowedRequisites")?,
+          disallowed_references: list("disallowedReferences")?.unwrap_or_default(),
+          disallowed_requisites: list("disallowedRequisites")?.unwrap_or_default(),
+          ignore_self_refs: check.get("ignoreSelfRefs").and_then(Value::as_bool).unwrap_or(false),
+        };
+        Ok((output.clone(), checks))
+      })
+      .collect()
+  }
+
+  /// Parsed `exportReferencesGraph` attribute, mapping each file name to the store paths whose closure it exports.
+  pub fn export_references_graph(&self) -> Result<HashMap<String, Vec<String>>> {
+    let Some(graph) = self.get("exportReferencesGraph") else {
+      return Ok(HashMap::new());
+    };
+    let Value::Object(graph) = graph else {
+      anyhow::bail!("Attribute 'exportReferencesGraph' should be an attribute set");
+    };
+    graph
+      .iter()
+      .map(|(name, paths)| {
+        let paths = match paths {
+          Value::String(path) => vec![path.clone()],
+          other => string_list(other, "exportReferencesGraph")?
+        };
+        Ok((name.clone(), paths))
+      })
+      .collect()
+  }
+
+  pub fn required_system_features(&self) -> Result<Vec<String>> {
+    self.get("requiredSystemFeatures")
+      .map(|v| string_list(v, "requiredSystemFeatures"))
+      .unwrap_or(Ok(Vec::new()))
+  }
+
+  pub fn prefer_local_build(&self) -> bool {
+    self.get("preferLocalBuild").and_then(Value::as_bool).unwrap_or(false)
+  }
+
+  pub fn allow_substitutes(&self) -> bool {
+    self.get("allowSubstitutes").and_then(Value::as_bool).unwrap_or(true)
+  }
+
+  /// Contents of the `.attrs.json` file given to the builder, where `outputs` maps each output name to its path.
+  ///
+  /// Nix replaces each `exportReferencesGraph` entry by the path info of its closure, which is not
+  /// available through the C API, so those entries are kept as they are.
+  pub fn attrs_json(&self, outputs: &HashMap<String, String>) -> Value {
+    let mut attrs = self.attrs.clone();
+    let outputs = outputs
+      .iter()
+      .map(|(name, path)| (name.clone(), Value::String(path.clone())))
+      .collect();
+    attrs.insert("outputs".to_string(), Value::Object(outputs));
+    Value::Object(attrs)
+  }
+
+  /// Contents of the `.attrs.sh` file given to the builder, which declares a bash variable
+  /// for each attribute that has a shell representation.
+  pub fn attrs_sh(&self, outputs: &HashMap<String, String>) -> String {
+    let Value::Object(attrs) = self.attrs_json(outputs) else {
+      unreachable!("attrs_json always returns an object");
+    };
+    let mut sh = String::new();
+    for (key, value) in attrs.iter().filter(|(key, _)| is_shell_var_name(key)) {
+      if let Some(s) = shell_simple_value(value) {
+        sh.push_str(&format!("declare {key}={s}\n"));
+      } else if let Value::Array(values) = value {
+        let values: Option<String> = values
+          .iter()
+          .map(|v| shell_simple_value(v).map(|s| s + " "))
+          .collect();
+        if let Some(values) = values {
+          sh.push_str(&format!("declare -a {key}=({values})\n"));
+        }
+      } else if let Value::Object(values) = value {
+        let values: Option<String> = values
+          .iter()
+          .map(|(k, v)| shell_simple_value(v).map(|s| format!("[{}]={s} ", shell_escape(k))))
+          .collect();
+        if let Some(values) = values {
+          sh.push_str(&format!("declare -A {key}=({values})\n"));
+        }
+      }
+    }
+    sh
+  }
+
+  /// Writes `.attrs.json` and `.attrs.sh` into `dir`, as nix does before running the builder.
+  pub fn write_attrs_files<P: AsRef<Path>>(&self, dir: P, outputs: &HashMap<String, String>) -> Result<()> {
+    let dir = dir.as_ref();
+    std::fs::write(dir.join(".attrs.json"), serde_json::to_string(&self.attrs_json(outputs))?)?;
+    std::fs::write(dir.join(".attrs.sh"), self.attrs_sh(outputs))?;
+    Ok(())
+  }
+}
+
+impl<'store> Derivation<'store> {
+  /// Structured attributes of the derivation, or `None` if it doesn't use `__structuredAttrs`.
+  pub fn structured_attrs(&self) -> Result<Option<StructuredAttrs>> {
+    self.env
+      .get("__json")
+      .map(|json| StructuredAttrs::parse(json))
+      .transpose()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// `__json` of a nixpkgs `stdenv.mkDerivation` with `__structuredAttrs = true`, along with values
+  /// that have no shell representation: a non-integer number, an invalid name and nested lists and sets.
+  const HELLO: &str = r#"{
+  "NIX_DEBUG": 1,
+  "buildInputs": [],
+  "builder": "/nix/store/58br4vk3q5akf4g8lx0pqzfhn47k3j8d-bash-5.2p37/bin/bash",
+  "cmakeFlags": [],
+  "configureFlags": ["--with-foo", "--greeting=it's"],
+  "doCheck": true,
+  "enableParallelBuilding": true,
+  "env": { "NIX_CFLAGS_COMPILE": "-O2", "NIX_MAIN_PROGRAM": "hello" },
+  "exportReferencesGraph": {
+    "closure": ["/nix/store/58br4vk3q5akf4g8lx0pqzfhn47k3j8d-bash-5.2p37"],
+    "single": "/nix/store/0p2h2n6gl0ak2ldbh2yx8wxjk6l5vxqz-hook"
+  },
+  "meta": { "description": "A program that produces a familiar, friendly greeting", "license": { "free": true } },
+  "name": "hello-2.12.1",
+  "nativeBuildInputs": ["/nix/store/0p2h2n6gl0ak2ldbh2yx8wxjk6l5vxqz-hook"],
+  "nested": [[1]],
+  "outputChecks": {
+    "dev": { "disallowedRequisites": ["/nix/store/xqbs0ifawpmxhb4bmwr4fmwwbqc1hrd9-gcc-14.2.1"], "ignoreSelfRefs": true },
+    "out": { "allowedReferences": [], "maxSize": 1048576 }
+  },
+  "outputs": ["out", "dev"],
+  "patch-level": 3,
+  "preferLocalBuild": true,
+  "ratio": 1.5,
+  "requiredSystemFeatures": ["kvm", "big-parallel"],
+  "stdenv": "/nix/store/jdzx8qf1qh5ksz5y7zw8qbgfjzbspm5n-stdenv-linux",
+  "strictDeps": false,
+  "system": "x86_64-linux",
+  "version": "2.12.1"
+}"#;
+
+  fn hello() -> StructuredAttrs {
+    StructuredAttrs::parse(HELLO).unwrap()
+  }
+
+  fn outputs() -> HashMap<String, String> {
+    HashMap::from([
+      ("out".to_string(), "/nix/store/a7hnr9dcmx3qkkn8ia1ppxs8sdwk5qa5-hello-2.12.1".to_string()),
+      ("dev".to_string(), "/nix/store/y1s0kj1rq5x6rjcqxmyjvxzq0bg4f1ld-hello-2.12.1-dev".to_string())
+    ])
+  }
+
+  fn strings(strings: &[&str]) -> Vec<String> {
+    strings.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn parses_output_checks() {
+    let checks = hello().output_checks().unwrap();
+    assert_eq!(checks.len(), 2);
+    assert_eq!(checks["out"], OutputChecks {
+      max_size: Some(1048576),
+      allowed_references: Some(Vec::new()),
+      ..OutputChecks::default()
+    });
+    assert_eq!(checks["dev"], OutputChecks {
+      disallowed_requisites: strings(&["/nix/store/xqbs0ifawpmxhb4bmwr4fmwwbqc1hrd9-gcc-14.2.1"]),
+      ignore_self_refs: true,
+      ..OutputChecks::default()
+    });
+  }
+
+  #[test]
+  fn rejects_invalid_output_checks() {
+    let attrs = StructuredAttrs::parse(r#"{ "outputChecks": { "out": { "maxSize": "large" } } }"#).unwrap();
+    assert!(attrs.output_checks().is_err());
+    let attrs = StructuredAttrs::parse(r#"{ "outputChecks": { "out": { "allowedReferences": [1] } } }"#).unwrap();
+    assert!(attrs.output_checks().is_err());
+  }
+
+  #[test]
+  fn parses_export_references_graph() {
+    let graph = hello().export_references_graph().unwrap();
+    assert_eq!(graph, HashMap::from([
+      ("closure".to_string(), strings(&["/nix/store/58br4vk3q5akf4g8lx0pqzfhn47k3j8d-bash-5.2p37"])),
+      ("single".to_string(), strings(&["/nix/store/0p2h2n6gl0ak2ldbh2yx8wxjk6l5vxqz-hook"]))
+    ]));
+  }
+
+  #[test]
+  fn parses_scheduling_attributes() {
+    let attrs = hello();
+    assert_eq!(attrs.required_system_features().unwrap(), strings(&["kvm", "big-parallel"]));
+    assert!(attrs.prefer_local_build());
+    assert!(attrs.allow_substitutes());
+    let empty = StructuredAttrs::parse("{}").unwrap();
+    assert!(empty.required_system_features().unwrap().is_empty());
+    assert!(!empty.prefer_local_build());
+  }
+
+  #[test]
+  fn attrs_json_maps_outputs_to_paths() {
+    let json = hello().attrs_json(&outputs());
+    assert_eq!(json["outputs"]["out"], "/nix/store/a7hnr9dcmx3qkkn8ia1ppxs8sdwk5qa5-hello-2.12.1");
+    assert_eq!(json["outputs"]["dev"], "/nix/store/y1s0kj1rq5x6rjcqxmyjvxzq0bg4f1ld-hello-2.12.1-dev");
+    assert_eq!(json["name"], "hello-2.12.1");
+  }
+
+  /// Same as the `.attrs.sh` that nix writes, whose variables are sorted by name.
+  #[test]
+  fn attrs_sh_matches_nix() {
+    let expected = r#"declare NIX_DEBUG=1
+declare -a buildInputs=()
+declare builder='/nix/store/58br4vk3q5akf4g8lx0pqzfhn47k3j8d-bash-5.2p37/bin/bash'
+declare -a cmakeFlags=()
+declare -a configureFlags=('--with-foo' '--greeting=it'\''s' )
+declare doCheck=1
+declare enableParallelBuilding=1
+declare -A env=(['NIX_CFLAGS_COMPILE']='-O2' ['NIX_MAIN_PROGRAM']='hello' )
+declare name='hello-2.12.1'
+declare -a nativeBuildInputs=('/nix/store/0p2h2n6gl0ak2ldbh2yx8wxjk6l5vxqz-hook' )
+declare -A outputs=(['dev']='/nix/store/y1s0kj1rq5x6rjcqxmyjvxzq0bg4f1ld-hello-2.12.1-dev' ['out']='/nix/store/a7hnr9dcmx3qkkn8ia1ppxs8sdwk5qa5-hello-2.12.1' )
+declare preferLocalBuild=1
+declare -a requiredSystemFeatures=('kvm' 'big-parallel' )
+declare stdenv='/nix/store/jdzx8qf1qh5ksz5y7zw8qbgfjzbspm5n-stdenv-linux'
+declare strictDeps=
+declare system='x86_64-linux'
+declare version='2.12.1'
+"#;
+    assert_eq!(hello().attrs_sh(&outputs()), expected);
+  }
+
+  #[test]
+  fn writes_attrs_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    hello().write_attrs_files(dir.path(), &outputs())?;
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(dir.path().join(".attrs.json"))?)?;
+    assert_eq!(json, hello().attrs_json(&outputs()));
+    assert_eq!(std::fs::read_to_string(dir.path().join(".attrs.sh"))?, hello().attrs_sh(&outputs()));
+    Ok(())
+  }
+}
@@ -24,6 +24,17 @@ pub enum NixErrorKind {
   GenericError { info_msg: String, name: String }
 }
 
+impl NixError {
+  pub fn kind(&self) -> &NixErrorKind {
+    &self.kind
+  }
+
+  /// Full message of the error, including the trace of where it was thrown for errors thrown by nix.
+  pub fn message(&self) -> &str {
+    &self.msg
+  }
+}
+
 impl std::fmt::Debug for NixError {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "({}) {self}", self.code as u32)
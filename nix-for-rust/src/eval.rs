@@ -1,8 +1,8 @@
 use crate::bindings::{alloc_value, eval_state_build, eval_state_builder, eval_state_builder_free, eval_state_builder_load, eval_state_builder_new, eval_state_builder_set_lookup_path, expr_eval_from_string, libexpr_init, state_create, state_free, value_decref, value_incref, EvalState, Value};
 use crate::settings::NixSettings;
 use crate::store::{NixContext, NixStore};
-use crate::term::{NixEvalError, NixTerm, ToNix};
-use std::path::PathBuf;
+use crate::term::{NixEvalError, NixResult, NixTerm, ToNix};
+use std::path::{Path, PathBuf};
 use std::ptr::NonNull;
 use std::ffi::{c_char, CString};
 use anyhow::Result;
@@ -48,12 +48,17 @@ impl NixEvalState {
   }
   
   pub fn eval_string<'state>(&'state self, expr: &str, cwd: PathBuf) -> Result<NixTerm<'state>> {
-    let cstr = CString::new(expr)?;
-    let current_dir = cwd.as_path()
+    self.eval_expr(expr, &cwd).map_err(|err: NixEvalError| anyhow::anyhow!(err))
+  }
+
+  /// Evaluates `expr` with `cwd` as the base directory for relative paths,
+  /// returning errors as [`NixEvalError`] instead of [`anyhow::Error`].
+  pub(crate) fn eval_expr<'state>(&'state self, expr: &str, cwd: &Path) -> NixResult<NixTerm<'state>> {
+    let cstr = CString::new(expr).map_err(|_| NixEvalError::InvalidString)?;
+    let current_dir = cwd
       .to_str()
-      .ok_or_else(|| anyhow::format_err!("Cannot get current directory"))?
-      .to_owned();
-    let current_dir = CString::new(current_dir)?;
+      .and_then(|dir| CString::new(dir).ok())
+      .ok_or_else(|| NixEvalError::InvalidPath(cwd.to_string_lossy().to_string()))?;
     let val = RawValue::empty(self);
     unsafe {
       expr_eval_from_string(
@@ -64,7 +69,7 @@ impl NixEvalState {
         val.value.as_ptr());
     }
     self.store.ctx.check_call()?;
-    val.to_nix(self).map_err(|err: NixEvalError| anyhow::anyhow!(err))
+    val.to_nix(self)
   }
 
   pub fn eval_file<'state, P: AsRef<std::path::Path>>(&'state self, file: P) -> Result<NixTerm<'state>> {
@@ -0,0 +1,169 @@
+use nix::libc::{self, c_long, user_regs_struct};
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("the eval-cache feature only supports x86_64 and aarch64");
+
+/// Syscalls that the file tracer needs to know about, independently of their number on each architecture.
+/// Some of them only exist on x86_64, where aarch64 only has their `*at` variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+pub(super) enum Syscall {
+  /// `read`, `pread64`, `readv`, `preadv` and `preadv2` of the fd in the first argument.
+  Read,
+  /// `mmap` of the fd in the fifth argument.
+  Mmap,
+  /// `open` of the path in the first argument.
+  Open,
+  /// `openat` of the path in the second argument, relative to the fd in the first one.
+  OpenAt,
+  /// `openat2` of the path in the second argument, relative to the fd in the first one,
+  /// with the flags in the `open_how` struct of the third one.
+  OpenAt2,
+  /// `stat`, `lstat` and `access` of the path in the first argument.
+  Stat,
+  /// `newfstatat`, `statx`, `faccessat` and `faccessat2` of the path in the second argument,
+  /// relative to the fd in the first one.
+  StatAt,
+  /// `fstat` of the fd in the first argument.
+  FStat,
+  /// `readlink` of the path in the first argument.
+  ReadLink,
+  /// `readlinkat` of the path in the second argument, relative to the fd in the first one.
+  ReadLinkAt,
+  /// `getdents` and `getdents64` of the fd in the first argument.
+  GetDents,
+  /// `dup` and `dup2` of the fd in the first argument, returning the new fd.
+  Dup,
+  /// `dup3` of the fd in the first argument, with the flags in the third one.
+  Dup3,
+  /// `fcntl` of the fd in the first argument, which duplicates it with `F_DUPFD` and `F_DUPFD_CLOEXEC`,
+  /// or changes its flags with `F_SETFD`.
+  Fcntl,
+  Close
+}
+
+/// Access to the syscall number, arguments and return value in the registers of a stopped tracee.
+pub(super) trait SyscallRegisters {
+  /// Syscall being executed, only valid when stopped at syscall entry.
+  fn syscall(&self) -> Option<Syscall>;
+  /// `n`th argument of the syscall, only valid when stopped at syscall entry.
+  fn arg(&self, n: usize) -> u64;
+  /// Only valid when stopped at syscall exit.
+  fn return_value(&self) -> u64;
+}
+
+pub(super) type Registers = user_regs_struct;
+
+#[cfg(target_arch = "x86_64")]
+pub(super) fn get_registers(pid: Pid) -> nix::Result<Registers> {
+  ptrace::getregs(pid)
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(super) fn get_registers(pid: Pid) -> nix::Result<Registers> {
+  // there is no PTRACE_GETREGS on aarch64.
+  ptrace::getregset::<ptrace::regset::NT_PRSTATUS>(pid)
+}
+
+/// Numbers of the syscalls the file tracer knows about.
+#[cfg(target_arch = "x86_64")]
+pub(super) const SYSCALLS: &[(c_long, Syscall)] = &[
+  (libc::SYS_read, Syscall::Read),
+  (libc::SYS_pread64, Syscall::Read),
+  (libc::SYS_readv, Syscall::Read),
+  (libc::SYS_preadv, Syscall::Read),
+  (libc::SYS_preadv2, Syscall::Read),
+  (libc::SYS_mmap, Syscall::Mmap),
+  (libc::SYS_open, Syscall::Open),
+  (libc::SYS_openat, Syscall::OpenAt),
+  (libc::SYS_openat2, Syscall::OpenAt2),
+  (libc::SYS_stat, Syscall::Stat),
+  (libc::SYS_lstat, Syscall::Stat),
+  (libc::SYS_access, Syscall::Stat),
+  (libc::SYS_newfstatat, Syscall::StatAt),
+  (libc::SYS_statx, Syscall::StatAt),
+  (libc::SYS_faccessat, Syscall::StatAt),
+  (libc::SYS_faccessat2, Syscall::StatAt),
+  (libc::SYS_fstat, Syscall::FStat),
+  (libc::SYS_readlink, Syscall::ReadLink),
+  (libc::SYS_readlinkat, Syscall::ReadLinkAt),
+  (libc::SYS_getdents, Syscall::GetDents),
+  (libc::SYS_getdents64, Syscall::GetDents),
+  (libc::SYS_dup, Syscall::Dup),
+  (libc::SYS_dup2, Syscall::Dup),
+  (libc::SYS_dup3, Syscall::Dup3),
+  (libc::SYS_fcntl, Syscall::Fcntl),
+  (libc::SYS_close, Syscall::Close)
+];
+
+/// Numbers of the syscalls the file tracer knows about.
+#[cfg(target_arch = "aarch64")]
+pub(super) const SYSCALLS: &[(c_long, Syscall)] = &[
+  (libc::SYS_read, Syscall::Read),
+  (libc::SYS_pread64, Syscall::Read),
+  (libc::SYS_readv, Syscall::Read),
+  (libc::SYS_preadv, Syscall::Read),
+  (libc::SYS_preadv2, Syscall::Read),
+  (libc::SYS_mmap, Syscall::Mmap),
+  (libc::SYS_openat, Syscall::OpenAt),
+  (libc::SYS_openat2, Syscall::OpenAt2),
+  (libc::SYS_newfstatat, Syscall::StatAt),
+  (libc::SYS_statx, Syscall::StatAt),
+  (libc::SYS_faccessat, Syscall::StatAt),
+  (libc::SYS_faccessat2, Syscall::StatAt),
+  (libc::SYS_fstat, Syscall::FStat),
+  (libc::SYS_readlinkat, Syscall::ReadLinkAt),
+  (libc::SYS_getdents64, Syscall::GetDents),
+  (libc::SYS_dup, Syscall::Dup),
+  (libc::SYS_dup3, Syscall::Dup3),
+  (libc::SYS_fcntl, Syscall::Fcntl),
+  (libc::SYS_close, Syscall::Close)
+];
+
+/// `AUDIT_ARCH_X86_64`, the architecture seen by seccomp filters.
+#[cfg(target_arch = "x86_64")]
+pub(super) const AUDIT_ARCH: u32 = 0xc000_003e;
+
+/// `AUDIT_ARCH_AARCH64`, the architecture seen by seccomp filters.
+#[cfg(target_arch = "aarch64")]
+pub(super) const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+pub(super) fn syscall_from_number(number: c_long) -> Option<Syscall> {
+  SYSCALLS
+    .iter()
+    .find(|(n, _)| *n == number)
+    .map(|(_, syscall)| *syscall)
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SyscallRegisters for Registers {
+  fn syscall(&self) -> Option<Syscall> {
+    syscall_from_number(self.orig_rax as c_long)
+  }
+
+  fn arg(&self, n: usize) -> u64 {
+    [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9][n]
+  }
+
+  fn return_value(&self) -> u64 {
+    self.rax
+  }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl SyscallRegisters for Registers {
+  fn syscall(&self) -> Option<Syscall> {
+    syscall_from_number(self.regs[8] as c_long)
+  }
+
+  fn arg(&self, n: usize) -> u64 {
+    // x0 is overwritten with the return value, so arguments must be read at syscall entry.
+    self.regs[..6][n]
+  }
+
+  fn return_value(&self) -> u64 {
+    self.regs[0]
+  }
+}
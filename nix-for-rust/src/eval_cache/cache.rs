@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use sqlx::{Pool, Sqlite};
+
+use crate::eval::NixEvalState;
+use super::impure::ImpureInputs;
+use super::{db, EvalCacheSettings, FileAttribute, FileInput, InputKind};
+
+/// File read while evaluating an entry, as it was when the entry was recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedInput {
+  pub path: PathBuf,
+  pub kind: InputKind,
+  /// Hash of the part of the file the evaluation depended on, `None` for entries recorded by older versions.
+  pub hash: Option<String>,
+  /// Modification time in nanoseconds of regular files and directories when they were hashed.
+  pub mtime: Option<i64>,
+  /// Size of regular files and directories when they were hashed.
+  pub size: Option<i64>
+}
+
+impl From<FileInput> for RecordedInput {
+  fn from(input: FileInput) -> Self {
+    RecordedInput { path: input.path, kind: input.kind, hash: None, mtime: None, size: None }
+  }
+}
+
+/// Database of an evaluation cache, to look it up and maintain it.
+pub struct EvalCache {
+  pool: Pool<Sqlite>,
+  max_age: Option<Duration>,
+  max_size: Option<u64>
+}
+
+/// Output of an attribute stored in the cache, along with what it depended on.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+  pub id: i64,
+  pub file: PathBuf,
+  pub accessor_path: Vec<String>,
+  /// Hash of `file` when the entry was recorded.
+  pub file_hash: String,
+  /// Hash of the inputs, settings and environment variables when the entry was recorded.
+  pub input_hash: String,
+  /// Serialized [`CachedValue`](super::CachedValue).
+  pub output: String,
+  /// Unix time at which the entry was recorded.
+  pub created_at: i64,
+  /// Unix time at which the entry was last returned from the cache.
+  pub last_used: i64,
+  pub inputs: Vec<RecordedInput>,
+  pub settings: BTreeMap<String, String>,
+  /// Environment variables read by the evaluation, `None` for unset ones.
+  pub env: BTreeMap<String, Option<String>>
+}
+
+/// Reason why an entry can't be returned from the cache anymore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+  /// The main file was changed or removed.
+  FileChanged,
+  /// A file read while evaluating was changed.
+  InputChanged { path: PathBuf, kind: InputKind },
+  /// One of the files read while evaluating was changed, for entries recorded without the hash of each input.
+  InputsChanged,
+  Setting { name: String, recorded: Option<String>, current: Option<String> },
+  Env { name: String, recorded: Option<String>, current: Option<String> }
+}
+
+impl EvalCache {
+  /// Opens the cache at the location of `settings`, creating it if needed.
+  pub fn open(settings: &EvalCacheSettings) -> Result<Self> {
+    Ok(EvalCache {
+      pool: db::open(&settings.location)?,
+      max_age: settings.max_age,
+      max_size: settings.max_size
+    })
+  }
+
+  pub(super) fn query(&self, file_attr: &FileAttribute, settings: &BTreeMap<String, String>) -> Result<Option<String>> {
+    db::query_attr_in_cache(&self.pool, file_attr, settings)
+  }
+
+  pub(super) fn insert(&self, file_attr: &FileAttribute, inputs: Vec<FileInput>, impure: &ImpureInputs, output: &str) -> Result<()> {
+    db::insert_evaluation_output(&self.pool, file_attr, inputs, impure, output)
+  }
+
+  /// Evicts the entries that are too old, or least recently used past the maximum size of the cache.
+  /// Returns the number of evicted entries.
+  pub fn evict(&self) -> Result<u64> {
+    if self.max_age.is_none() && self.max_size.is_none() {
+      return Ok(0);
+    }
+    db::evict(&self.pool, self.max_age, self.max_size)
+  }
+
+  /// Shrinks the database file after entries were removed.
+  pub fn vacuum(&self) -> Result<()> {
+    db::vacuum(&self.pool)
+  }
+
+  /// Removes the entries of every attribute of `file`. Returns the number of removed entries.
+  pub fn clear_file<P: AsRef<Path>>(&self, file: P) -> Result<u64> {
+    db::delete_entries(&self.pool, &std::fs::canonicalize(file)?, None)
+  }
+
+  /// Removes the entries of the attribute at `accessor_path` in `file`, and of the attributes nested in it.
+  /// Returns the number of removed entries.
+  pub fn clear_attr<S: AsRef<str>, I: IntoIterator<Item=S>, P: AsRef<Path>>(&self, file: P, accessor_path: I) -> Result<u64> {
+    let accessor_path: Vec<String> = accessor_path.into_iter().map(|s| String::from(s.as_ref())).collect();
+    if accessor_path.is_empty() {
+      return self.clear_file(file);
+    }
+    db::delete_entries(&self.pool, &std::fs::canonicalize(file)?, Some(&accessor_path.join(".")))
+  }
+
+  /// Entries of every file, from the most recently used one.
+  pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+    db::query_entries(&self.pool, None, None)
+  }
+
+  /// Entries of the attribute at `accessor_path` in `file`, from the most recently used one.
+  pub fn attr_entries<S: AsRef<str>, I: IntoIterator<Item=S>, P: AsRef<Path>>(&self, file: P, accessor_path: I) -> Result<Vec<CacheEntry>> {
+    let accessor_path: Vec<String> = accessor_path.into_iter().map(|s| String::from(s.as_ref())).collect();
+    db::query_entries(&self.pool, Some(&std::fs::canonicalize(file)?), Some(&accessor_path.join(".")))
+  }
+
+  /// Reasons why `entry` wouldn't be returned by `state`, empty if it is still valid.
+  pub fn why_invalid(&self, state: &NixEvalState, entry: &CacheEntry) -> Result<Vec<Invalidation>> {
+    let mut reasons = Vec::new();
+    let file_hash = std::fs::read(&entry.file).ok().map(|contents| blake3::hash(&contents).to_string());
+    if file_hash.as_ref() != Some(&entry.file_hash) {
+      reasons.push(Invalidation::FileChanged);
+    }
+    let mut inputs = Vec::new();
+    for input in &entry.inputs {
+      let current = db::rehash_input(input).ok();
+      if input.hash.is_some() && current.as_ref().map(|c| &c.hash) != Some(&input.hash) {
+        reasons.push(Invalidation::InputChanged { path: input.path.clone(), kind: input.kind });
+      }
+      inputs.push(current.unwrap_or_else(|| input.clone()));
+    }
+    // the recorded settings and variables are used so that the hash only changes along with the files.
+    let recorded = ImpureInputs { settings: entry.settings.clone(), env: entry.env.clone() };
+    let inputs_changed = db::combine_hashes(&inputs, &recorded).to_string() != entry.input_hash;
+    if inputs_changed && !reasons.iter().any(|r| matches!(r, Invalidation::InputChanged { .. })) {
+      reasons.push(Invalidation::InputsChanged);
+    }
+    let settings = state.evaluator_settings()?;
+    let names: BTreeSet<&String> = settings.keys().chain(entry.settings.keys()).collect();
+    for name in names {
+      let (recorded, current) = (entry.settings.get(name), settings.get(name));
+      if recorded != current {
+        reasons.push(Invalidation::Setting { name: name.clone(), recorded: recorded.cloned(), current: current.cloned() });
+      }
+    }
+    let current = ImpureInputs::new(BTreeMap::new(), entry.env.keys().cloned());
+    for (name, value) in current.env {
+      if entry.env.get(&name) != Some(&value) {
+        reasons.push(Invalidation::Env { recorded: entry.env.get(&name).cloned().flatten(), current: value, name });
+      }
+    }
+    Ok(reasons)
+  }
+
+  /// Removes the entries recorded for an older version of their main file, which can't be used anymore,
+  /// evicts entries according to the settings and shrinks the database. Returns the number of removed entries.
+  pub fn prune(&self) -> Result<u64> {
+    let mut removed = 0;
+    for (file, file_hash) in db::query_main_files(&self.pool)? {
+      let current = std::fs::read(&file).ok().map(|contents| blake3::hash(&contents).to_string());
+      if current.as_ref() != Some(&file_hash) {
+        removed += db::delete_file_version(&self.pool, &file, &file_hash)?;
+      }
+    }
+    removed += self.evict()?;
+    self.vacuum()?;
+    Ok(removed)
+  }
+
+  /// Removes every entry. Returns the number of removed entries.
+  pub fn clear(&self) -> Result<u64> {
+    db::delete_all_entries(&self.pool)
+  }
+}
@@ -1,13 +1,22 @@
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::io::ErrorKind;
 use std::str::FromStr;
-use std::path::{PathBuf, Path};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use sqlx::{Acquire, Pool, Sqlite};
-use sqlx::{sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous}, SqlitePool};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
 use anyhow::Result;
-use std::sync::LazyLock;
-use super::FileAttribute;
+use super::{CacheEntry, CacheLocation, FileAttribute, FileInput, InputKind, RecordedInput};
+use super::impure::ImpureInputs;
 use tokio::runtime::Runtime;
 
+/// Least recently used entries deleted at once when the cache is over its maximum size.
+const EVICTION_BATCH: i64 = 64;
+
 static TOKIO_RT: LazyLock<Runtime> = LazyLock::new(|| {
   tokio::runtime::Builder::new_current_thread()
     .enable_time()
@@ -15,55 +24,204 @@ static TOKIO_RT: LazyLock<Runtime> = LazyLock::new(|| {
     .expect("Could not initialize tokio runtime")
 });
 
-static SQLITE_POOL: LazyLock<Pool<Sqlite>> = LazyLock::new(|| {
-  let cache_directory = home::home_dir()
-    .unwrap_or_else(|| Path::new("/tmp").to_path_buf())
-    .join(".cache")
-    .join("nix-for-rust")
-    .join("eval-cache");
-  std::fs::create_dir_all(&cache_directory).expect("Could not create cache directory");
-  let sqlite_file = cache_directory.join("sqlite-v1.db");
-  let db_url = sqlite_file.as_path().to_string_lossy();
-  TOKIO_RT.block_on(async { setup_db(db_url).await.expect("Could not run database setup") })
-});
+/// Pools of the databases opened so far, so that each one is only set up once.
+static SQLITE_POOLS: LazyLock<Mutex<HashMap<CacheLocation, Pool<Sqlite>>>> = LazyLock::new(Default::default);
+
+/// Opens the database at `location`, creating it if needed.
+pub fn open(location: &CacheLocation) -> Result<Pool<Sqlite>> {
+  let mut pools = SQLITE_POOLS.lock().unwrap_or_else(|e| e.into_inner());
+  if let Some(pool) = pools.get(location) {
+    return Ok(pool.clone());
+  }
+  let pool = match location {
+    CacheLocation::Default => {
+      let cache_directory = home::home_dir()
+        .unwrap_or_else(|| Path::new("/tmp").to_path_buf())
+        .join(".cache")
+        .join("nix-for-rust")
+        .join("eval-cache");
+      std::fs::create_dir_all(&cache_directory)?;
+      let sqlite_file = cache_directory.join("sqlite-v1.db");
+      TOKIO_RT.block_on(setup_db(SqliteConnectOptions::new().filename(sqlite_file), SqlitePoolOptions::new()))?
+    }
+    CacheLocation::File(path) => {
+      if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+      }
+      TOKIO_RT.block_on(setup_db(SqliteConnectOptions::new().filename(path), SqlitePoolOptions::new()))?
+    }
+    CacheLocation::InMemory => open_in_memory()?
+  };
+  pools.insert(location.clone(), pool.clone());
+  Ok(pool)
+}
 
-pub fn query_attr_in_cache(file_attr: &FileAttribute) -> Result<Option<String>> {
-  let pool = &*SQLITE_POOL;
+/// Opens a new database in memory, which is lost once the pool is dropped.
+fn open_in_memory() -> Result<Pool<Sqlite>> {
+  // every connection would open a different database, so a single one is kept open.
+  let pool_options = SqlitePoolOptions::new()
+    .max_connections(1)
+    .min_connections(1)
+    .idle_timeout(None)
+    .max_lifetime(None);
+  TOKIO_RT.block_on(setup_db(SqliteConnectOptions::from_str("sqlite::memory:")?, pool_options))
+}
+
+pub fn query_attr_in_cache(pool: &Pool<Sqlite>, file_attr: &FileAttribute, settings: &BTreeMap<String, String>) -> Result<Option<String>> {
   TOKIO_RT.block_on(async {
-    query_evaluation_output(pool, file_attr).await
+    query_evaluation_output(pool, file_attr, settings).await
   })
 }
 
 
-pub async fn setup_db<P: AsRef<str>>(database_url: P) -> Result<SqlitePool> {
-  let conn_options = SqliteConnectOptions::from_str(database_url.as_ref())?
+pub async fn setup_db(conn_options: SqliteConnectOptions, pool_options: SqlitePoolOptions) -> Result<Pool<Sqlite>> {
+  let conn_options = conn_options
     .foreign_keys(true)
     .journal_mode(SqliteJournalMode::Wal)
     .synchronous(SqliteSynchronous::Normal)
     .pragma("mmap_size", "134217728")
     .create_if_missing(true);
-  let pool = SqlitePool::connect_with(conn_options).await?;
+  let pool = pool_options.connect_with(conn_options).await?;
   sqlx::migrate!("./src/eval_cache/migrations").run(&pool).await?;
   Ok(pool)
 }
 
-pub fn hash_files(files: &[PathBuf]) -> Result<blake3::Hash> {
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+  unsafe { Path::new(OsStr::from_encoded_bytes_unchecked(bytes)).to_path_buf() }
+}
+
+fn unix_time() -> i64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// Type of the file at `metadata`, or `missing` if the file does not exist.
+fn file_type(metadata: std::io::Result<Metadata>) -> Result<&'static [u8]> {
+  match metadata {
+    Ok(m) if m.is_dir() => Ok(b"directory"),
+    Ok(m) if m.is_symlink() => Ok(b"symlink"),
+    Ok(m) if m.is_file() => Ok(b"file"),
+    Ok(_) => Ok(b"other"),
+    Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => Ok(b"missing"),
+    Err(e) => Err(e.into())
+  }
+}
+
+/// Hashes the names and types of the entries of the directory at `path`. Subdirectories are separate inputs,
+/// recorded only if the evaluation listed them too.
+fn hash_directory(hasher: &mut blake3::Hasher, path: &Path) -> Result<()> {
+  let mut entries = std::fs::read_dir(path)?.collect::<std::io::Result<Vec<_>>>()?;
+  entries.sort_by_key(|entry| entry.file_name());
+  for entry in entries {
+    hasher.update(entry.file_name().as_encoded_bytes());
+    hasher.update(b"\0");
+    // symbolic links aren't followed.
+    let entry_type = file_type(entry.metadata())?;
+    hasher.update(entry_type);
+    hasher.update(b"\0");
+  }
+  // names can't contain a slash, so this ends the listing unambiguously.
+  hasher.update(b"/");
+  Ok(())
+}
+
+/// Modification time in nanoseconds and size of `metadata`, if it is a regular file or a directory,
+/// whose modification time changes whenever entries are added, removed or renamed.
+fn file_stamp(metadata: &std::io::Result<Metadata>) -> (Option<i64>, Option<i64>) {
+  match metadata {
+    Ok(m) if m.is_file() || m.is_dir() => (Some(m.mtime() * 1_000_000_000 + m.mtime_nsec()), Some(m.size() as i64)),
+    _ => (None, None)
+  }
+}
+
+/// Hashes the part of `input` that the evaluation depended on, including whether it exists. The recorded hash
+/// of regular files and directories is kept without reading them if their modification time and size didn't change.
+pub fn rehash_input(input: &RecordedInput) -> Result<RecordedInput> {
+  let path = &input.path;
   let mut hasher = blake3::Hasher::new();
-  let mut files = files.to_vec();
-  files.sort();
-  for file in files {
-    if file.is_dir() {
-      for entry in std::fs::read_dir(file)? {
-        hasher.update(entry?.path().as_os_str().as_encoded_bytes());
+  let mut stamp = (None, None);
+  match input.kind {
+    InputKind::Contents => {
+      let metadata = std::fs::metadata(path);
+      stamp = file_stamp(&metadata);
+      if input.hash.is_some() && stamp.0.is_some() && (input.mtime, input.size) == stamp {
+        return Ok(input.clone());
+      }
+      let file_type = file_type(metadata)?;
+      hasher.update(file_type);
+      if file_type == b"directory" {
+        hash_directory(&mut hasher, path)?;
+      } else if file_type == b"file" {
+        // only the type of devices and pipes is known, since reading them might never end.
+        hasher.update_mmap(path)?;
+      }
+    }
+    InputKind::Metadata => {
+      hasher.update(file_type(std::fs::symlink_metadata(path))?);
+      hasher.update(file_type(std::fs::metadata(path))?);
+    }
+    InputKind::Link => match std::fs::read_link(path) {
+      Ok(target) => {
+        hasher.update(target.as_os_str().as_encoded_bytes());
+      }
+      // not a symbolic link.
+      Err(e) if e.kind() == ErrorKind::InvalidInput => {
+        hasher.update(b"no link");
+      }
+      Err(e) => {
+        hasher.update(file_type(Err(e))?);
       }
-    } else {
-      hasher.update_mmap(&file)?;
     }
   }
-  Ok(hasher.finalize())
+  Ok(RecordedInput {
+    path: path.clone(),
+    kind: input.kind,
+    hash: Some(hasher.finalize().to_string()),
+    mtime: stamp.0,
+    size: stamp.1
+  })
 }
 
-pub async fn query_evaluation_output<'a, A>(conn: A, file_attr: &FileAttribute) -> Result<Option<String>>
+/// Combines the hashes of `inputs` with the impure inputs of the evaluation.
+pub fn combine_hashes(inputs: &[RecordedInput], impure: &ImpureInputs) -> blake3::Hash {
+  let mut inputs: Vec<&RecordedInput> = inputs.iter().collect();
+  inputs.sort_by(|a, b| (&a.path, a.kind).cmp(&(&b.path, b.kind)));
+  let mut hasher = blake3::Hasher::new();
+  for input in inputs {
+    hasher.update(input.path.as_os_str().as_encoded_bytes());
+    hasher.update(b"\0");
+    hasher.update(input.kind.as_str().as_bytes());
+    hasher.update(b"\0");
+    hasher.update(input.hash.as_deref().unwrap_or_default().as_bytes());
+    hasher.update(b"\0");
+  }
+  impure.update_hash(&mut hasher);
+  hasher.finalize()
+}
+
+/// Columns of `evaluation_input` making a [`RecordedInput`].
+type InputRow = (Vec<u8>, String, Option<String>, Option<i64>, Option<i64>);
+
+/// Inputs of the evaluation `evaluation_id`, as they were when it was recorded.
+async fn query_inputs(conn: &mut SqliteConnection, evaluation_id: i64) -> Result<Vec<RecordedInput>> {
+  let rows: Vec<InputRow> = sqlx::query_as(
+      "SELECT file_path, kind, hash, mtime, size FROM evaluation_input WHERE evaluation_id = ?"
+    )
+    .bind(evaluation_id)
+    .fetch_all(&mut *conn)
+    .await?;
+  rows
+    .into_iter()
+    .map(|(path, kind, hash, mtime, size)| Ok(RecordedInput {
+      path: path_from_bytes(&path),
+      kind: InputKind::from_str(&kind)?,
+      hash,
+      mtime,
+      size
+    }))
+    .collect()
+}
+
+pub async fn query_evaluation_output<'a, A>(conn: A, file_attr: &FileAttribute, settings: &BTreeMap<String, String>) -> Result<Option<String>>
 where
     A: Acquire<'a, Database = Sqlite>,
 {
@@ -77,31 +235,61 @@ where
     .fetch_all(&mut *conn)
     .await?;
   for (evaluation_id, input_hash, output) in eval_outputs {
-    let files: Vec<PathBuf> = sqlx::query_as("SELECT file_path FROM evaluation_input WHERE evaluation_id = ?")
+    let recorded = query_inputs(&mut conn, evaluation_id).await?;
+    let env_vars: Vec<(String, )> = sqlx::query_as("SELECT name FROM evaluation_impure_input WHERE evaluation_id = ? AND kind = 'env'")
       .bind(evaluation_id)
       .fetch_all(&mut *conn)
-      .await?
-      .into_iter()
-      .map(|(path,): (Vec<u8>, )| unsafe {
-        Path::new(OsStr::from_encoded_bytes_unchecked(&path)).to_path_buf()
-      })
-      .collect();
-    let Ok(hash) = hash_files(&files) else {
+      .await?;
+    let impure = ImpureInputs::new(settings.clone(), env_vars.into_iter().map(|(name, )| name));
+    let Ok(inputs) = recorded.iter().map(rehash_input).collect::<Result<Vec<_>>>() else {
       continue
     };
-    if hash.to_string() == input_hash {
+    if combine_hashes(&inputs, &impure).to_string() == input_hash {
+      sqlx::query("UPDATE evaluation_output SET last_used = ? WHERE id = ?")
+        .bind(unix_time())
+        .bind(evaluation_id)
+        .execute(&mut *conn)
+        .await?;
+      // files that were touched without being changed don't need to be hashed again next time.
+      for input in inputs.iter().filter(|input| !recorded.contains(input)) {
+        sqlx::query("UPDATE evaluation_input SET hash = ?, mtime = ?, size = ? WHERE evaluation_id = ? AND file_path = ? AND kind = ?")
+          .bind(&input.hash)
+          .bind(input.mtime)
+          .bind(input.size)
+          .bind(evaluation_id)
+          .bind(input.path.as_os_str().as_encoded_bytes())
+          .bind(input.kind.as_str())
+          .execute(&mut *conn)
+          .await?;
+      }
       return Ok(Some(output));
     }
   }
   Ok(None)
 }
 
-pub fn insert_evaluation_output(file_attr: &FileAttribute, input_files: Vec<PathBuf>, output: &str) -> Result<()> {
+pub fn insert_evaluation_output(pool: &Pool<Sqlite>, file_attr: &FileAttribute, inputs: Vec<FileInput>, impure: &ImpureInputs, output: &str) -> Result<()> {
   TOKIO_RT.block_on(async {
-    let mut conn = SQLITE_POOL.acquire().await?;
-    let input_hash = hash_files(&input_files)?;
+    let mut conn = pool.acquire().await?;
+    let inputs = inputs
+      .into_iter()
+      .map(|input| rehash_input(&RecordedInput::from(input)))
+      .collect::<Result<Vec<_>>>()?;
+    let input_hash = combine_hashes(&inputs, impure);
+    // entries written in an older format are replaced.
+    sqlx::query(r#"
+        DELETE FROM evaluation_output
+        WHERE main_file_path = ? AND accessor_path = ? AND main_file_hash = ? AND input_hash = ?
+      "#)
+      .bind(file_attr.path.as_os_str().as_encoded_bytes())
+      .bind(file_attr.accessor_path.join("."))
+      .bind(file_attr.hash.to_string())
+      .bind(input_hash.to_string())
+      .execute(&mut *conn)
+      .await?;
     let (evaluation_id, ): (i64, ) = sqlx::query_as(r#"
-        INSERT INTO evaluation_output (main_file_path, accessor_path, output, main_file_hash, input_hash) VALUES (?, ?, ?, ?, ?)
+        INSERT INTO evaluation_output (main_file_path, accessor_path, output, main_file_hash, input_hash, created_at, last_used)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
         RETURNING id
       "#)
       .bind(file_attr.path.as_os_str().as_encoded_bytes())
@@ -109,15 +297,312 @@ pub fn insert_evaluation_output(file_attr: &FileAttribute, input_files: Vec<Path
       .bind(output)
       .bind(file_attr.hash.to_string())
       .bind(input_hash.to_string())
+      .bind(unix_time())
+      .bind(unix_time())
       .fetch_one(&mut *conn)
       .await?;
-    for file in input_files {
-      sqlx::query("INSERT INTO evaluation_input (evaluation_id, file_path) VALUES (?, ?)")
+    for input in inputs {
+      sqlx::query("INSERT INTO evaluation_input (evaluation_id, file_path, kind, hash, mtime, size) VALUES (?, ?, ?, ?, ?, ?)")
         .bind(evaluation_id)
-        .bind(file.as_os_str().as_encoded_bytes())
+        .bind(input.path.as_os_str().as_encoded_bytes())
+        .bind(input.kind.as_str())
+        .bind(input.hash)
+        .bind(input.mtime)
+        .bind(input.size)
+        .execute(&mut *conn)
+        .await?;
+    }
+    let settings = impure.settings.iter().map(|(name, value)| ("setting", name, Some(value)));
+    let env_vars = impure.env.iter().map(|(name, value)| ("env", name, value.as_ref()));
+    for (kind, name, value) in settings.chain(env_vars) {
+      sqlx::query("INSERT INTO evaluation_impure_input (evaluation_id, kind, name, value) VALUES (?, ?, ?, ?)")
+        .bind(evaluation_id)
+        .bind(kind)
+        .bind(name)
+        .bind(value)
         .execute(&mut *conn)
         .await?;
     }
     Ok(())
   })
 }
+
+
+/// Size of the pages of the database that are in use, in bytes.
+async fn database_size(pool: &Pool<Sqlite>) -> Result<u64> {
+  let (page_count, ): (i64, ) = sqlx::query_as("PRAGMA page_count").fetch_one(pool).await?;
+  let (freelist_count, ): (i64, ) = sqlx::query_as("PRAGMA freelist_count").fetch_one(pool).await?;
+  let (page_size, ): (i64, ) = sqlx::query_as("PRAGMA page_size").fetch_one(pool).await?;
+  Ok(((page_count - freelist_count) * page_size) as u64)
+}
+
+/// Deletes the entries that weren't used for longer than `max_age`, then the least recently used ones
+/// until the database is smaller than `max_size` bytes. Returns the number of deleted entries.
+pub fn evict(pool: &Pool<Sqlite>, max_age: Option<Duration>, max_size: Option<u64>) -> Result<u64> {
+  TOKIO_RT.block_on(async {
+    let mut deleted = 0;
+    if let Some(max_age) = max_age {
+      deleted += sqlx::query("DELETE FROM evaluation_output WHERE last_used < ?")
+        .bind(unix_time() - max_age.as_secs() as i64)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    }
+    if let Some(max_size) = max_size {
+      while database_size(pool).await? > max_size {
+        let batch = sqlx::query(r#"
+            DELETE FROM evaluation_output WHERE id IN (
+              SELECT id FROM evaluation_output ORDER BY last_used LIMIT ?
+            )
+          "#)
+          .bind(EVICTION_BATCH)
+          .execute(pool)
+          .await?
+          .rows_affected();
+        if batch == 0 {
+          break;
+        }
+        deleted += batch;
+      }
+    }
+    Ok(deleted)
+  })
+}
+
+/// Rebuilds the database, giving the space of deleted entries back to the file system.
+pub fn vacuum(pool: &Pool<Sqlite>) -> Result<()> {
+  TOKIO_RT.block_on(async {
+    sqlx::query("VACUUM").execute(pool).await?;
+    Ok(())
+  })
+}
+
+/// Deletes the entries of `file`, only those of `accessor_path` and the attributes under it if given.
+/// Returns the number of deleted entries.
+pub fn delete_entries(pool: &Pool<Sqlite>, file: &Path, accessor_path: Option<&str>) -> Result<u64> {
+  TOKIO_RT.block_on(async {
+    let query = match accessor_path {
+      Some(accessor_path) => sqlx::query(r#"
+          DELETE FROM evaluation_output
+          WHERE main_file_path = ? AND (accessor_path = ? OR substr(accessor_path, 1, length(?) + 1) = ? || '.')
+        "#)
+        .bind(file.as_os_str().as_encoded_bytes())
+        .bind(accessor_path)
+        .bind(accessor_path)
+        .bind(accessor_path),
+      None => sqlx::query("DELETE FROM evaluation_output WHERE main_file_path = ?")
+        .bind(file.as_os_str().as_encoded_bytes())
+    };
+    Ok(query.execute(pool).await?.rows_affected())
+  })
+}
+
+/// Deletes every entry. Returns the number of deleted entries.
+pub fn delete_all_entries(pool: &Pool<Sqlite>) -> Result<u64> {
+  TOKIO_RT.block_on(async {
+    Ok(sqlx::query("DELETE FROM evaluation_output").execute(pool).await?.rows_affected())
+  })
+}
+
+/// Columns of `evaluation_output` making a [`CacheEntry`].
+type EntryRow = (i64, Vec<u8>, String, String, String, String, i64, i64);
+
+/// Entries of `file`, or of every file, at `accessor_path` if given, from the most recently used one.
+pub fn query_entries(pool: &Pool<Sqlite>, file: Option<&Path>, accessor_path: Option<&str>) -> Result<Vec<CacheEntry>> {
+  TOKIO_RT.block_on(async {
+    let mut conn = pool.acquire().await?;
+    let rows: Vec<EntryRow> = sqlx::query_as(r#"
+        SELECT id, main_file_path, accessor_path, main_file_hash, input_hash, output, created_at, last_used
+        FROM evaluation_output
+        WHERE (?1 IS NULL OR main_file_path = ?1) AND (?2 IS NULL OR accessor_path = ?2)
+        ORDER BY last_used DESC
+      "#)
+      .bind(file.map(|f| f.as_os_str().as_encoded_bytes()))
+      .bind(accessor_path)
+      .fetch_all(&mut *conn)
+      .await?;
+    let mut entries = Vec::new();
+    for (id, path, accessor_path, file_hash, input_hash, output, created_at, last_used) in rows {
+      let inputs = query_inputs(&mut conn, id).await?;
+      let impure: Vec<(String, String, Option<String>)> = sqlx::query_as("SELECT kind, name, value FROM evaluation_impure_input WHERE evaluation_id = ?")
+        .bind(id)
+        .fetch_all(&mut *conn)
+        .await?;
+      let mut settings = BTreeMap::new();
+      let mut env = BTreeMap::new();
+      for (kind, name, value) in impure {
+        if kind == "setting" {
+          settings.insert(name, value.unwrap_or_default());
+        } else {
+          env.insert(name, value);
+        }
+      }
+      entries.push(CacheEntry {
+        id,
+        file: path_from_bytes(&path),
+        accessor_path: accessor_path.split('.').filter(|a| !a.is_empty()).map(String::from).collect(),
+        file_hash,
+        input_hash,
+        output,
+        created_at,
+        last_used,
+        inputs,
+        settings,
+        env
+      });
+    }
+    Ok(entries)
+  })
+}
+
+/// Distinct main files of the entries, along with the hashes they had when the entries were recorded.
+pub fn query_main_files(pool: &Pool<Sqlite>) -> Result<Vec<(PathBuf, String)>> {
+  TOKIO_RT.block_on(async {
+    let rows: Vec<(Vec<u8>, String)> = sqlx::query_as("SELECT DISTINCT main_file_path, main_file_hash FROM evaluation_output")
+      .fetch_all(pool)
+      .await?;
+    Ok(rows.into_iter().map(|(path, hash)| (path_from_bytes(&path), hash)).collect())
+  })
+}
+
+/// Deletes the entries recorded when `file` had the hash `file_hash`. Returns the number of deleted entries.
+pub fn delete_file_version(pool: &Pool<Sqlite>, file: &Path, file_hash: &str) -> Result<u64> {
+  TOKIO_RT.block_on(async {
+    Ok(sqlx::query("DELETE FROM evaluation_output WHERE main_file_path = ? AND main_file_hash = ?")
+      .bind(file.as_os_str().as_encoded_bytes())
+      .bind(file_hash)
+      .execute(pool)
+      .await?
+      .rows_affected())
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Inserts an entry without inputs for the attribute at `accessor_path` of `file`, returning its id.
+  fn insert(pool: &Pool<Sqlite>, file: &str, accessor_path: &str, output: &str) -> Result<i64> {
+    let file_attr = FileAttribute {
+      path: PathBuf::from(file),
+      hash: blake3::hash(file.as_bytes()),
+      accessor_path: accessor_path.split('.').filter(|a| !a.is_empty()).map(String::from).collect()
+    };
+    insert_evaluation_output(pool, &file_attr, Vec::new(), &ImpureInputs::default(), output)?;
+    let entries = query_entries(pool, Some(Path::new(file)), Some(accessor_path))?;
+    Ok(entries[0].id)
+  }
+
+  fn set_last_used(pool: &Pool<Sqlite>, id: i64, last_used: i64) -> Result<()> {
+    TOKIO_RT.block_on(sqlx::query("UPDATE evaluation_output SET last_used = ? WHERE id = ?").bind(last_used).bind(id).execute(pool))?;
+    Ok(())
+  }
+
+  /// Accessor paths of the entries of `file`, sorted.
+  fn accessor_paths(pool: &Pool<Sqlite>, file: &str) -> Result<Vec<String>> {
+    let mut paths: Vec<String> = query_entries(pool, Some(Path::new(file)), None)?
+      .into_iter()
+      .map(|entry| entry.accessor_path.join("."))
+      .collect();
+    paths.sort();
+    Ok(paths)
+  }
+
+  fn contents_input(path: &Path) -> RecordedInput {
+    RecordedInput { path: path.to_path_buf(), kind: InputKind::Contents, hash: None, mtime: None, size: None }
+  }
+
+  #[test]
+  fn directories_only_depend_on_their_entries() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::create_dir(dir.path().join("sub"))?;
+    let recorded = rehash_input(&contents_input(dir.path()))?;
+    assert!(recorded.mtime.is_some());
+    std::fs::write(dir.path().join("sub/file"), "")?;
+    assert_eq!(rehash_input(&recorded)?, recorded);
+    std::fs::write(dir.path().join("file"), "")?;
+    assert_ne!(rehash_input(&recorded)?.hash, recorded.hash);
+    Ok(())
+  }
+
+  #[test]
+  fn special_files_are_not_read() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fifo = dir.path().join("fifo");
+    nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU)?;
+    // reading either of them would block or never end.
+    for path in [fifo.as_path(), Path::new("/dev/zero")] {
+      let recorded = rehash_input(&contents_input(path))?;
+      assert_eq!(recorded.hash, Some(blake3::hash(b"other").to_string()), "{path:?}");
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn evicts_old_entries() -> Result<()> {
+    // each test has its own database, since the one of `CacheLocation::InMemory` is shared by the whole process.
+    let pool = open_in_memory()?;
+    let old = insert(&pool, "/default.nix", "old", "1")?;
+    insert(&pool, "/default.nix", "recent", "2")?;
+    set_last_used(&pool, old, unix_time() - 2 * 24 * 3600)?;
+    assert_eq!(evict(&pool, None, None)?, 0);
+    assert_eq!(evict(&pool, Some(Duration::from_secs(24 * 3600)), None)?, 1);
+    assert_eq!(accessor_paths(&pool, "/default.nix")?, ["recent"]);
+    Ok(())
+  }
+
+  #[test]
+  fn evicts_least_recently_used_entries_down_to_the_maximum_size() -> Result<()> {
+    let pool = open_in_memory()?;
+    let output = "x".repeat(4096);
+    let count = 4 * EVICTION_BATCH;
+    for i in 0..count {
+      let id = insert(&pool, "/default.nix", &format!("attr{i}"), &output)?;
+      // inserted in a different order than they were used.
+      set_last_used(&pool, id, (i * 7) % count)?;
+    }
+    let max_size = TOKIO_RT.block_on(database_size(&pool))? / 2;
+    let deleted = evict(&pool, None, Some(max_size))?;
+    assert!(deleted > 0 && deleted < count as u64, "{deleted}");
+    assert!(TOKIO_RT.block_on(database_size(&pool))? <= max_size);
+
+    let mut last_used: Vec<i64> = query_entries(&pool, None, None)?.iter().map(|entry| entry.last_used).collect();
+    last_used.sort();
+    let kept = (count as u64 - deleted) as i64;
+    assert_eq!(last_used, (count - kept..count).collect::<Vec<_>>());
+    Ok(())
+  }
+
+  #[test]
+  fn clears_attributes_and_the_ones_nested_in_them() -> Result<()> {
+    let pool = open_in_memory()?;
+    for accessor_path in ["a", "a.b", "a.b.c", "ab", "a_b", "b"] {
+      insert(&pool, "/default.nix", accessor_path, "1")?;
+    }
+    insert(&pool, "/other.nix", "a", "1")?;
+    assert_eq!(delete_entries(&pool, Path::new("/default.nix"), Some("a.b"))?, 2);
+    assert_eq!(accessor_paths(&pool, "/default.nix")?, ["a", "a_b", "ab", "b"]);
+    assert_eq!(delete_entries(&pool, Path::new("/default.nix"), Some("a"))?, 1);
+    assert_eq!(accessor_paths(&pool, "/default.nix")?, ["a_b", "ab", "b"]);
+    assert_eq!(delete_entries(&pool, Path::new("/default.nix"), None)?, 3);
+    assert_eq!(accessor_paths(&pool, "/other.nix")?, ["a"]);
+    Ok(())
+  }
+
+  #[test]
+  fn vacuum_frees_the_pages_of_deleted_entries() -> Result<()> {
+    let pool = open_in_memory()?;
+    for i in 0..16 {
+      insert(&pool, "/default.nix", &format!("attr{i}"), &"x".repeat(4096))?;
+    }
+    delete_entries(&pool, Path::new("/default.nix"), None)?;
+    let free_pages = || -> Result<i64> {
+      let (free_pages, ): (i64, ) = TOKIO_RT.block_on(sqlx::query_as("PRAGMA freelist_count").fetch_one(&pool))?;
+      Ok(free_pages)
+    };
+    assert!(free_pages()? > 0);
+    vacuum(&pool)?;
+    assert_eq!(free_pages()?, 0);
+    Ok(())
+  }
+}
@@ -0,0 +1,183 @@
+use std::fmt::Display;
+use anyhow::Result;
+use serde_json::Value;
+use thiserror::Error;
+use crate::error::NixErrorKind;
+use crate::term::NixEvalError;
+use super::value::{CachedValue, CACHED_VALUE_VERSION};
+
+/// Errors thrown by nix that only depend on the evaluated files, and can be cached.
+///
+/// Nix reports the name of the class of the exception, and most evaluation errors, like coercion failures
+/// or duplicate attributes, are thrown as the base `EvalError`. Errors depending on the environment aren't listed:
+/// `SysError` and `FileTransferError` for I/O failures, `BuildError` and `IFDError` for import from derivation,
+/// `StackOverflowError`, which depends on the stack size, and `Interrupted`.
+const DETERMINISTIC_ERRORS: &[&str] = &[
+  "ThrownError",
+  "AssertionError",
+  "Abort",
+  "EvalError",
+  "TypeError",
+  "UndefinedVarError",
+  "MissingArgumentError",
+  "InfiniteRecursionError",
+  "ParseError"
+];
+
+/// Whether nix errors named `name`, like `nix::ThrownError`, are deterministic.
+fn is_deterministic(name: &str) -> bool {
+  name.rsplit("::").next().is_some_and(|name| DETERMINISTIC_ERRORS.contains(&name))
+}
+
+/// Error of an evaluation done in the traced process, sent back to the caller of
+/// [`eval_attr_from_file`](crate::eval::NixEvalState::eval_attr_from_file).
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub struct EvaluationError {
+  /// Name of the error thrown by nix, `None` for errors that didn't come from nix.
+  pub name: Option<String>,
+  pub message: String,
+  /// Full error reported by nix, with the trace of where it was thrown.
+  pub trace: Option<String>,
+  /// Whether evaluating the same files again would fail the same way, so that the error can be cached.
+  pub deterministic: bool
+}
+
+impl Display for EvaluationError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.trace {
+      Some(trace) => write!(f, "{trace}"),
+      None => write!(f, "{}", self.message)
+    }
+  }
+}
+
+impl From<&NixEvalError> for EvaluationError {
+  fn from(error: &NixEvalError) -> Self {
+    match error {
+      NixEvalError::RuntimeError(e) => {
+        let trace = Some(e.message().to_string()).filter(|msg| !msg.is_empty());
+        match e.kind() {
+          NixErrorKind::GenericError { info_msg, name } => EvaluationError {
+            deterministic: is_deterministic(name),
+            name: Some(name.clone()),
+            message: info_msg.clone(),
+            trace
+          },
+          kind => EvaluationError {
+            name: None,
+            message: kind.to_string(),
+            trace,
+            // missing attributes in the accessor path.
+            deterministic: matches!(kind, NixErrorKind::KeyError)
+          }
+        }
+      }
+      // errors of the bindings, while converting values.
+      e => EvaluationError {
+        name: None,
+        message: e.to_string(),
+        trace: None,
+        deterministic: matches!(e, NixEvalError::TypeError { .. } | NixEvalError::AttrPathEmpty)
+      }
+    }
+  }
+}
+
+impl From<anyhow::Error> for EvaluationError {
+  fn from(error: anyhow::Error) -> Self {
+    match error.downcast_ref::<NixEvalError>() {
+      Some(e) => EvaluationError::from(e),
+      None => EvaluationError { name: None, message: format!("{error:#}"), trace: None, deterministic: false }
+    }
+  }
+}
+
+/// Result of an evaluation, as sent by the traced process and stored in the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Output {
+  Value(CachedValue),
+  Error(EvaluationError)
+}
+
+impl Output {
+  /// Serializes the output to a single line of versioned JSON.
+  pub fn serialize(&self) -> Result<String> {
+    match self {
+      Output::Value(value) => value.serialize(),
+      Output::Error(error) => Ok(serde_json::to_string(&serde_json::json!({
+        "version": CACHED_VALUE_VERSION,
+        "error": {
+          "name": error.name,
+          "message": error.message,
+          "trace": error.trace,
+          "deterministic": error.deterministic
+        }
+      }))?)
+    }
+  }
+
+  /// Parses a serialized output, returning `None` if it was written in another version of the format.
+  pub fn deserialize(serialized: &str) -> Result<Option<Self>> {
+    let json: Value = serde_json::from_str(serialized)?;
+    let Some(error) = json.get("error") else {
+      return Ok(CachedValue::deserialize(serialized)?.map(Output::Value));
+    };
+    if json.get("version").and_then(Value::as_u64) != Some(CACHED_VALUE_VERSION) {
+      return Ok(None);
+    }
+    let string = |name: &str| error.get(name).and_then(Value::as_str).map(String::from);
+    Ok(Some(Output::Error(EvaluationError {
+      name: string("name"),
+      message: string("message").unwrap_or_default(),
+      trace: string("trace"),
+      deterministic: error.get("deterministic").and_then(Value::as_bool).unwrap_or(false)
+    })))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn evaluation_errors_are_deterministic() {
+    for name in ["nix::ThrownError", "nix::EvalError", "nix::InfiniteRecursionError", "ParseError"] {
+      assert!(is_deterministic(name), "{name}");
+    }
+    for name in ["nix::SysError", "nix::FileTransferError", "nix::BuildError", "nix::Interrupted", "nix::Error", "nix::EvalErrorX"] {
+      assert!(!is_deterministic(name), "{name}");
+    }
+  }
+
+  #[test]
+  fn errors_of_the_bindings_and_tracers() {
+    let type_error = NixEvalError::TypeError { expected: "string".to_string(), got: "int".to_string() };
+    let error = EvaluationError::from(anyhow::Error::from(type_error));
+    assert_eq!(error.message, "Type error, expected 'string' but got 'int'");
+    assert!(error.deterministic);
+    assert!(!EvaluationError::from(anyhow::Error::from(NixEvalError::InvalidString)).deterministic);
+
+    let error = EvaluationError::from(anyhow::format_err!("tracer failed").context("while evaluating"));
+    assert_eq!(error, EvaluationError {
+      name: None,
+      message: "while evaluating: tracer failed".to_string(),
+      trace: None,
+      deterministic: false
+    });
+  }
+
+  #[test]
+  fn errors_round_trip() -> Result<()> {
+    let error = Output::Error(EvaluationError {
+      name: Some("nix::ThrownError".to_string()),
+      message: "boom".to_string(),
+      trace: Some("error: boom".to_string()),
+      deterministic: true
+    });
+    assert_eq!(Output::deserialize(&error.serialize()?)?, Some(error));
+    let value = Output::Value(CachedValue::Int(1));
+    assert_eq!(Output::deserialize(&value.serialize()?)?, Some(value));
+    assert_eq!(Output::deserialize(r#"{"version":0,"error":{"message":"boom"}}"#)?, None);
+    Ok(())
+  }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use anyhow::Result;
+use crate::eval::NixEvalState;
+use crate::term::force;
+
+/// Name recorded for evaluations that read environment variables with names that aren't known statically,
+/// which depend on the whole environment.
+pub(super) const ALL_ENV_VARS: &str = "*";
+
+/// Settings of the evaluator that change the result of an evaluation, as JSON.
+const EVALUATOR_SETTINGS: &str = r#"
+builtins.mapAttrs (_: builtins.toJSON) {
+  pure = !(builtins ? currentTime);
+  system = builtins.currentSystem or null;
+  nixPath = builtins.nixPath or [];
+}
+"#;
+
+/// Possible use of an impure builtin, found in the source of a nix file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum ImpureAccess {
+  Env(String),
+  /// `getEnv` not applied to a string literal, e.g. through an alias.
+  AnyEnv,
+  CurrentTime
+}
+
+/// Evaluator settings and environment variables that an evaluation depended on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) struct ImpureInputs {
+  pub settings: BTreeMap<String, String>,
+  /// Values of the environment variables read with `builtins.getEnv`, `None` for unset ones.
+  pub env: BTreeMap<String, Option<String>>
+}
+
+impl ImpureAccess {
+  /// Finds the mentions of `getEnv` and `currentTime` in `source`, including `__getEnv` and aliases.
+  ///
+  /// Nix reads these without any syscall the file tracer could see, so the nix files read by an evaluation are
+  /// scanned instead. This is conservative: mentions in comments or in code that isn't evaluated count as uses.
+  /// Builtins accessed with a computed name, as in `builtins.${"getEnv"}`, aren't found.
+  pub fn scan(source: &str) -> Vec<Self> {
+    let env = mentions(source, "getEnv").map(|rest| match string_literal(rest.trim_start()) {
+      Some(name) => ImpureAccess::Env(name.to_string()),
+      None => ImpureAccess::AnyEnv
+    });
+    let time = mentions(source, "currentTime").map(|_| ImpureAccess::CurrentTime);
+    env.chain(time).collect()
+  }
+}
+
+/// The rest of `source` after each occurrence of the identifier `name`.
+fn mentions<'s>(source: &'s str, name: &'s str) -> impl Iterator<Item=&'s str> {
+  source
+    .match_indices(name)
+    .map(move |(i, _)| &source[i + name.len()..])
+    .filter(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '\'' || c == '-'))
+}
+
+/// Contents of the string literal at the start of `source`, if it has no escapes or interpolations.
+fn string_literal(source: &str) -> Option<&str> {
+  let (contents, _) = source.strip_prefix('"')?.split_once('"')?;
+  (!contents.contains(['\\', '$'])).then_some(contents)
+}
+
+impl ImpureInputs {
+  /// `settings` along with the current values of the environment variables `env_vars`.
+  pub fn new<I: IntoIterator<Item=String>>(settings: BTreeMap<String, String>, env_vars: I) -> Self {
+    let env = env_vars
+      .into_iter()
+      .map(|name| {
+        let value = env_value(&name);
+        (name, value)
+      })
+      .collect();
+    ImpureInputs { settings, env }
+  }
+
+  pub fn update_hash(&self, hasher: &mut blake3::Hasher) {
+    for (name, value) in &self.settings {
+      hasher.update(name.as_bytes());
+      hasher.update(b"=");
+      hasher.update(value.as_bytes());
+      hasher.update(b"\0");
+    }
+    for (name, value) in &self.env {
+      hasher.update(b"$");
+      hasher.update(name.as_bytes());
+      match value {
+        Some(value) => {
+          hasher.update(b"=");
+          hasher.update(value.as_bytes());
+        }
+        None => {
+          hasher.update(b" unset");
+        }
+      }
+      hasher.update(b"\0");
+    }
+  }
+}
+
+/// Value of the environment variable `name`, or a hash of the whole environment for [`ALL_ENV_VARS`].
+fn env_value(name: &str) -> Option<String> {
+  if name != ALL_ENV_VARS {
+    return std::env::var_os(name).map(|v| v.to_string_lossy().into_owned());
+  }
+  let mut vars: Vec<_> = std::env::vars_os().collect();
+  vars.sort();
+  let mut hasher = blake3::Hasher::new();
+  for (name, value) in vars {
+    hasher.update(name.as_bytes());
+    hasher.update(b"=");
+    hasher.update(value.as_bytes());
+    hasher.update(b"\0");
+  }
+  Some(hasher.finalize().to_string())
+}
+
+impl NixEvalState {
+  /// Current values of the settings that change the result of evaluations.
+  pub(super) fn evaluator_settings(&self) -> Result<BTreeMap<String, String>> {
+    let settings = self.eval_expr(EVALUATOR_SETTINGS, Path::new("/"))?;
+    settings
+      .items()?
+      .map(|(name, value)| Ok((name, force(value?)?.as_string()?)))
+      .collect()
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hash(impure: &ImpureInputs) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    impure.update_hash(&mut hasher);
+    hasher.finalize()
+  }
+
+  #[test]
+  fn finds_environment_variables() {
+    let source = r#"{ home = builtins.getEnv "HOME"; user = __getEnv  "USER"; }"#;
+    let accesses = ImpureAccess::scan(source);
+    assert_eq!(accesses, vec![ImpureAccess::Env("HOME".to_string()), ImpureAccess::Env("USER".to_string())]);
+  }
+
+  #[test]
+  fn aliases_depend_on_every_variable() {
+    let source = r#"let get = builtins.getEnv; in [ (get "HOME") (builtins.getEnv "${"HO"}ME") ]"#;
+    assert_eq!(ImpureAccess::scan(source), vec![ImpureAccess::AnyEnv, ImpureAccess::AnyEnv]);
+  }
+
+  #[test]
+  fn finds_the_current_time() {
+    assert_eq!(ImpureAccess::scan("builtins.currentTime"), vec![ImpureAccess::CurrentTime]);
+    assert_eq!(ImpureAccess::scan("{ currentTimeZone = 1; getEnvironment = 2; }"), vec![]);
+  }
+
+  #[test]
+  fn environment_variables_change_the_hash() {
+    let inputs = |value: Option<&str>| ImpureInputs {
+      settings: BTreeMap::from([("pure".to_string(), "false".to_string())]),
+      env: BTreeMap::from([("VALUE".to_string(), value.map(String::from))])
+    };
+    assert_eq!(hash(&inputs(Some("before"))), hash(&inputs(Some("before"))));
+    assert_ne!(hash(&inputs(Some("before"))), hash(&inputs(Some("after"))));
+    assert_ne!(hash(&inputs(Some(""))), hash(&inputs(None)));
+    let settings = ImpureInputs { settings: BTreeMap::from([("VALUE".to_string(), "before".to_string())]), env: BTreeMap::new() };
+    assert_ne!(hash(&settings), hash(&inputs(Some("before"))));
+  }
+
+  /// The environment is only read, since changing it would race with the tests that fork.
+  #[test]
+  fn reads_the_current_environment() {
+    let unset = "NIX_FOR_RUST_UNSET_VARIABLE";
+    let names = ["PATH", unset, ALL_ENV_VARS].map(String::from);
+    let impure = ImpureInputs::new(BTreeMap::new(), names.clone());
+    assert_eq!(impure.env["PATH"], std::env::var("PATH").ok());
+    assert_eq!(impure.env[unset], None);
+    assert!(impure.env[ALL_ENV_VARS].is_some());
+    assert_eq!(ImpureInputs::new(BTreeMap::new(), names), impure);
+  }
+}
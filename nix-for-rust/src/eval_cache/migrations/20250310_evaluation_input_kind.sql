@@ -0,0 +1,2 @@
+-- what the evaluation depended on: 'contents', 'metadata' (existence and type) or 'link' (symlink target)
+ALTER TABLE evaluation_input ADD COLUMN kind TEXT NOT NULL DEFAULT 'contents';
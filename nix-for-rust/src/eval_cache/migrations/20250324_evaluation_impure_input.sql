@@ -0,0 +1,11 @@
+-- evaluator settings and environment variables that an evaluation depended on, also part of its input_hash
+CREATE TABLE IF NOT EXISTS evaluation_impure_input (
+  evaluation_id  INTEGER NOT NULL,
+  kind           TEXT NOT NULL, -- 'setting' or 'env'
+  name           TEXT NOT NULL,
+  value          TEXT, -- NULL for unset environment variables
+  FOREIGN KEY (evaluation_id) REFERENCES evaluation_output(id)
+    ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_evaluation_impure_input_evaluation_id ON evaluation_impure_input(evaluation_id);
@@ -0,0 +1,7 @@
+-- unix time at which an entry was inserted and last returned from the cache, used to evict old entries
+ALTER TABLE evaluation_output ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE evaluation_output ADD COLUMN last_used INTEGER NOT NULL DEFAULT 0;
+
+UPDATE evaluation_output SET created_at = CAST(strftime('%s', 'now') AS INTEGER), last_used = CAST(strftime('%s', 'now') AS INTEGER);
+
+CREATE INDEX IF NOT EXISTS idx_evaluation_output_last_used ON evaluation_output(last_used);
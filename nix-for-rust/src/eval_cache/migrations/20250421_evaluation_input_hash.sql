@@ -0,0 +1,5 @@
+-- hash of each input, and the modification time (in nanoseconds) and size of regular files when they were hashed,
+-- so that unchanged files aren't hashed again. NULL for inputs recorded before they were stored.
+ALTER TABLE evaluation_input ADD COLUMN hash CHAR(64);
+ALTER TABLE evaluation_input ADD COLUMN mtime INTEGER;
+ALTER TABLE evaluation_input ADD COLUMN size INTEGER;
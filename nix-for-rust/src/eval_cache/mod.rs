@@ -1,17 +1,89 @@
+mod arch;
+mod cache;
 mod db;
+mod error;
+mod impure;
+mod ptrace;
+mod seccomp;
 mod trace;
+mod value;
 
 use anyhow::Result;
 use interprocess::unnamed_pipe::Sender;
-use nix::sys::signal;
+use nix::libc;
+use nix::sys::signal::{self, Signal};
 use nix::unistd::{fork, ForkResult};
-use trace::FileTracer;
+use error::Output;
+use impure::ImpureInputs;
+use trace::{Dependencies, Tracer};
+use std::collections::BTreeSet;
 use std::io::{BufReader, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{PathBuf, Path};
-use nix::sys::ptrace;
+use std::time::Duration;
 
 use crate::eval::NixEvalState;
-use crate::term::{NixTerm, Repr};
+use crate::term::{NixTerm, ToNix};
+pub use cache::{CacheEntry, EvalCache, Invalidation, RecordedInput};
+pub use error::EvaluationError;
+pub use trace::TracerBackend;
+pub use value::CachedValue;
+
+/// Where the evaluation cache is stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum CacheLocation {
+  /// `~/.cache/nix-for-rust/eval-cache/sqlite-v1.db`
+  #[default]
+  Default,
+  /// SQLite database at the given path, created if it doesn't exist.
+  File(PathBuf),
+  /// Database shared by the whole process and lost when it exits, mostly useful for tests.
+  InMemory
+}
+
+/// Settings of the evaluation cache used by [`NixEvalState::eval_attr_from_file`].
+#[derive(Debug, Clone, Default)]
+pub struct EvalCacheSettings {
+  pub tracer: TracerBackend,
+  pub location: CacheLocation,
+  /// Entries that weren't used for longer than this are evicted.
+  pub max_age: Option<Duration>,
+  /// Maximum size of the database in bytes, the least recently used entries are evicted past it.
+  pub max_size: Option<u64>,
+  /// Whether evaluation errors that only depend on the evaluated files are cached.
+  pub cache_failures: bool
+}
+
+impl EvalCacheSettings {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_tracer(mut self, tracer: TracerBackend) -> Self {
+    self.tracer = tracer;
+    self
+  }
+
+  pub fn with_location(mut self, location: CacheLocation) -> Self {
+    self.location = location;
+    self
+  }
+
+  pub fn with_max_age(mut self, max_age: Duration) -> Self {
+    self.max_age = Some(max_age);
+    self
+  }
+
+  pub fn with_max_size(mut self, max_size: u64) -> Self {
+    self.max_size = Some(max_size);
+    self
+  }
+
+  pub fn with_cache_failures(mut self, cache_failures: bool) -> Self {
+    self.cache_failures = cache_failures;
+    self
+  }
+}
 
 struct FileAttribute {
   path: PathBuf,
@@ -29,56 +101,142 @@ impl FileAttribute {
   }
 }
 
+/// What part of an input file the evaluation depended on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum InputKind {
+  /// Contents of a file, or the entries of a directory.
+  Contents,
+  /// Only whether the file exists, and its type.
+  Metadata,
+  /// Target of a symbolic link.
+  Link
+}
+
+/// A file read while evaluating, stored in `evaluation_input`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct FileInput {
+  path: PathBuf,
+  kind: InputKind
+}
+
+impl InputKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      InputKind::Contents => "contents",
+      InputKind::Metadata => "metadata",
+      InputKind::Link => "link"
+    }
+  }
+
+  fn from_str(kind: &str) -> Result<Self> {
+    match kind {
+      "contents" => Ok(InputKind::Contents),
+      "metadata" => Ok(InputKind::Metadata),
+      "link" => Ok(InputKind::Link),
+      _ => anyhow::bail!("Unknown kind of input '{kind}'")
+    }
+  }
+}
+
 impl NixEvalState {
 
-  fn evaluate_traced<'s, S: AsRef<str>, I: IntoIterator<Item=S>, P: AsRef<Path>>(&self, file: P, accessor_path: I, mut sender: Sender) {
-    ptrace::traceme().unwrap();
-    signal::raise(signal::Signal::SIGSTOP).unwrap();
-    let path = accessor_path
+  /// Evaluates the attribute in the traced process, and sends its value or error on a single line.
+  fn evaluate_traced<S: AsRef<str>, I: IntoIterator<Item=S>, P: AsRef<Path>>(&self, tracer: &mut dyn Tracer, file: P, accessor_path: I, mut sender: Sender) {
+    // evaluating without being traced would cache results with missing dependencies.
+    let value = tracer.attach().and_then(|()| accessor_path
       .into_iter()
       .fold(self.eval_file(file), |attr, accessor| attr.and_then(|term| term
         .get(accessor.as_ref())
-        .map_err(|e| anyhow::format_err!(e))))
-      .and_then(|term| match term {
-        NixTerm::String(p) => Ok(p),
-        other => Err(anyhow::format_err!("Attribute did not evaluate to string: '{}'", other.repr().unwrap()))
-      });
-    match path {
-      Ok(p) => {
-        sender.write_all(p.as_bytes()).unwrap();
-        sender.write(b"\n").unwrap();
-      },
-      Err(e) => {
-        sender.write_all(b"\n").unwrap();
-        eprintln!("{e}");
-      },
-    }
+        .map_err(anyhow::Error::from)))
+      .and_then(CachedValue::from_term));
+    let output = match value {
+      Ok(value) => Output::Value(value),
+      Err(e) => Output::Error(EvaluationError::from(e))
+    };
+    let serialized = output
+      .serialize()
+      .or_else(|e| Output::Error(EvaluationError::from(e)).serialize())
+      .unwrap_or_default();
+    // if this fails, the parent sees the pipe closing without a result.
+    let _ = sender.write_all(serialized.as_bytes()).and_then(|()| sender.write_all(b"\n"));
   }
 
-  pub fn eval_attr_from_file<S: AsRef<str>, I: IntoIterator<Item=S> + Clone, P: AsRef<Path>>(&self, file: P, accessor_path: I) -> Result<String> {
-    let file_attribute = FileAttribute::new(&file, accessor_path.clone())?;
-    if let Some(p) = db::query_attr_in_cache(&file_attribute)? {
-      Ok(p)
-    } else {
-      let (sender, receiver) = interprocess::unnamed_pipe::pipe()?;
-      match unsafe { fork()? } {
-        ForkResult::Child => {
-          self.evaluate_traced(file, accessor_path, sender);
-          std::process::exit(0);
-        }
-        ForkResult::Parent { child } => {
-          let tracer = FileTracer::new();
-          let input_files = tracer.watch(child)?;
+  /// Evaluates the attribute in a traced child process, returning its output as serialized and what it depended on.
+  fn evaluate_in_child<S: AsRef<str>, I: IntoIterator<Item=S>, P: AsRef<Path>>(&self, file: P, accessor_path: I) -> Result<(String, Dependencies)> {
+    let (sender, receiver) = interprocess::unnamed_pipe::pipe()?;
+    let mut tracer = self.settings.eval_cache.tracer.tracer()?;
+    match unsafe { fork()? } {
+      ForkResult::Child => {
+        drop(receiver);
+        // the child must exit even if evaluating panics, instead of unwinding into the code of the caller.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| self.evaluate_traced(tracer.as_mut(), file, accessor_path, sender)));
+        // the exit handlers of the parent, like the ones of nix, must not run in the copy of its state.
+        unsafe { libc::_exit(0) };
+      }
+      ForkResult::Parent { child } => {
+        // otherwise the pipe would never be closed if the child exits without writing.
+        drop(sender);
+        // the child blocks on writing outputs larger than the pipe buffer, so they are read while tracing it.
+        let reader = std::thread::spawn(move || -> std::io::Result<String> {
           let mut output = String::new();
           BufReader::new(receiver).read_line(&mut output)?;
-          let out = output.trim();
-          if out.is_empty() {
-            return Err(anyhow::format_err!("Error while evaluating expression"));
-          };
-          db::insert_evaluation_output(&file_attribute, input_files.into_iter().collect(), &out)?;
           Ok(output)
+        });
+        let dependencies = tracer.watch(child).inspect_err(|_| {
+          let _ = signal::kill(child, Signal::SIGKILL);
+        })?;
+        let output = reader
+          .join()
+          .map_err(|_| anyhow::format_err!("Could not read the output of the evaluation process"))??;
+        let output = output.trim();
+        if output.is_empty() {
+          anyhow::bail!("Evaluation process exited without a result");
         }
+        Ok((output.to_string(), dependencies))
       }
     }
   }
+
+  /// Evaluates the attribute at `accessor_path` of the nix file `file`, caching the fully evaluated result
+  /// until the file, any of the files read while evaluating it, the environment variables it read
+  /// or the evaluator settings change. Results depending on `builtins.currentTime` aren't cached.
+  ///
+  /// Uses of `builtins.getEnv` and `builtins.currentTime` can't be traced, so they are looked for in the nix files
+  /// that were read, which can add dependencies on variables or the time that weren't actually read.
+  /// Reaching them with a computed attribute name, as in `builtins.${"getEnv"}`, isn't detected.
+  ///
+  /// Evaluation errors are returned as [`EvaluationError`], and cached along with results if they are deterministic
+  /// and [`EvalCacheSettings::cache_failures`] is set.
+  pub fn eval_attr_from_file<'state, S: AsRef<str>, I: IntoIterator<Item=S> + Clone, P: AsRef<Path>>(&'state self, file: P, accessor_path: I) -> Result<NixTerm<'state>> {
+    let file_attribute = FileAttribute::new(&file, accessor_path.clone())?;
+    let settings = self.evaluator_settings()?;
+    let cache = EvalCache::open(&self.settings.eval_cache)?;
+    let cache_failures = self.settings.eval_cache.cache_failures;
+    let cached = cache.query(&file_attribute, &settings)?
+      .and_then(|serialized| Output::deserialize(&serialized).ok().flatten());
+    match cached {
+      Some(Output::Value(value)) => return Ok(value.to_nix(self)?),
+      Some(Output::Error(error)) if cache_failures => return Err(error.into()),
+      _ => ()
+    }
+    let (serialized, dependencies) = self.evaluate_in_child(file, accessor_path)?;
+    let output = Output::deserialize(&serialized)?
+      .ok_or_else(|| anyhow::format_err!("Evaluation returned a value in an unknown format"))?;
+    let cacheable = match &output {
+      Output::Value(_) => true,
+      Output::Error(error) => cache_failures && error.deterministic
+    };
+    // in pure mode, `getEnv` always returns an empty string and `currentTime` doesn't exist.
+    let pure = settings.get("pure").is_some_and(|pure| pure == "true");
+    if cacheable && (pure || !dependencies.current_time) {
+      let env_vars = if pure { BTreeSet::new() } else { dependencies.env_vars };
+      let impure = ImpureInputs::new(settings, env_vars);
+      cache.insert(&file_attribute, dependencies.inputs.into_iter().collect(), &impure, &serialized)?;
+      cache.evict()?;
+    }
+    match output {
+      Output::Value(value) => Ok(value.to_nix(self)?),
+      Output::Error(error) => Err(error.into())
+    }
+  }
 }
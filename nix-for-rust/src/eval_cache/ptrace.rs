@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use anyhow::{Context, Result};
+use nix::libc::{self, AT_FDCWD};
+use nix::sys::signal::{self, Signal};
+use nix::errno::Errno;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::sys::ptrace::{self, AddressType, Event, Options};
+use nix::unistd::Pid;
+
+use super::arch::{get_registers, Registers, Syscall, SyscallRegisters};
+use super::trace::{join_relative, Dependencies, DependencyRecorder, Tracer};
+use super::InputKind;
+
+/// Path given to a syscall, relative to `dirfd` when it isn't absolute.
+struct PathArg {
+  dirfd: i32,
+  path: PathBuf
+}
+
+enum FileAccess {
+  /// `fd` is `None` when the file could not be opened.
+  OpenFile { path: PathArg, fd: Option<i32>, close_on_exec: bool },
+  FileRead { fd: i32 },
+  ListDir { fd: i32 },
+  Stat { path: PathArg },
+  StatFd { fd: i32 },
+  ReadLink { path: PathArg },
+  Dup { fd: i32, new_fd: i32, close_on_exec: bool },
+  SetCloseOnExec { fd: i32, close_on_exec: bool },
+  Close { fd: i32 }
+}
+
+/// File opened by a tracee.
+#[derive(Debug, Clone, PartialEq)]
+struct OpenFile {
+  path: PathBuf,
+  /// Whether the file descriptor is closed when its process calls exec.
+  close_on_exec: bool
+}
+
+/// Return value of a syscall returning a file descriptor, `None` if it failed.
+fn returned_fd(regs: &Registers) -> Option<i32> {
+  let fd = regs.return_value() as i64;
+  (fd >= 0).then_some(fd as i32)
+}
+
+impl PathArg {
+  fn read(pid: Pid, dirfd: i32, address: u64) -> Self {
+    PathArg { dirfd, path: read_path_from_register(pid, address as AddressType) }
+  }
+}
+
+/// Syscall accessing files that a tracee entered, with the arguments needed once it exits.
+struct SyscallEntry {
+  syscall: Syscall,
+  regs: Registers,
+  path: Option<PathArg>,
+  /// Flags of the file descriptor created by opens and `dup3`.
+  flags: u64
+}
+
+impl SyscallEntry {
+  /// Reads the arguments of the syscall `pid` is entering, `None` for syscalls that don't access files.
+  fn read(pid: Pid) -> nix::Result<Option<Self>> {
+    let regs = get_registers(pid)?;
+    let Some(syscall) = regs.syscall() else {
+      return Ok(None);
+    };
+    let path = match syscall {
+      Syscall::Open | Syscall::Stat | Syscall::ReadLink => Some(PathArg::read(pid, AT_FDCWD, regs.arg(0))),
+      Syscall::OpenAt | Syscall::OpenAt2 | Syscall::StatAt | Syscall::ReadLinkAt => Some(PathArg::read(pid, regs.arg(0) as i32, regs.arg(1))),
+      _ => None
+    };
+    let flags = match syscall {
+      Syscall::Open => regs.arg(1),
+      Syscall::OpenAt | Syscall::Dup3 => regs.arg(2),
+      // the flags are the first field of `struct open_how`.
+      Syscall::OpenAt2 => ptrace::read(pid, regs.arg(2) as AddressType).map(|flags| flags as u64).unwrap_or(0),
+      _ => 0
+    };
+    Ok(Some(SyscallEntry { syscall, regs, path, flags }))
+  }
+
+  /// Access done by the syscall, given the registers at syscall exit.
+  fn finish(self, exit_regs: &Registers) -> Option<FileAccess> {
+    let fd = self.regs.arg(0) as i32;
+    let close_on_exec = self.flags & libc::O_CLOEXEC as u64 != 0;
+    let access = match (self.syscall, self.path) {
+      (Syscall::Read, _) => FileAccess::FileRead { fd },
+      (Syscall::Mmap, _) => {
+        // anonymous mappings have a fd of -1.
+        let fd = self.regs.arg(4) as i32;
+        if fd < 0 {
+          return None;
+        }
+        FileAccess::FileRead { fd }
+      }
+      (Syscall::Open | Syscall::OpenAt | Syscall::OpenAt2, Some(path)) => {
+        FileAccess::OpenFile { path, fd: returned_fd(exit_regs), close_on_exec }
+      }
+      (Syscall::Stat | Syscall::StatAt, Some(path)) => FileAccess::Stat { path },
+      (Syscall::ReadLink | Syscall::ReadLinkAt, Some(path)) => FileAccess::ReadLink { path },
+      (Syscall::FStat, _) => FileAccess::StatFd { fd },
+      (Syscall::GetDents, _) => FileAccess::ListDir { fd },
+      // duplicates don't keep the close-on-exec flag of the original file descriptor.
+      (Syscall::Dup, _) => FileAccess::Dup { fd, new_fd: returned_fd(exit_regs)?, close_on_exec: false },
+      (Syscall::Dup3, _) => FileAccess::Dup { fd, new_fd: returned_fd(exit_regs)?, close_on_exec },
+      (Syscall::Fcntl, _) => {
+        let command = self.regs.arg(1) as i32;
+        match command {
+          libc::F_DUPFD | libc::F_DUPFD_CLOEXEC => {
+            FileAccess::Dup { fd, new_fd: returned_fd(exit_regs)?, close_on_exec: command == libc::F_DUPFD_CLOEXEC }
+          }
+          // the flags are unchanged when it fails.
+          libc::F_SETFD if returned_fd(exit_regs).is_some() => {
+            FileAccess::SetCloseOnExec { fd, close_on_exec: self.regs.arg(2) as i32 & libc::FD_CLOEXEC != 0 }
+          }
+          _ => return None
+        }
+      }
+      (Syscall::Close, _) => FileAccess::Close { fd },
+      (_, None) => return None
+    };
+    Some(access)
+  }
+}
+
+/// Thread or process being traced.
+struct Tracee {
+  /// Index of its file descriptor table in [`FileTracer::fd_tables`].
+  fd_table: usize,
+  /// Whether the tracee is between the entry and the exit of a syscall.
+  in_syscall: bool,
+  entry: Option<SyscallEntry>,
+  /// Whether the stop of new tracees, that happens before they run, was seen.
+  started: bool
+}
+
+impl Tracee {
+  fn new(fd_table: usize, started: bool) -> Self {
+    Tracee { fd_table, in_syscall: false, entry: None, started }
+  }
+}
+
+/// Wait status of a tracee, sent by the thread waiting for it.
+type TraceeStatus = (Pid, nix::Result<WaitStatus>);
+
+/// Traces the file accesses of a process, its threads and its subprocesses, by stopping them on every syscall.
+pub(super) struct PtraceTracer {
+  recorder: DependencyRecorder,
+  tracees: HashMap<Pid, Tracee>,
+  /// File descriptor tables, shared by the threads of a process.
+  fd_tables: Vec<HashMap<i32, OpenFile>>,
+  status_sender: Sender<TraceeStatus>,
+  statuses: Receiver<TraceeStatus>
+}
+
+impl PtraceTracer {
+  pub fn new() -> Self {
+    let (status_sender, statuses) = channel();
+    PtraceTracer {
+      recorder: DependencyRecorder::new(),
+      tracees: HashMap::new(),
+      fd_tables: Vec::new(),
+      status_sender,
+      statuses
+    }
+  }
+
+  /// Starts tracing `pid`, whose stops are waited for by a thread of its own until it exits.
+  ///
+  /// Waiting for any child would also reap the other children of the process, which belong to whoever spawned them,
+  /// so each tracee is waited for by pid. Only the tracer thread can resume tracees, but any thread of its process can wait for them.
+  fn add_tracee(&mut self, pid: Pid, tracee: Tracee) {
+    self.tracees.insert(pid, tracee);
+    let statuses = self.status_sender.clone();
+    std::thread::spawn(move || loop {
+      // threads are only reported with __WALL.
+      let status = waitpid(pid, Some(WaitPidFlag::__WALL));
+      let stopped = matches!(status, Ok(WaitStatus::PtraceSyscall(_) | WaitStatus::PtraceEvent(..) | WaitStatus::Stopped(..)));
+      // the tracer stopped listening after failing.
+      if statuses.send((pid, status)).is_err() || !stopped {
+        break;
+      }
+    });
+  }
+
+  fn fd_table(&mut self, pid: Pid) -> &mut HashMap<i32, OpenFile> {
+    let index = self.tracees.get(&pid).map(|tracee| tracee.fd_table).unwrap_or(0);
+    &mut self.fd_tables[index]
+  }
+
+  /// Makes `path` absolute, returning `None` if it is relative to an unknown file descriptor.
+  fn resolve(&mut self, pid: Pid, path: PathArg) -> Option<PathBuf> {
+    if path.path.is_absolute() {
+      return Some(path.path);
+    }
+    let dir = if path.dirfd == AT_FDCWD {
+      std::fs::read_link(format!("/proc/{pid}/cwd")).ok()?
+    } else {
+      self.fd_table(pid).get(&path.dirfd)?.path.clone()
+    };
+    Some(join_relative(dir, &path.path))
+  }
+
+  fn add_fd_input(&mut self, pid: Pid, fd: i32, kind: InputKind) {
+    // unknown file descriptors are pipes, sockets or files opened before tracing.
+    if let Some(file) = self.fd_table(pid).get(&fd).cloned() {
+      self.recorder.add_input(file.path, kind);
+    }
+  }
+
+  fn handle_access(&mut self, pid: Pid, access: FileAccess) {
+    match access {
+      FileAccess::OpenFile { path, fd, close_on_exec } => {
+        let Some(path) = self.resolve(pid, path) else {
+          return;
+        };
+        match fd {
+          Some(fd) => {
+            self.fd_table(pid).insert(fd, OpenFile { path, close_on_exec });
+          }
+          // the evaluation depends on the file not existing.
+          None => self.recorder.add_input(path, InputKind::Metadata)
+        }
+      }
+      FileAccess::ListDir { fd } | FileAccess::FileRead { fd } => {
+        self.add_fd_input(pid, fd, InputKind::Contents);
+      }
+      FileAccess::Stat { path } => {
+        if let Some(path) = self.resolve(pid, path) {
+          self.recorder.add_input(path, InputKind::Metadata);
+        }
+      }
+      FileAccess::StatFd { fd } => {
+        self.add_fd_input(pid, fd, InputKind::Metadata);
+      }
+      FileAccess::ReadLink { path } => {
+        if let Some(path) = self.resolve(pid, path) {
+          self.recorder.add_input(path, InputKind::Link);
+        }
+      }
+      FileAccess::Dup { fd, new_fd, close_on_exec } => {
+        let fd_table = self.fd_table(pid);
+        match fd_table.get(&fd).cloned() {
+          Some(file) => fd_table.insert(new_fd, OpenFile { close_on_exec, ..file }),
+          None => fd_table.remove(&new_fd)
+        };
+      }
+      FileAccess::SetCloseOnExec { fd, close_on_exec } => {
+        if let Some(file) = self.fd_table(pid).get_mut(&fd) {
+          file.close_on_exec = close_on_exec;
+        }
+      }
+      FileAccess::Close { fd } => {
+        self.fd_table(pid).remove(&fd);
+      }
+    }
+  }
+
+  /// Syscall stops alternate between the entry and the exit of a syscall.
+  fn handle_syscall_stop(&mut self, pid: Pid) -> nix::Result<()> {
+    let Some(tracee) = self.tracees.get_mut(&pid) else {
+      return Ok(());
+    };
+    if !tracee.in_syscall {
+      tracee.entry = SyscallEntry::read(pid)?;
+      tracee.in_syscall = true;
+      return Ok(());
+    }
+    tracee.in_syscall = false;
+    if let Some(entry) = tracee.entry.take() {
+      if let Some(access) = entry.finish(&get_registers(pid)?) {
+        self.handle_access(pid, access);
+      }
+    }
+    Ok(())
+  }
+
+  fn handle_event(&mut self, pid: Pid, event: i32) -> nix::Result<()> {
+    let is_event = |e: Event| event == e as i32;
+    if is_event(Event::PTRACE_EVENT_CLONE) || is_event(Event::PTRACE_EVENT_FORK) || is_event(Event::PTRACE_EVENT_VFORK) {
+      let new_pid = Pid::from_raw(ptrace::getevent(pid)? as i32);
+      let parent_table = self.tracees.get(&pid).map(|tracee| tracee.fd_table).unwrap_or(0);
+      // threads share the file descriptors of their process, while new processes get a copy.
+      let fd_table = if is_event(Event::PTRACE_EVENT_CLONE) {
+        parent_table
+      } else {
+        self.fd_tables.push(self.fd_tables[parent_table].clone());
+        self.fd_tables.len() - 1
+      };
+      // its first stop is only waited for once it is known, so it is resumed then.
+      self.add_tracee(new_pid, Tracee::new(fd_table, false));
+    } else if is_event(Event::PTRACE_EVENT_EXEC) {
+      let former_pid = Pid::from_raw(ptrace::getevent(pid)? as i32);
+      // a thread other than the main one called exec, and took over the pid of the process.
+      if former_pid != pid {
+        if let Some(tracee) = self.tracees.remove(&former_pid) {
+          self.tracees.insert(pid, tracee);
+        }
+      }
+      self.close_on_exec(pid);
+    }
+    Ok(())
+  }
+
+  /// Forgets the file descriptors that were closed when `pid` called exec.
+  fn close_on_exec(&mut self, pid: Pid) {
+    self.fd_table(pid).retain(|_, file| !file.close_on_exec);
+  }
+
+  /// Waits for the next stop or exit of a tracee.
+  fn wait(&mut self) -> nix::Result<WaitStatus> {
+    loop {
+      let (pid, status) = self.statuses.recv().map_err(|_| Errno::ECHILD)?;
+      match status {
+        // threads killed by an exec of another thread may not report their exit.
+        Err(Errno::ECHILD) => {
+          self.tracees.remove(&pid);
+          if self.tracees.is_empty() {
+            return Err(Errno::ECHILD);
+          }
+        }
+        status => return status
+      }
+    }
+  }
+}
+
+impl Tracer for PtraceTracer {
+  fn attach(&mut self) -> Result<()> {
+    ptrace::traceme()?;
+    signal::raise(Signal::SIGSTOP)?;
+    Ok(())
+  }
+
+  fn watch(mut self: Box<Self>, child: Pid) -> Result<Dependencies> {
+    waitpid(child, None).context("while attaching to child")?;
+    let options = Options::PTRACE_O_TRACESYSGOOD
+      | Options::PTRACE_O_TRACECLONE
+      | Options::PTRACE_O_TRACEFORK
+      | Options::PTRACE_O_TRACEVFORK
+      | Options::PTRACE_O_TRACEEXEC
+      | Options::PTRACE_O_EXITKILL;
+    ptrace::setoptions(child, options).context("setting ptrace options")?;
+    self.fd_tables.push(HashMap::new());
+    self.add_tracee(child, Tracee::new(0, true));
+    ptrace::syscall(child, None).context("Exception thrown when executing syscall")?;
+    while !self.tracees.is_empty() {
+      let status = match self.wait() {
+        Err(Errno::ECHILD) => break,
+        status => status.context("while waiting for tracees")?
+      };
+      match status {
+        WaitStatus::Exited(pid, _) => {
+          self.tracees.remove(&pid);
+        }
+        WaitStatus::Signaled(pid, signal, _) => {
+          self.tracees.remove(&pid);
+          if pid == child {
+            anyhow::bail!("Evaluation process was killed by {signal}.");
+          }
+        }
+        WaitStatus::PtraceSyscall(pid) => {
+          match self.handle_syscall_stop(pid) {
+            // killed by another thread, its exit is reported next.
+            Err(Errno::ESRCH) => continue,
+            result => result.context("while reading syscall arguments")?
+          }
+          resume(pid, None);
+        }
+        WaitStatus::PtraceEvent(pid, _signal, event) => {
+          match self.handle_event(pid, event) {
+            Err(Errno::ESRCH) => continue,
+            result => result.context("while handling ptrace event")?
+          }
+          resume(pid, None);
+        }
+        WaitStatus::Stopped(pid, Signal::SIGSTOP) if self.tracees.get(&pid).is_some_and(|tracee| !tracee.started) => {
+          if let Some(tracee) = self.tracees.get_mut(&pid) {
+            tracee.started = true;
+          }
+          resume(pid, None);
+        }
+        WaitStatus::Stopped(pid, signal) => {
+          resume(pid, Some(signal));
+        }
+        WaitStatus::Continued(_) | WaitStatus::StillAlive => ()
+      }
+    };
+    Ok(self.recorder.finish())
+  }
+}
+
+/// Resumes `pid` until its next syscall stop. Errors are ignored, since they mean that
+/// the tracee was killed, and its exit is reported by the next wait.
+fn resume(pid: Pid, signal: Option<Signal>) {
+  let _ = ptrace::syscall(pid, signal);
+}
+
+fn read_path_from_register(pid: Pid, address: AddressType) -> PathBuf {
+  let mut bytes = Vec::new();
+  // Move 8 bytes up each time for next read.
+  let mut count = 0;
+  'done: loop {
+    let address = address.wrapping_add(count);
+
+    let res: i64 = match ptrace::read(pid, address) {
+      Ok(c_long) => c_long,
+      Err(_) => break 'done,
+    };
+
+    let bits = res.to_le_bytes();
+
+    if let Some(null_pos) = bits.iter().position(|&c| c == b'\0') {
+      bytes.extend_from_slice(&bits[..null_pos]);
+      break 'done
+    } else {
+      bytes.extend_from_slice(&bits);
+    }
+
+    count += size_of::<i64>();
+  }
+  PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::process::{Command, Stdio};
+  use std::time::Duration;
+  use nix::unistd::{fork, ForkResult};
+  use super::*;
+  use super::super::FileInput;
+
+  /// Files read by threads and subprocesses of the child are seen, while other children of the process
+  /// exit during the trace and must still be reaped by their owner.
+  #[test]
+  fn traces_threads_and_subprocesses() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let files: Vec<PathBuf> = (0..4).map(|i| dir.path().join(format!("file{i}"))).collect();
+    for file in &files {
+      std::fs::write(file, "contents")?;
+    }
+    let mut other = Command::new("true").spawn()?;
+    let mut tracer: Box<dyn Tracer> = Box::new(PtraceTracer::new());
+    match unsafe { fork()? } {
+      ForkResult::Child => {
+        let _ = tracer.attach();
+        // lets `other` exit while being traced.
+        std::thread::sleep(Duration::from_millis(100));
+        let threads: Vec<_> = files[..3]
+          .iter()
+          .cloned()
+          .map(|file| std::thread::spawn(move || std::fs::read(file)))
+          .collect();
+        for thread in threads {
+          let _ = thread.join();
+        }
+        let _ = Command::new("cat").arg(&files[3]).stdout(Stdio::null()).status();
+        std::process::exit(0);
+      }
+      ForkResult::Parent { child } => {
+        let dependencies = tracer.watch(child)?;
+        for file in &files {
+          let input = FileInput { path: file.clone(), kind: InputKind::Contents };
+          assert!(dependencies.inputs.contains(&input), "{file:?} wasn't traced");
+        }
+        assert!(other.wait()?.success());
+      }
+    }
+    Ok(())
+  }
+
+  fn open(path: &str, fd: i32, close_on_exec: bool) -> FileAccess {
+    FileAccess::OpenFile { path: PathArg { dirfd: AT_FDCWD, path: PathBuf::from(path) }, fd: Some(fd), close_on_exec }
+  }
+
+  #[test]
+  fn close_on_exec_file_descriptors_are_forgotten_on_exec() {
+    let pid = Pid::from_raw(1);
+    let mut tracer = PtraceTracer::new();
+    tracer.fd_tables.push(HashMap::new());
+    tracer.tracees.insert(pid, Tracee::new(0, true));
+    tracer.handle_access(pid, open("/kept", 3, false));
+    tracer.handle_access(pid, open("/closed", 4, true));
+    tracer.handle_access(pid, open("/changed", 5, true));
+    tracer.handle_access(pid, FileAccess::SetCloseOnExec { fd: 5, close_on_exec: false });
+    tracer.handle_access(pid, FileAccess::Dup { fd: 3, new_fd: 6, close_on_exec: true });
+    tracer.handle_access(pid, FileAccess::Dup { fd: 4, new_fd: 7, close_on_exec: false });
+    tracer.close_on_exec(pid);
+
+    let mut paths: Vec<(i32, PathBuf)> = tracer.fd_tables[0].iter().map(|(fd, file)| (*fd, file.path.clone())).collect();
+    paths.sort();
+    assert_eq!(paths, [(3, PathBuf::from("/kept")), (5, PathBuf::from("/changed")), (7, PathBuf::from("/closed"))]);
+  }
+
+  /// Reads through a file descriptor duplicated by fcntl are attributed to the original file.
+  #[test]
+  fn traces_reads_of_duplicated_file_descriptors() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("file");
+    std::fs::write(&file, "contents")?;
+    let mut tracer: Box<dyn Tracer> = Box::new(PtraceTracer::new());
+    match unsafe { fork()? } {
+      ForkResult::Child => {
+        let _ = tracer.attach();
+        let opened = std::fs::File::open(&file).and_then(|file| file.try_clone());
+        if let Ok(mut duplicate) = opened {
+          let _ = std::io::Read::read_to_end(&mut duplicate, &mut Vec::new());
+        }
+        unsafe { libc::_exit(0) };
+      }
+      ForkResult::Parent { child } => {
+        let dependencies = tracer.watch(child)?;
+        let input = FileInput { path: file.clone(), kind: InputKind::Contents };
+        assert!(dependencies.inputs.contains(&input), "{file:?} wasn't traced");
+      }
+    }
+    Ok(())
+  }
+}
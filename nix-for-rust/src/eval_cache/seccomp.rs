@@ -0,0 +1,237 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{IoSlice, IoSliceMut};
+use std::mem::offset_of;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::libc::{self, c_long, seccomp_data, seccomp_notif, seccomp_notif_resp, sock_filter, sock_fprog, AT_FDCWD};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::prctl;
+use nix::sys::socket::{recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+use super::arch::{syscall_from_number, Syscall, AUDIT_ARCH, SYSCALLS};
+use super::trace::{join_relative, Dependencies, DependencyRecorder, Tracer};
+use super::InputKind;
+
+nix::ioctl_readwrite!(seccomp_notif_recv, b'!', 0, seccomp_notif);
+nix::ioctl_readwrite!(seccomp_notif_send, b'!', 1, seccomp_notif_resp);
+nix::ioctl_write_ptr!(seccomp_notif_id_valid, b'!', 2, u64);
+
+/// Longest path read from the memory of the tracee.
+const PATH_MAX: usize = 4096;
+
+/// How long to wait for notifications before checking whether the child exited, on kernels
+/// that don't report it on the notification fd.
+const POLL_TIMEOUT_MS: u16 = 100;
+
+/// Traces file accesses with a seccomp filter that notifies the parent of file syscalls, and lets them
+/// continue once their arguments are read. Other syscalls run without stopping.
+pub(super) struct SeccompTracer {
+  recorder: DependencyRecorder,
+  /// Sockets through which the child sends the notification fd of its filter.
+  parent_socket: OwnedFd,
+  child_socket: Option<OwnedFd>
+}
+
+/// File access seen in a notification, with its paths resolved.
+enum Access {
+  Input(PathBuf, InputKind),
+  Stat(PathBuf)
+}
+
+fn bpf_statement(code: u32, k: u32) -> sock_filter {
+  sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+  sock_filter { code: code as u16, jt, jf, k }
+}
+
+/// Filter notifying the listener of the syscalls that read files, and allowing everything else.
+fn file_syscalls_filter() -> Vec<sock_filter> {
+  // the file descriptors are read from /proc when they are used, so dup, fcntl and close don't need to be seen.
+  let traced: Vec<c_long> = SYSCALLS
+    .iter()
+    .filter(|(_, syscall)| !matches!(syscall, Syscall::Dup | Syscall::Dup3 | Syscall::Fcntl | Syscall::Close))
+    .map(|(number, _)| *number)
+    .collect();
+  let mut filter = vec![
+    bpf_statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset_of!(seccomp_data, arch) as u32),
+    bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+    bpf_statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+    bpf_statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset_of!(seccomp_data, nr) as u32),
+  ];
+  for (i, number) in traced.iter().enumerate() {
+    // jump over the remaining comparisons and the allow to the notification.
+    let to_notify = (traced.len() - i) as u8;
+    filter.push(bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, *number as u32, to_notify, 0));
+  }
+  filter.push(bpf_statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+  filter.push(bpf_statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_USER_NOTIF));
+  filter
+}
+
+/// Path of the file descriptor `fd` of `pid`, `None` if it isn't open or isn't a file.
+fn fd_path(pid: Pid, fd: i32) -> Option<PathBuf> {
+  std::fs::read_link(format!("/proc/{pid}/fd/{fd}"))
+    .ok()
+    .filter(|path| path.is_absolute())
+}
+
+/// Reads the path at `address` in the memory of `pid`, making it absolute.
+fn path_arg(pid: Pid, dirfd: i32, address: u64) -> Option<PathBuf> {
+  let memory = File::open(format!("/proc/{pid}/mem")).ok()?;
+  let mut bytes = Vec::new();
+  let mut chunk = [0u8; 256];
+  while bytes.len() < PATH_MAX {
+    let read = memory.read_at(&mut chunk, address + bytes.len() as u64).ok().filter(|read| *read > 0)?;
+    match chunk[..read].iter().position(|&c| c == b'\0') {
+      Some(null_pos) => {
+        bytes.extend_from_slice(&chunk[..null_pos]);
+        break;
+      }
+      None => bytes.extend_from_slice(&chunk[..read])
+    }
+  }
+  let path = PathBuf::from(OsString::from_vec(bytes));
+  if path.is_absolute() {
+    return Some(path);
+  }
+  let dir = if dirfd == AT_FDCWD {
+    std::fs::read_link(format!("/proc/{pid}/cwd")).ok()?
+  } else {
+    fd_path(pid, dirfd)?
+  };
+  Some(join_relative(dir, &path))
+}
+
+/// Reads the access done by a syscall, while its process is waiting for the notification to be answered.
+fn read_access(pid: Pid, syscall: Syscall, args: &[u64; 6]) -> Option<Access> {
+  let fd = args[0] as i32;
+  let access = match syscall {
+    Syscall::Read | Syscall::GetDents => Access::Input(fd_path(pid, fd)?, InputKind::Contents),
+    // anonymous mappings have a fd of -1.
+    Syscall::Mmap => Access::Input(fd_path(pid, args[4] as i32)?, InputKind::Contents),
+    Syscall::FStat => Access::Input(fd_path(pid, fd)?, InputKind::Metadata),
+    // whether the file could be opened isn't known yet, its contents are an input once it is read.
+    Syscall::Open | Syscall::Stat => Access::Stat(path_arg(pid, AT_FDCWD, args[0])?),
+    Syscall::OpenAt | Syscall::OpenAt2 | Syscall::StatAt => Access::Stat(path_arg(pid, fd, args[1])?),
+    Syscall::ReadLink => Access::Input(path_arg(pid, AT_FDCWD, args[0])?, InputKind::Link),
+    Syscall::ReadLinkAt => Access::Input(path_arg(pid, fd, args[1])?, InputKind::Link),
+    Syscall::Dup | Syscall::Dup3 | Syscall::Fcntl | Syscall::Close => return None
+  };
+  Some(access)
+}
+
+impl SeccompTracer {
+  pub fn new() -> Result<Self> {
+    let (parent_socket, child_socket) = socketpair(AddressFamily::Unix, SockType::Stream, None, SockFlag::SOCK_CLOEXEC)
+      .context("while creating socket for the seccomp listener")?;
+    Ok(SeccompTracer {
+      recorder: DependencyRecorder::new(),
+      parent_socket,
+      child_socket: Some(child_socket)
+    })
+  }
+
+  fn receive_listener(&self) -> Result<OwnedFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut byte)];
+    let mut cmsg_buffer = nix::cmsg_space!(RawFd);
+    let message = recvmsg::<()>(self.parent_socket.as_raw_fd(), &mut iov, Some(&mut cmsg_buffer), MsgFlags::empty())
+      .context("while receiving the seccomp listener")?;
+    for cmsg in message.cmsgs()? {
+      if let ControlMessageOwned::ScmRights(fds) = cmsg {
+        if let Some(fd) = fds.first() {
+          return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+        }
+      }
+    }
+    anyhow::bail!("Evaluation process could not install its seccomp filter")
+  }
+
+  /// Reads the access of a notified syscall and lets it continue.
+  fn handle_notification(&mut self, listener: &OwnedFd) -> Result<()> {
+    let mut notification: seccomp_notif = unsafe { std::mem::zeroed() };
+    match unsafe { seccomp_notif_recv(listener.as_raw_fd(), &mut notification) } {
+      // the process was killed before the notification was received.
+      Err(Errno::ENOENT | Errno::EINTR) => return Ok(()),
+      result => result.context("while receiving seccomp notification")?
+    };
+    let pid = Pid::from_raw(notification.pid as i32);
+    let access = syscall_from_number(notification.data.nr as c_long)
+      .and_then(|syscall| read_access(pid, syscall, &notification.data.args));
+    // the pid could have been reused if the process was killed while its memory was read.
+    let valid = unsafe { seccomp_notif_id_valid(listener.as_raw_fd(), &notification.id) }.is_ok();
+    let mut response = seccomp_notif_resp {
+      id: notification.id,
+      val: 0,
+      error: 0,
+      flags: libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32
+    };
+    match unsafe { seccomp_notif_send(listener.as_raw_fd(), &mut response) } {
+      Err(Errno::ENOENT) => return Ok(()),
+      result => result.context("while answering seccomp notification")?
+    };
+    match access.filter(|_| valid) {
+      Some(Access::Input(path, kind)) => self.recorder.add_input(path, kind),
+      Some(Access::Stat(path)) => self.recorder.add_input(path, InputKind::Metadata),
+      None => ()
+    }
+    Ok(())
+  }
+}
+
+impl Tracer for SeccompTracer {
+  fn attach(&mut self) -> Result<()> {
+    let socket = self.child_socket.take().context("seccomp tracer was already attached")?;
+    // allows installing a filter without privileges.
+    prctl::set_no_new_privs()?;
+    let mut filter = file_syscalls_filter();
+    let program = sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+    let listener = unsafe {
+      libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, libc::SECCOMP_FILTER_FLAG_NEW_LISTENER, &program as *const sock_fprog)
+    };
+    let listener = Errno::result(listener).context("while installing seccomp filter")?;
+    let listener = unsafe { OwnedFd::from_raw_fd(listener as RawFd) };
+    sendmsg::<()>(socket.as_raw_fd(), &[IoSlice::new(b"\0")], &[ControlMessage::ScmRights(&[listener.as_raw_fd()])], MsgFlags::empty(), None)
+      .context("while sending the seccomp listener")?;
+    Ok(())
+  }
+
+  fn watch(mut self: Box<Self>, child: Pid) -> Result<Dependencies> {
+    // closed so that receiving fails instead of blocking if the child exits without sending the listener.
+    self.child_socket = None;
+    let listener = self.receive_listener()?;
+    let status = loop {
+      let mut fds = [PollFd::new(listener.as_fd(), PollFlags::POLLIN)];
+      match poll(&mut fds, POLL_TIMEOUT_MS) {
+        Err(Errno::EINTR) => continue,
+        result => result.context("while waiting for seccomp notifications")?
+      };
+      let events = fds[0].revents().unwrap_or(PollFlags::empty());
+      if events.contains(PollFlags::POLLIN) {
+        self.handle_notification(&listener)?;
+      } else if events.contains(PollFlags::POLLHUP) {
+        // every process using the filter exited.
+        break waitpid(child, None)?;
+      } else {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+          WaitStatus::StillAlive => (),
+          status => break status
+        }
+      }
+    };
+    if let WaitStatus::Signaled(_pid, signal, _) = status {
+      anyhow::bail!("Evaluation process was killed by {signal}.");
+    }
+    Ok(self.recorder.finish())
+  }
+}
@@ -1,72 +1,71 @@
-use std::collections::{HashSet, HashMap};
-use std::ffi::{c_void, OsString};
-use std::os::unix::ffi::OsStringExt;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Component, Path, PathBuf};
 
+use anyhow::Result;
 use home::home_dir;
-use anyhow::{Context, Result};
-use nix::sys::signal::Signal;
-use nix::sys::wait::{wait, waitpid, WaitStatus};
-use nix::sys::ptrace::{self, AddressType, Options};
 use nix::unistd::Pid;
 
-enum FileAccess {
-  OpenFile { path: PathBuf, out_fd: u64 },
-  FileRead { fd : u64 },
-  ListDir { fd: u64 }
+use super::impure::{ImpureAccess, ALL_ENV_VARS};
+use super::ptrace::PtraceTracer;
+use super::seccomp::SeccompTracer;
+use super::{FileInput, InputKind};
+
+/// How the file accesses of the evaluation process are traced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TracerBackend {
+  /// Stops the evaluation on every syscall with `ptrace`. Works on any kernel,
+  /// but slows down evaluations and doesn't work where ptrace is forbidden.
+  #[default]
+  Ptrace,
+  /// Only stops the evaluation on file syscalls, with a seccomp filter notifying the parent (Linux 5.8 or later).
+  /// The results of syscalls aren't seen, so files that are opened are considered inputs even if they aren't read.
+  Seccomp
 }
 
-fn wait_till_syscall_exit(pid: Pid) -> Result<()> {
-  ptrace::syscall(pid, None).context("when executing a syscall")?;
-  waitpid(pid, None).context("while waiting for syscall exit")?;
-  Ok(())
+/// Watches the file accesses of the forked evaluation process.
+pub(super) trait Tracer {
+  /// Called in the child before evaluating, to start being traced.
+  fn attach(&mut self) -> Result<()>;
+  /// Called in the parent, traces `child` until it and its subprocesses exit.
+  fn watch(self: Box<Self>, child: Pid) -> Result<Dependencies>;
 }
 
-impl FileAccess {
-  fn from_syscall(pid: Pid) -> Result<Option<Self>> {
-    let regs = ptrace::getregs(pid)?;
-    let syscall = regs.orig_rax;
-    match syscall {
-      0 => {
-        let regs = ptrace::getregs(pid)?;
-        Ok(Some(FileAccess::FileRead { fd: regs.rdi }))
-      }
-      2 => { // open
-        let path = read_path_from_register(pid, regs.rdi as *mut c_void);
-        wait_till_syscall_exit(pid)?;
-        let regs = ptrace::getregs(pid)?;
-        Ok(Some(FileAccess::OpenFile { path, out_fd: regs.rax }))
-      },
-      257 => { // openat
-        let path = read_path_from_register(pid, regs.rsi as *mut c_void);
-        wait_till_syscall_exit(pid)?;
-        let regs = ptrace::getregs(pid)?;
-        Ok(Some(FileAccess::OpenFile { path, out_fd: regs.rax }))
-      }
-      78 | 217 => { // getdents, getdents64
-        let regs = ptrace::getregs(pid)?;
-        wait_till_syscall_exit(pid)?;
-        Ok(Some(FileAccess::ListDir { fd: regs.rdi }))
-      }
-      _ => {
-        wait_till_syscall_exit(pid)?;
-        Ok(None)
-      }
-    }
+impl TracerBackend {
+  /// Creates a tracer, before forking the evaluation process.
+  pub(super) fn tracer(&self) -> Result<Box<dyn Tracer>> {
+    Ok(match self {
+      TracerBackend::Ptrace => Box::new(PtraceTracer::new()),
+      TracerBackend::Seccomp => Box::new(SeccompTracer::new()?)
+    })
   }
 }
 
-pub struct FileTracer {
-  read_files: HashSet<PathBuf>,
-  file_descriptors: HashMap<u64, PathBuf>,
+/// Everything an evaluation was seen depending on.
+pub struct Dependencies {
+  pub inputs: HashSet<FileInput>,
+  /// Environment variables read with `builtins.getEnv`, [`ALL_ENV_VARS`] if some names aren't known.
+  pub env_vars: BTreeSet<String>,
+  /// Whether `builtins.currentTime` might have been read, making the result impossible to cache.
+  pub current_time: bool
+}
+
+/// Collects the [`Dependencies`] out of the file accesses seen by a tracer.
+pub(super) struct DependencyRecorder {
+  dependencies: Dependencies,
+  /// Nix files read, including untracked ones, whose uses of impure builtins are looked for.
+  sources: HashSet<PathBuf>,
   home_cache_dir: Option<PathBuf>
 }
 
-impl FileTracer {
+impl DependencyRecorder {
   pub fn new() -> Self {
-    FileTracer {
-      read_files: HashSet::new(),
-      file_descriptors: HashMap::new(),
+    DependencyRecorder {
+      dependencies: Dependencies {
+        inputs: HashSet::new(),
+        env_vars: BTreeSet::new(),
+        current_time: false
+      },
+      sources: HashSet::new(),
       home_cache_dir: home_dir().map(|mut p| {
         p.push(".cache");
         p
@@ -79,85 +78,76 @@ impl FileTracer {
     // /nix/var -> ephemeral, does not change results of evaluation.
     let is_nix = path.starts_with("/nix");
     let is_git_cache = self.home_cache_dir.as_ref().map(|h| path.starts_with(h)).unwrap_or(true);
-    let is_in_proc = path.starts_with("/proc"); 
-    !is_nix && !is_in_proc && !is_git_cache
+    // devices, terminals and kernel state, like /dev/urandom or /sys/devices, that aren't evaluation inputs.
+    let is_kernel = ["/proc", "/dev", "/sys"].iter().any(|dir| path.starts_with(dir));
+    !is_nix && !is_kernel && !is_git_cache
   }
 
-  fn handle_access(&mut self, access: FileAccess) {
-    match access {
-      FileAccess::OpenFile { path, out_fd: fd } => {
-        self.file_descriptors.insert(fd, path);
-      }
-      FileAccess::ListDir { fd } | FileAccess::FileRead { fd } => {
-        let path = self.file_descriptors.get(&fd).expect(&format!("unknown file descriptor '{fd}'"));
-        if self.should_track_file(path) {
-          self.read_files.insert(path.clone());
-        }
-      }
+  pub fn add_input(&mut self, path: PathBuf, kind: InputKind) {
+    if kind == InputKind::Contents && path.extension().is_some_and(|ext| ext == "nix") {
+      self.sources.insert(path.clone());
+    }
+    let input = FileInput { path, kind };
+    if self.dependencies.inputs.contains(&input) || !self.should_track_file(&input.path) {
+      return;
+    }
+    // the contents of pipes, sockets and devices can't be hashed again, and reading them might never end.
+    let is_special = |m: std::fs::Metadata| !m.is_file() && !m.is_dir();
+    if kind == InputKind::Contents && std::fs::metadata(&input.path).is_ok_and(is_special) {
+      return;
     }
+    self.dependencies.inputs.insert(input);
   }
 
-  pub(crate) fn watch(mut self, child: Pid) -> Result<HashSet<PathBuf>> {
-    wait().context("while attaching to child")?;
-    ptrace::setoptions(child, Options::PTRACE_O_TRACESYSGOOD).context("setting ptrace options")?;
-    ptrace::syscall(child, None).context("Exception thrown when executing syscall")?;
-    loop {
-      let status = wait().context("while waiting for syscall entry")?;
-      match status {
-        WaitStatus::Exited(_pid, _) => {
-          break
-        },
-        WaitStatus::Stopped(pid, sig @ (Signal::SIGCHLD | Signal::SIGWINCH)) => {
-          ptrace::syscall(pid, Some(sig)).context("while resuming from a signal")?;
+  pub fn finish(mut self) -> Dependencies {
+    // unreadable sources couldn't have been evaluated either.
+    let sources = self.sources.iter().filter_map(|path| std::fs::read_to_string(path).ok());
+    for access in sources.flat_map(|source| ImpureAccess::scan(&source)) {
+      match access {
+        ImpureAccess::Env(name) => {
+          self.dependencies.env_vars.insert(name);
         }
-        WaitStatus::Signaled(_pid, _signal, _) => {
-          anyhow::bail!("Evaluation process was killed.");
-        },
-        WaitStatus::PtraceSyscall(pid) => {
-          // pre-syscall execution
-          let access = FileAccess::from_syscall(pid)
-            .context("while parsing syscall entry")?;
-          // post-syscall execution 
-          if let Some(access) = access {
-            self.handle_access(access);
-          }
-          // arrange for next syscall
-          if let Err(_) = ptrace::syscall(pid, None) {
-            break;
-          };
+        ImpureAccess::AnyEnv => {
+          self.dependencies.env_vars.insert(ALL_ENV_VARS.to_string());
         }
-        other => {
-          unreachable!("Wait status '{other:?}' should never happen.")
+        ImpureAccess::CurrentTime => {
+          self.dependencies.current_time = true;
         }
       }
-    };
-    Ok(self.read_files)
+    }
+    self.dependencies
   }
-
 }
 
-fn read_path_from_register(pid: Pid, address: AddressType) -> PathBuf {
-  let mut bytes = Vec::new();
-  // Move 8 bytes up each time for next read.
-  let mut count = 0;
-  'done: loop {
-    let address = address.wrapping_add(count);
-
-    let res: i64 = match ptrace::read(pid, address) {
-      Ok(c_long) => c_long,
-      Err(_) => break 'done,
-    };
+/// Joins a path relative to `dir`, an empty path referring to `dir` itself as with `AT_EMPTY_PATH`.
+pub(super) fn join_relative(dir: PathBuf, path: &Path) -> PathBuf {
+  dir.join(path).components().filter(|c| c != &Component::CurDir).collect()
+}
 
-    let bits = res.to_le_bytes();
+#[cfg(test)]
+mod tests {
+  use super::*;
 
-    if let Some(null_pos) = bits.iter().position(|&c| c == b'\0') {
-      bytes.extend_from_slice(&bits[..null_pos]);
-      break 'done
-    } else {
-      bytes.extend_from_slice(&bits);
+  #[test]
+  fn only_files_and_directories_are_content_inputs() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("file");
+    std::fs::write(&file, "contents")?;
+    let fifo = dir.path().join("fifo");
+    nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU)?;
+    let mut recorder = DependencyRecorder::new();
+    for path in [Path::new("/dev/urandom"), Path::new("/sys/kernel"), &fifo, &file, dir.path()] {
+      recorder.add_input(path.to_path_buf(), InputKind::Contents);
     }
-    
-    count += size_of::<i64>();
+    recorder.add_input(fifo.clone(), InputKind::Metadata);
+
+    let inputs = recorder.finish().inputs;
+    let expected = [
+      FileInput { path: file, kind: InputKind::Contents },
+      FileInput { path: dir.path().to_path_buf(), kind: InputKind::Contents },
+      FileInput { path: fifo, kind: InputKind::Metadata }
+    ];
+    assert_eq!(inputs, HashSet::from(expected));
+    Ok(())
   }
-  PathBuf::from(OsString::from_vec(bytes))
 }
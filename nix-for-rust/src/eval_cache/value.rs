@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use serde_json::{Map, Value};
+use crate::eval::NixEvalState;
+use crate::term::{force, CollectToNix, NixAttrSet, NixList, NixResult, NixTerm, ToNix};
+
+/// Version of the serialization format of [`CachedValue`]s. Entries with another version are ignored.
+pub(super) const CACHED_VALUE_VERSION: u64 = 1;
+
+/// Values nested deeper than this are assumed to be infinitely recursive.
+const MAX_DEPTH: usize = 256;
+
+/// Rebuilds a derivation with the context needed to build it.
+const REBUILD_DERIVATION: &str = r#"
+name: drvPath: outPath: outputName: {
+  type = "derivation";
+  inherit name outputName;
+  drvPath = builtins.appendContext drvPath { ${drvPath} = { allOutputs = true; }; };
+  outPath = builtins.appendContext outPath { ${drvPath} = { outputs = [ outputName ]; }; };
+}
+"#;
+
+/// A fully evaluated value, in the form it is stored in the eval cache.
+///
+/// Derivations are stored by reference, as their attributes are neither needed nor meant to be fully evaluated,
+/// and are restored as an attribute set with `name`, `drvPath`, `outPath` and `outputName`, that can still be built.
+/// The context of other strings is not kept.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CachedValue {
+  Null,
+  Bool(bool),
+  Int(i64),
+  Float(f64),
+  String(String),
+  Path(PathBuf),
+  List(Vec<CachedValue>),
+  AttrSet(BTreeMap<String, CachedValue>),
+  Derivation { name: String, drv_path: String, out_path: String, output_name: String }
+}
+
+fn string_attr(term: &NixTerm, name: &str) -> NixResult<Option<String>> {
+  if term.names()?.any(|n| n == name) {
+    Ok(Some(force(term.get(name)?)?.as_string()?))
+  } else {
+    Ok(None)
+  }
+}
+
+fn expect_str<'v>(value: &'v Value, what: &str) -> Result<&'v str> {
+  value
+    .as_str()
+    .ok_or_else(|| anyhow::format_err!("Expected {what} to be a string in cached value"))
+}
+
+impl CachedValue {
+  /// Evaluates `term` deeply, failing on values that cannot be cached, like functions.
+  pub fn from_term(term: NixTerm) -> Result<Self> {
+    Self::from_term_rec(term, 0)
+  }
+
+  fn from_term_rec(term: NixTerm, depth: usize) -> Result<Self> {
+    if depth > MAX_DEPTH {
+      anyhow::bail!("Value is nested more than {MAX_DEPTH} levels deep, and might be infinitely recursive");
+    }
+    let value = match force(term)? {
+      NixTerm::Null => CachedValue::Null,
+      NixTerm::Bool(b) => CachedValue::Bool(b),
+      NixTerm::Int(i) => CachedValue::Int(i),
+      // JSON has no representation for them.
+      NixTerm::Float(f) if !f.is_finite() => anyhow::bail!("Float {f} cannot be cached"),
+      NixTerm::Float(f) => CachedValue::Float(f),
+      NixTerm::String(s) => CachedValue::String(s),
+      NixTerm::Path(p) => CachedValue::Path(p),
+      list @ NixTerm::List(_) => CachedValue::List(
+        list
+          .iter()?
+          .map(|item| Self::from_term_rec(item?, depth + 1))
+          .collect::<Result<_>>()?
+      ),
+      attrs @ NixTerm::AttrSet(_) if string_attr(&attrs, "type")?.as_deref() == Some("derivation") => {
+        let required = |name: &str| string_attr(&attrs, name)?
+          .ok_or_else(|| anyhow::format_err!("Derivation has no '{name}' attribute"));
+        CachedValue::Derivation {
+          name: required("name")?,
+          drv_path: required("drvPath")?,
+          out_path: required("outPath")?,
+          output_name: string_attr(&attrs, "outputName")?.unwrap_or_else(|| "out".to_string())
+        }
+      }
+      attrs @ NixTerm::AttrSet(_) => CachedValue::AttrSet(
+        attrs
+          .items()?
+          .map(|(name, item)| Ok((name, Self::from_term_rec(item?, depth + 1)?)))
+          .collect::<Result<_>>()?
+      ),
+      other => anyhow::bail!("Values of type '{}' cannot be cached", other.get_typename())
+    };
+    Ok(value)
+  }
+
+  /// Objects are tagged by a single key, so that attribute sets can be told apart from paths and derivations.
+  fn to_json(&self) -> Value {
+    match self {
+      CachedValue::Null => Value::Null,
+      CachedValue::Bool(b) => Value::Bool(*b),
+      CachedValue::Int(i) => Value::from(*i),
+      CachedValue::Float(f) => Value::from(*f),
+      CachedValue::String(s) => Value::String(s.clone()),
+      CachedValue::Path(p) => serde_json::json!({ "path": p.to_string_lossy() }),
+      CachedValue::List(items) => Value::Array(items.iter().map(Self::to_json).collect()),
+      CachedValue::AttrSet(attrs) => {
+        let attrs: Map<String, Value> = attrs.iter().map(|(k, v)| (k.clone(), v.to_json())).collect();
+        serde_json::json!({ "attrs": attrs })
+      }
+      CachedValue::Derivation { name, drv_path, out_path, output_name } => serde_json::json!({
+        "derivation": { "name": name, "drvPath": drv_path, "outPath": out_path, "outputName": output_name }
+      })
+    }
+  }
+
+  fn from_json(value: &Value) -> Result<Self> {
+    let cached = match value {
+      Value::Null => CachedValue::Null,
+      Value::Bool(b) => CachedValue::Bool(*b),
+      Value::Number(n) => match n.as_i64() {
+        Some(i) => CachedValue::Int(i),
+        None => CachedValue::Float(n.as_f64().unwrap_or(f64::NAN))
+      },
+      Value::String(s) => CachedValue::String(s.clone()),
+      Value::Array(items) => CachedValue::List(items.iter().map(Self::from_json).collect::<Result<_>>()?),
+      Value::Object(tagged) if tagged.len() == 1 => match tagged.iter().next() {
+        Some((tag, Value::String(path))) if tag == "path" => CachedValue::Path(PathBuf::from(path)),
+        Some((tag, Value::Object(attrs))) if tag == "attrs" => CachedValue::AttrSet(
+          attrs.iter().map(|(k, v)| Ok((k.clone(), Self::from_json(v)?))).collect::<Result<_>>()?
+        ),
+        Some((tag, drv)) if tag == "derivation" => CachedValue::Derivation {
+          name: expect_str(&drv["name"], "name")?.to_string(),
+          drv_path: expect_str(&drv["drvPath"], "drvPath")?.to_string(),
+          out_path: expect_str(&drv["outPath"], "outPath")?.to_string(),
+          output_name: expect_str(&drv["outputName"], "outputName")?.to_string()
+        },
+        _ => anyhow::bail!("Unknown object in cached value")
+      }
+      Value::Object(_) => anyhow::bail!("Expected objects in cached value to have a single tag")
+    };
+    Ok(cached)
+  }
+
+  /// Serializes the value to a single line of versioned JSON.
+  pub fn serialize(&self) -> Result<String> {
+    Ok(serde_json::to_string(&serde_json::json!({ "version": CACHED_VALUE_VERSION, "value": self.to_json() }))?)
+  }
+
+  /// Parses a serialized value, returning `None` if it was written in another version of the format.
+  pub fn deserialize(serialized: &str) -> Result<Option<Self>> {
+    let json: Value = serde_json::from_str(serialized)?;
+    if json.get("version").and_then(Value::as_u64) != Some(CACHED_VALUE_VERSION) {
+      return Ok(None);
+    }
+    Ok(Some(Self::from_json(&json["value"])?))
+  }
+}
+
+impl<'state> ToNix<'state> for CachedValue {
+  fn to_nix(self, eval_state: &'state NixEvalState) -> NixResult<NixTerm<'state>> {
+    let term = match self {
+      CachedValue::Null => NixTerm::Null,
+      CachedValue::Bool(b) => NixTerm::Bool(b),
+      CachedValue::Int(i) => NixTerm::Int(i),
+      CachedValue::Float(f) => NixTerm::Float(f),
+      CachedValue::String(s) => NixTerm::String(s),
+      CachedValue::Path(p) => NixTerm::Path(p),
+      CachedValue::List(items) => {
+        let list: NixList = items.into_iter().collect_to_nix(eval_state)?;
+        NixTerm::List(list)
+      }
+      CachedValue::AttrSet(attrs) => {
+        let attrs: NixAttrSet = attrs.into_iter().collect_to_nix(eval_state)?;
+        NixTerm::AttrSet(attrs)
+      }
+      CachedValue::Derivation { name, drv_path, out_path, output_name } => eval_state
+        .eval_expr(REBUILD_DERIVATION, Path::new("/"))?
+        .call_with(name)?
+        .call_with(drv_path)?
+        .call_with(out_path)?
+        .call_with(output_name)?
+    };
+    Ok(term)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hello() -> CachedValue {
+    CachedValue::Derivation {
+      name: "hello".to_string(),
+      drv_path: "/nix/store/0c9qhz9dhl6ldr2k1i8m0rv5s2a9b6w2-hello.drv".to_string(),
+      out_path: "/nix/store/a9b6w20c9qhz9dhl6ldr2k1i8m0rv5s2-hello".to_string(),
+      output_name: "out".to_string()
+    }
+  }
+
+  #[test]
+  fn values_round_trip() -> Result<()> {
+    let attrs = [
+      ("null", CachedValue::Null),
+      ("bool", CachedValue::Bool(true)),
+      ("int", CachedValue::Int(-3)),
+      ("float", CachedValue::Float(1.0)),
+      ("string", CachedValue::String("hello".to_string())),
+      ("path", CachedValue::Path(PathBuf::from("/etc/hosts"))),
+      ("drv", hello()),
+      ("attrs", CachedValue::AttrSet(BTreeMap::new()))
+    ];
+    let value = CachedValue::List(vec![
+      CachedValue::AttrSet(attrs.into_iter().map(|(name, value)| (name.to_string(), value)).collect()),
+      CachedValue::List(vec![CachedValue::Float(0.5)])
+    ]);
+    assert_eq!(CachedValue::deserialize(&value.serialize()?)?, Some(value));
+    Ok(())
+  }
+
+  #[test]
+  fn objects_are_tagged() -> Result<()> {
+    let value = CachedValue::AttrSet(BTreeMap::from([
+      ("path".to_string(), CachedValue::String("/etc/hosts".to_string())),
+      ("file".to_string(), CachedValue::Path(PathBuf::from("/etc/hosts"))),
+      ("drv".to_string(), hello())
+    ]));
+    let expected = concat!(
+      r#"{"value":{"attrs":{"#,
+      r#""drv":{"derivation":{"drvPath":"/nix/store/0c9qhz9dhl6ldr2k1i8m0rv5s2a9b6w2-hello.drv","name":"hello","#,
+      r#""outPath":"/nix/store/a9b6w20c9qhz9dhl6ldr2k1i8m0rv5s2-hello","outputName":"out"}},"#,
+      r#""file":{"path":"/etc/hosts"},"path":"/etc/hosts"}},"version":1}"#
+    );
+    assert_eq!(value.serialize()?, expected);
+    for untagged in [r#"{}"#, r#"{"name":"hello"}"#, r#"{"path":"/etc/hosts","attrs":{}}"#] {
+      let serialized = format!(r#"{{"version":1,"value":{untagged}}}"#);
+      assert!(CachedValue::deserialize(&serialized).is_err(), "{untagged}");
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn other_versions_are_ignored() -> Result<()> {
+    assert_eq!(CachedValue::deserialize(r#"{"version":1,"value":null}"#)?, Some(CachedValue::Null));
+    assert_eq!(CachedValue::deserialize(r#"{"version":0,"value":null}"#)?, None);
+    assert_eq!(CachedValue::deserialize(r#"{"value":null}"#)?, None);
+    Ok(())
+  }
+
+  #[test]
+  fn non_finite_floats_are_rejected() {
+    for float in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+      assert!(CachedValue::from_term(NixTerm::Float(float)).is_err(), "{float}");
+    }
+    assert_eq!(CachedValue::from_term(NixTerm::Float(0.5)).ok(), Some(CachedValue::Float(0.5)));
+  }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::Result;
+use serde_json::Value;
+use crate::eval::NixEvalState;
+use crate::term::NixTerm;
+
+/// Attributes of a flake reference in URL form, as a JSON string.
+const PARSE_FLAKE_REF: &str = "uri: builtins.toJSON (builtins.parseFlakeRef uri)";
+
+/// URL form of the flake reference attributes given as a JSON string.
+const FLAKE_REF_TO_STRING: &str = "attrs: builtins.flakeRefToString (builtins.fromJSON attrs)";
+
+/// Attributes of a flake reference, as found in the `locked` and `original` fields of a lock file,
+/// or returned by `builtins.parseFlakeRef`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FlakeRefAttrs {
+  pub attrs: BTreeMap<String, Value>
+}
+
+impl FlakeRefAttrs {
+  /// Reference of type `ref_type` with no other attributes, to be filled with [`FlakeRefAttrs::with`].
+  pub fn new(ref_type: &str) -> Self {
+    FlakeRefAttrs::default().with("type", ref_type)
+  }
+
+  pub fn github(owner: &str, repo: &str) -> Self {
+    FlakeRefAttrs::new("github").with("owner", owner).with("repo", repo)
+  }
+
+  pub fn gitlab(owner: &str, repo: &str) -> Self {
+    FlakeRefAttrs::new("gitlab").with("owner", owner).with("repo", repo)
+  }
+
+  /// Reference to be looked up in the flake registry, like `nixpkgs`.
+  pub fn indirect(id: &str) -> Self {
+    FlakeRefAttrs::new("indirect").with("id", id)
+  }
+
+  pub fn git(url: &str) -> Self {
+    FlakeRefAttrs::new("git").with("url", url)
+  }
+
+  pub fn tarball(url: &str) -> Self {
+    FlakeRefAttrs::new("tarball").with("url", url)
+  }
+
+  pub fn local(path: &Path) -> Self {
+    FlakeRefAttrs::new("path").with("path", path.to_string_lossy().as_ref())
+  }
+
+  pub fn with<V: Into<Value>>(mut self, attr: &str, value: V) -> Self {
+    self.attrs.insert(attr.to_string(), value.into());
+    self
+  }
+
+  pub fn with_ref(self, git_ref: &str) -> Self {
+    self.with("ref", git_ref)
+  }
+
+  pub fn with_rev(self, rev: &str) -> Self {
+    self.with("rev", rev)
+  }
+
+  pub fn with_dir(self, dir: &str) -> Self {
+    self.with("dir", dir)
+  }
+
+  pub fn with_nar_hash(self, nar_hash: &str) -> Self {
+    self.with("narHash", nar_hash)
+  }
+
+  pub fn get(&self, attr: &str) -> Option<&Value> {
+    self.attrs.get(attr)
+  }
+
+  pub fn get_str(&self, attr: &str) -> Option<&str> {
+    self.get(attr).and_then(Value::as_str)
+  }
+
+  /// Type of the reference, like `github`, `git`, `path`, `tarball` or `indirect`.
+  pub fn ref_type(&self) -> Option<&str> {
+    self.get_str("type")
+  }
+
+  pub fn owner(&self) -> Option<&str> {
+    self.get_str("owner")
+  }
+
+  pub fn repo(&self) -> Option<&str> {
+    self.get_str("repo")
+  }
+
+  pub fn id(&self) -> Option<&str> {
+    self.get_str("id")
+  }
+
+  pub fn git_ref(&self) -> Option<&str> {
+    self.get_str("ref")
+  }
+
+  pub fn rev(&self) -> Option<&str> {
+    self.get_str("rev")
+  }
+
+  pub fn dir(&self) -> Option<&str> {
+    self.get_str("dir")
+  }
+
+  pub fn url(&self) -> Option<&str> {
+    self.get_str("url")
+  }
+
+  pub fn path(&self) -> Option<&str> {
+    self.get_str("path")
+  }
+
+  pub fn nar_hash(&self) -> Option<&str> {
+    self.get_str("narHash")
+  }
+
+  /// Unix timestamp of the last modification of the source.
+  pub fn last_modified(&self) -> Option<i64> {
+    self.get("lastModified").and_then(Value::as_i64)
+  }
+}
+
+impl From<serde_json::Map<String, Value>> for FlakeRefAttrs {
+  fn from(attrs: serde_json::Map<String, Value>) -> Self {
+    FlakeRefAttrs { attrs: attrs.into_iter().collect() }
+  }
+}
+
+impl From<&FlakeRefAttrs> for Value {
+  fn from(attrs: &FlakeRefAttrs) -> Self {
+    Value::Object(attrs.attrs.clone().into_iter().collect())
+  }
+}
+
+impl NixEvalState {
+  /// Parses a flake reference in URL form (without its fragment) with `builtins.parseFlakeRef`.
+  /// Paths must be absolute.
+  pub fn parse_flake_ref(&self, uri: &str) -> Result<FlakeRefAttrs> {
+    let json = self.eval_expr(PARSE_FLAKE_REF, Path::new("/"))?.call_with(uri)?;
+    match json {
+      NixTerm::String(json) => match serde_json::from_str(&json)? {
+        Value::Object(attrs) => Ok(FlakeRefAttrs::from(attrs)),
+        _ => anyhow::bail!("builtins.parseFlakeRef didn't return an attribute set")
+      },
+      _ => anyhow::bail!("builtins.toJSON didn't return a string")
+    }
+  }
+
+  /// Renders flake reference attributes in URL form with `builtins.flakeRefToString`.
+  pub fn flake_ref_to_string(&self, attrs: &FlakeRefAttrs) -> Result<String> {
+    let json = serde_json::to_string(&Value::from(attrs))?;
+    Ok(self.eval_expr(FLAKE_REF_TO_STRING, Path::new("/"))?.call_with(json.as_str())?.as_string()?)
+  }
+}
@@ -0,0 +1,152 @@
+use std::fmt::Display;
+use anyhow::Result;
+use crate::term::{force, NixResult, NixTerm};
+use super::inventory::{is_derivation, optional_string};
+use super::{InventoryEntry, LockedFlake, OutputKind};
+
+/// Outcome of checking a single flake output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckStatus {
+  /// The output evaluated and has the expected shape.
+  Valid,
+  /// The output is a check that was also built successfully.
+  Built,
+  Failed(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+  pub kind: OutputKind,
+  pub attr_path: Vec<String>,
+  pub status: CheckStatus
+}
+
+/// Report of [`LockedFlake::check`], with a result for every output that follows the standard schema.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheckReport {
+  pub results: Vec<CheckResult>
+}
+
+impl CheckResult {
+  pub fn passed(&self) -> bool {
+    !matches!(self.status, CheckStatus::Failed(_))
+  }
+}
+
+impl CheckReport {
+  /// Whether every output passed.
+  pub fn success(&self) -> bool {
+    self.results.iter().all(CheckResult::passed)
+  }
+
+  pub fn failures(&self) -> impl Iterator<Item=&CheckResult> {
+    self.results.iter().filter(|r| !r.passed())
+  }
+}
+
+impl Display for CheckReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for result in self.results.iter() {
+      let attr_path = result.attr_path.join(".");
+      match &result.status {
+        CheckStatus::Valid => writeln!(f, "ok      {attr_path}")?,
+        CheckStatus::Built => writeln!(f, "built   {attr_path}")?,
+        CheckStatus::Failed(error) => writeln!(f, "FAILED  {attr_path}: {error}")?
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Checks that `value` has the shape expected for outputs of `kind`, returning the reason when it doesn't.
+fn validate(kind: OutputKind, value: &NixTerm) -> NixResult<Option<String>> {
+  let error = match kind {
+    OutputKind::Package | OutputKind::DevShell | OutputKind::Check | OutputKind::Formatter => {
+      if is_derivation(value)? {
+        // instantiates the derivation, which fails if any of its attributes does.
+        ["drvPath", "outPath"]
+          .into_iter()
+          .map(|attr| Ok((attr, force(value.get(attr)?)?)))
+          .collect::<NixResult<Vec<_>>>()?
+          .into_iter()
+          .find(|(_, path)| !matches!(path, NixTerm::String(_)))
+          .map(|(attr, path)| format!("'{attr}' should be a string, got {}", path.get_typename()))
+      } else {
+        Some(format!("expected a derivation, got {}", value.get_typename()))
+      }
+    }
+    OutputKind::App => {
+      if optional_string(value, &["type"])?.as_deref() != Some("app") {
+        Some("expected an attribute set with 'type = \"app\"'".to_string())
+      } else if optional_string(value, &["program"])?.is_none() {
+        Some("app has no 'program' string".to_string())
+      } else {
+        None
+      }
+    }
+    OutputKind::Overlay => {
+      (!matches!(value, NixTerm::Function(_))).then(|| format!("expected an overlay function, got {}", value.get_typename()))
+    }
+    OutputKind::NixosModule => {
+      (!matches!(value, NixTerm::Function(_) | NixTerm::AttrSet(_)))
+        .then(|| format!("expected a module (function or attribute set), got {}", value.get_typename()))
+    }
+    OutputKind::NixosConfiguration => {
+      let toplevel = force(value.get_attrs(["config", "system", "build", "toplevel"])?)?;
+      (!is_derivation(&toplevel)?).then(|| "'config.system.build.toplevel' is not a derivation".to_string())
+    }
+    OutputKind::Template => {
+      if !value.names()?.any(|name| name == "path") {
+        Some("template has no 'path'".to_string())
+      } else if optional_string(value, &["description"])?.is_none() {
+        Some("template has no 'description' string".to_string())
+      } else {
+        None
+      }
+    }
+  };
+  Ok(error)
+}
+
+impl<'state> LockedFlake<'state> {
+  /// Evaluates and validates the outputs of the flake, like `nix flake check`.
+  /// Derivations are instantiated, so that failures in any of their attributes are reported.
+  ///
+  /// When `build_system` is set, the checks of that system (`checks.<build_system>.*`) are also built in the evaluator's store.
+  pub fn check(&self, build_system: Option<&str>) -> Result<CheckReport> {
+    let outputs = force(self.outputs()?)?;
+    let store = &self.state.store;
+    let mut report = CheckReport::default();
+    for (InventoryEntry { kind, attr_path, system, error, .. }, value) in self.inspect_outputs()? {
+      let status = match error {
+        Some(error) => CheckStatus::Failed(error),
+        None => {
+          // outputs that the inventory doesn't evaluate are only evaluated here.
+          let checked = value
+            .map(Ok)
+            .unwrap_or_else(|| outputs.get_attrs(&attr_path).and_then(force))
+            .and_then(|value| Ok((validate(kind, &value)?, value)));
+          match checked {
+            Err(e) => CheckStatus::Failed(e.to_string()),
+            Ok((Some(error), _)) => CheckStatus::Failed(error),
+            Ok((None, value)) if kind == OutputKind::Check && build_system.is_some() && system.as_deref() == build_system => {
+              let built = optional_string(&value, &["drvPath"])
+                .map_err(anyhow::Error::from)
+                .and_then(|drv_path| {
+                  let drv_path = drv_path.ok_or_else(|| anyhow::format_err!("check has no 'drvPath'"))?;
+                  Ok(store.build(&store.parse_path(&drv_path)?)?)
+                });
+              match built {
+                Ok(_) => CheckStatus::Built,
+                Err(e) => CheckStatus::Failed(e.to_string())
+              }
+            }
+            Ok((None, _)) => CheckStatus::Valid
+          }
+        }
+      };
+      report.results.push(CheckResult { kind, attr_path, status });
+    }
+    Ok(report)
+  }
+}
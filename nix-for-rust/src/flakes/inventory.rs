@@ -0,0 +1,171 @@
+use anyhow::{Context, Result};
+use crate::term::{force, NixResult, NixTerm};
+use super::LockedFlake;
+
+/// Kind of a flake output, following the standard flake output schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+  /// `packages.<system>.<name>`
+  Package,
+  /// `devShells.<system>.<name>`
+  DevShell,
+  /// `checks.<system>.<name>`
+  Check,
+  /// `apps.<system>.<name>`
+  App,
+  /// `formatter.<system>`
+  Formatter,
+  /// `nixosConfigurations.<name>`
+  NixosConfiguration,
+  /// `nixosModules.<name>`
+  NixosModule,
+  /// `overlays.<name>`
+  Overlay,
+  /// `templates.<name>`
+  Template
+}
+
+/// A single output found by [`LockedFlake::inventory`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct InventoryEntry {
+  pub kind: OutputKind,
+  /// Attribute path of the output, like `["packages", "x86_64-linux", "hello"]`.
+  pub attr_path: Vec<String>,
+  pub name: String,
+  pub system: Option<String>,
+  /// `meta.description` of packages, apps and shells, or `description` of templates.
+  pub description: Option<String>,
+  /// `name` attribute of outputs that are derivations.
+  pub derivation_name: Option<String>,
+  /// Error raised while evaluating the output, in which case the other attributes might be missing.
+  pub error: Option<String>
+}
+
+impl OutputKind {
+  fn from_output(output: &str) -> Option<Self> {
+    let kind = match output {
+      "packages" => OutputKind::Package,
+      "devShells" => OutputKind::DevShell,
+      "checks" => OutputKind::Check,
+      "apps" => OutputKind::App,
+      "formatter" => OutputKind::Formatter,
+      "nixosConfigurations" => OutputKind::NixosConfiguration,
+      "nixosModules" => OutputKind::NixosModule,
+      "overlays" => OutputKind::Overlay,
+      "templates" => OutputKind::Template,
+      _ => return None
+    };
+    Some(kind)
+  }
+
+  fn is_per_system(&self) -> bool {
+    matches!(self, OutputKind::Package | OutputKind::DevShell | OutputKind::Check | OutputKind::App | OutputKind::Formatter)
+  }
+
+  /// Whether the values of these outputs are evaluated to read their attributes.
+  /// NixOS configurations, modules and overlays are only listed, since evaluating them is expensive or meaningless.
+  fn is_inspected(&self) -> bool {
+    !matches!(self, OutputKind::NixosConfiguration | OutputKind::NixosModule | OutputKind::Overlay)
+  }
+}
+
+fn attr_names(term: &NixTerm) -> NixResult<Vec<String>> {
+  Ok(term.names()?.collect())
+}
+
+/// Gets the string at `path`, or `None` if some attribute along the path doesn't exist.
+pub(super) fn optional_string(term: &NixTerm, path: &[&str]) -> NixResult<Option<String>> {
+  let Some((attr, rest)) = path.split_first() else {
+    return match term {
+      NixTerm::String(s) => Ok(Some(s.clone())),
+      _ => Ok(None)
+    };
+  };
+  if !matches!(term, NixTerm::AttrSet(_)) || !term.names()?.any(|name| name == *attr) {
+    return Ok(None);
+  }
+  optional_string(&force(term.get(attr)?)?, rest)
+}
+
+pub(super) fn is_derivation(term: &NixTerm) -> NixResult<bool> {
+  Ok(optional_string(term, &["type"])?.as_deref() == Some("derivation"))
+}
+
+impl InventoryEntry {
+  fn new(kind: OutputKind, attr_path: Vec<String>, system: Option<&str>) -> Self {
+    InventoryEntry {
+      kind,
+      name: attr_path.last().cloned().unwrap_or_default(),
+      attr_path,
+      system: system.map(String::from),
+      description: None,
+      derivation_name: None,
+      error: None
+    }
+  }
+
+  /// Evaluates the attributes of the output named `self.name` inside `parent`, returning its value.
+  fn inspect<'state>(&mut self, parent: &NixTerm<'state>) -> NixResult<NixTerm<'state>> {
+    let value = force(parent.get(&self.name)?)?;
+    if is_derivation(&value)? {
+      self.derivation_name = optional_string(&value, &["name"])?;
+    }
+    self.description = match self.kind {
+      OutputKind::Template => optional_string(&value, &["description"])?,
+      _ => optional_string(&value, &["meta", "description"])?
+    };
+    Ok(value)
+  }
+}
+
+impl<'state> LockedFlake<'state> {
+  /// Lists the outputs of the flake that follow the standard output schema, across all systems.
+  ///
+  /// Only the attributes needed to fill the entries are evaluated, and outputs that fail
+  /// to evaluate are still listed, with their [`error`][InventoryEntry::error] set.
+  pub fn inventory(&self) -> Result<Vec<InventoryEntry>> {
+    Ok(self.inspect_outputs()?.into_iter().map(|(entry, _)| entry).collect())
+  }
+
+  /// Entries of the [inventory][LockedFlake::inventory], along with the values of the outputs that were evaluated.
+  pub(super) fn inspect_outputs(&self) -> Result<Vec<(InventoryEntry, Option<NixTerm<'state>>)>> {
+    let outputs = force(self.outputs()?)?;
+    let mut entries = Vec::new();
+    for output in attr_names(&outputs)? {
+      let Some(kind) = OutputKind::from_output(&output) else {
+        continue;
+      };
+      let value = force(outputs.get(&output)?).with_context(|| format!("while evaluating output '{output}'"))?;
+      let mut add_entry = |mut entry: InventoryEntry, parent: &NixTerm<'state>| {
+        let value = match kind.is_inspected().then(|| entry.inspect(parent)) {
+          Some(Ok(value)) => Some(value),
+          Some(Err(e)) => {
+            entry.error = Some(e.to_string());
+            None
+          }
+          None => None
+        };
+        entries.push((entry, value));
+      };
+      if kind == OutputKind::Formatter {
+        for system in attr_names(&value)? {
+          add_entry(InventoryEntry::new(kind, vec![output.clone(), system.clone()], Some(&system)), &value);
+        }
+      } else if kind.is_per_system() {
+        for system in attr_names(&value)? {
+          let per_system = force(value.get(&system)?)
+            .with_context(|| format!("while evaluating output '{output}.{system}'"))?;
+          for name in attr_names(&per_system)? {
+            let attr_path = vec![output.clone(), system.clone(), name];
+            add_entry(InventoryEntry::new(kind, attr_path, Some(&system)), &per_system);
+          }
+        }
+      } else {
+        for name in attr_names(&value)? {
+          add_entry(InventoryEntry::new(kind, vec![output.clone(), name], None), &value);
+        }
+      }
+    }
+    Ok(entries)
+  }
+}
@@ -0,0 +1,619 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use anyhow::Result;
+use serde_json::{Map, Value};
+use crate::eval::NixEvalState;
+use super::FlakeRefAttrs;
+
+/// Lock file versions that can be read. Nix always writes the latest one.
+const SUPPORTED_VERSIONS: std::ops::RangeInclusive<u64> = 5..=7;
+
+/// Where an input of a node points to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+  /// Key of the node in [`LockFile::nodes`].
+  Node(String),
+  /// Input path, starting at the root node, that this input follows.
+  Follows(Vec<String>)
+}
+
+/// A node of the lock file graph, that is, a locked flake (or non-flake) input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Node {
+  pub inputs: BTreeMap<String, Input>,
+  pub locked: Option<FlakeRefAttrs>,
+  pub original: Option<FlakeRefAttrs>,
+  /// `false` for inputs declared with `flake = false`.
+  pub flake: bool,
+  /// Attributes that are not modeled, kept so that the lock file is written back unchanged.
+  pub extra: BTreeMap<String, Value>
+}
+
+/// Typed representation of a `flake.lock` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockFile {
+  pub version: u64,
+  pub root: String,
+  pub nodes: BTreeMap<String, Node>,
+  pub extra: BTreeMap<String, Value>
+}
+
+/// What an input resolved to, as shown in a [`LockFileDiff`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockedInput {
+  Locked(FlakeRefAttrs),
+  Follows(Vec<String>)
+}
+
+/// Change of a single input between two lock files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputChange {
+  Added(LockedInput),
+  Removed(LockedInput),
+  Updated { old: LockedInput, new: LockedInput }
+}
+
+/// Inputs that changed between two lock files, keyed by input path (like `foo/nixpkgs`).
+/// [`LockFileDiff::report`] renders the same report as `nix flake update`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LockFileDiff {
+  pub changes: BTreeMap<String, InputChange>
+}
+
+/// Splits an input path like `foo/nixpkgs` into its components.
+pub fn parse_input_path(path: &str) -> Vec<String> {
+  path.split('/').filter(|p| !p.is_empty()).map(String::from).collect()
+}
+
+fn expect_object(value: Value, what: &str) -> Result<Map<String, Value>> {
+  match value {
+    Value::Object(map) => Ok(map),
+    _ => anyhow::bail!("Expected {what} to be an object")
+  }
+}
+
+impl Input {
+  fn parse(value: Value) -> Result<Self> {
+    match value {
+      Value::String(node) => Ok(Input::Node(node)),
+      Value::Array(path) => path
+        .into_iter()
+        .map(|p| match p {
+          Value::String(p) => Ok(p),
+          _ => anyhow::bail!("Expected follows path to be a list of strings")
+        })
+        .collect::<Result<_>>()
+        .map(Input::Follows),
+      _ => anyhow::bail!("Expected input to be a string or a list of strings")
+    }
+  }
+
+  fn to_json(&self) -> Value {
+    match self {
+      Input::Node(node) => Value::String(node.clone()),
+      Input::Follows(path) => Value::Array(path.iter().cloned().map(Value::String).collect())
+    }
+  }
+}
+
+/// Formats a unix timestamp as a `YYYY-MM-DD` date in UTC.
+fn format_date(timestamp: i64) -> String {
+  // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let days = timestamp.div_euclid(86400) + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days.rem_euclid(146097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let shifted_month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+  let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+  let year = year_of_era + era * 400 + i64::from(month <= 2);
+  format!("{year:04}-{month:02}-{day:02}")
+}
+
+impl LockedInput {
+  pub fn rev(&self) -> Option<&str> {
+    match self {
+      LockedInput::Locked(attrs) => attrs.rev(),
+      LockedInput::Follows(_) => None
+    }
+  }
+
+  pub fn last_modified(&self) -> Option<i64> {
+    match self {
+      LockedInput::Locked(attrs) => attrs.last_modified(),
+      LockedInput::Follows(_) => None
+    }
+  }
+
+  /// Last modification of the input, formatted as `YYYY-MM-DD`.
+  pub fn date(&self) -> Option<String> {
+    self.last_modified().map(format_date)
+  }
+
+  fn to_json(&self, state: &NixEvalState) -> Result<Value> {
+    Ok(match self {
+      LockedInput::Locked(attrs) => serde_json::json!({
+        "url": state.flake_ref_to_string(attrs)?,
+        "rev": attrs.rev(),
+        "lastModified": attrs.last_modified(),
+        "locked": Value::from(attrs)
+      }),
+      LockedInput::Follows(path) => serde_json::json!({ "follows": path })
+    })
+  }
+
+  /// The input as shown by `nix flake update`, like `'github:NixOS/nixpkgs/<rev>?narHash=...' (2024-12-05)`.
+  pub fn describe(&self, state: &NixEvalState) -> Result<String> {
+    match self {
+      LockedInput::Locked(attrs) => {
+        let url = state.flake_ref_to_string(attrs)?;
+        Ok(match self.date() {
+          Some(date) => format!("'{url}' ({date})"),
+          None => format!("'{url}'")
+        })
+      }
+      LockedInput::Follows(path) => Ok(format!("follows '{}'", path.join("/")))
+    }
+  }
+}
+
+impl LockFileDiff {
+  /// Compares two lock files, where `None` stands for a missing lock file.
+  pub fn new(old: Option<&LockFile>, new: Option<&LockFile>) -> Result<Self> {
+    let old_inputs = old.map(LockFile::locked_inputs).transpose()?.unwrap_or_default();
+    let mut new_inputs = new.map(LockFile::locked_inputs).transpose()?.unwrap_or_default();
+    let mut changes = BTreeMap::new();
+    for (path, old_input) in old_inputs {
+      match new_inputs.remove(&path) {
+        None => { changes.insert(path, InputChange::Removed(old_input)); },
+        Some(new_input) => if old_input != new_input {
+          changes.insert(path, InputChange::Updated { old: old_input, new: new_input });
+        }
+      }
+    }
+    changes.extend(new_inputs.into_iter().map(|(path, input)| (path, InputChange::Added(input))));
+    Ok(LockFileDiff { changes })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.changes.is_empty()
+  }
+
+  pub fn to_json(&self, state: &NixEvalState) -> Result<Value> {
+    let changes = self.changes
+      .iter()
+      .map(|(path, change)| {
+        let change = match change {
+          InputChange::Added(new) => serde_json::json!({ "type": "added", "new": new.to_json(state)? }),
+          InputChange::Removed(old) => serde_json::json!({ "type": "removed", "old": old.to_json(state)? }),
+          InputChange::Updated { old, new } => serde_json::json!({ "type": "updated", "old": old.to_json(state)?, "new": new.to_json(state)? })
+        };
+        Ok((path.clone(), change))
+      })
+      .collect::<Result<_>>()?;
+    Ok(Value::Object(changes))
+  }
+
+  /// Renders the same report as `nix flake update`, with a line per change.
+  pub fn report(&self, state: &NixEvalState) -> Result<String> {
+    let mut report = String::new();
+    for (path, change) in self.changes.iter() {
+      let line = match change {
+        InputChange::Added(new) => format!("• Added input '{path}': {}", new.describe(state)?),
+        InputChange::Removed(_) => format!("• Removed input '{path}'"),
+        InputChange::Updated { old, new } => format!("• Updated input '{path}': {} → {}", old.describe(state)?, new.describe(state)?)
+      };
+      report.push_str(&line);
+      report.push('\n');
+    }
+    Ok(report)
+  }
+}
+
+impl Node {
+  fn parse(value: Value) -> Result<Self> {
+    let mut extra: BTreeMap<String, Value> = expect_object(value, "node")?.into_iter().collect();
+    let inputs = match extra.remove("inputs") {
+      Some(inputs) => expect_object(inputs, "inputs")?
+        .into_iter()
+        .map(|(name, input)| Ok((name, Input::parse(input)?)))
+        .collect::<Result<_>>()?,
+      None => BTreeMap::new()
+    };
+    let flake_ref = |value: Option<Value>, what: &str| value
+      .map(|v| expect_object(v, what).map(FlakeRefAttrs::from))
+      .transpose();
+    let locked = flake_ref(extra.remove("locked"), "locked")?;
+    let original = flake_ref(extra.remove("original"), "original")?;
+    let flake = match extra.remove("flake") {
+      Some(Value::Bool(b)) => b,
+      Some(_) => anyhow::bail!("Expected 'flake' to be a boolean"),
+      None => true
+    };
+    Ok(Node { inputs, locked, original, flake, extra })
+  }
+
+  fn to_json(&self) -> Value {
+    let mut node: Map<String, Value> = self.extra.clone().into_iter().collect();
+    if !self.inputs.is_empty() {
+      let inputs = self.inputs.iter().map(|(name, input)| (name.clone(), input.to_json())).collect();
+      node.insert("inputs".to_string(), Value::Object(inputs));
+    }
+    if let Some(locked) = &self.locked {
+      node.insert("locked".to_string(), locked.into());
+    }
+    if let Some(original) = &self.original {
+      node.insert("original".to_string(), original.into());
+    }
+    if !self.flake {
+      node.insert("flake".to_string(), Value::Bool(false));
+    }
+    Value::Object(node)
+  }
+}
+
+impl LockFile {
+  pub fn parse(contents: &str) -> Result<Self> {
+    let mut extra: BTreeMap<String, Value> = expect_object(serde_json::from_str(contents)?, "lock file")?
+      .into_iter()
+      .collect();
+    let version = extra
+      .remove("version")
+      .and_then(|v| v.as_u64())
+      .ok_or_else(|| anyhow::format_err!("Lock file has no version"))?;
+    if !SUPPORTED_VERSIONS.contains(&version) {
+      anyhow::bail!("Unsupported lock file version {version}");
+    }
+    let root = match extra.remove("root") {
+      Some(Value::String(root)) => root,
+      _ => anyhow::bail!("Lock file has no root node")
+    };
+    let nodes = expect_object(extra.remove("nodes").unwrap_or_else(|| Value::Object(Map::new())), "nodes")?
+      .into_iter()
+      .map(|(name, node)| Ok((name, Node::parse(node)?)))
+      .collect::<Result<BTreeMap<_, _>>>()?;
+    if !nodes.contains_key(&root) {
+      anyhow::bail!("Root node '{root}' does not exist");
+    }
+    Ok(LockFile { version, root, nodes, extra })
+  }
+
+  pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+    Self::parse(&std::fs::read_to_string(path)?)
+  }
+
+  pub fn to_json(&self) -> Value {
+    let mut lock: Map<String, Value> = self.extra.clone().into_iter().collect();
+    let nodes = self.nodes.iter().map(|(name, node)| (name.clone(), node.to_json())).collect();
+    lock.insert("nodes".to_string(), Value::Object(nodes));
+    lock.insert("root".to_string(), Value::String(self.root.clone()));
+    lock.insert("version".to_string(), Value::from(self.version));
+    Value::Object(lock)
+  }
+
+  /// Serializes the lock file exactly like nix does, with sorted keys and two spaces of indentation.
+  pub fn to_json_string(&self) -> Result<String> {
+    let mut contents = serde_json::to_string_pretty(&self.to_json())?;
+    contents.push('\n');
+    Ok(contents)
+  }
+
+  pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    std::fs::write(path, self.to_json_string()?)?;
+    Ok(())
+  }
+
+  pub fn root_node(&self) -> &Node {
+    &self.nodes[&self.root]
+  }
+
+  pub fn node(&self, key: &str) -> Result<&Node> {
+    self.nodes
+      .get(key)
+      .ok_or_else(|| anyhow::format_err!("Lock file has no node '{key}'"))
+  }
+
+  /// Resolves an input path (like `["foo", "nixpkgs"]`) to the key of its node, following `follows` declarations.
+  pub fn resolve(&self, input_path: &[String]) -> Result<String> {
+    self.resolve_rec(input_path, 0)
+  }
+
+  fn resolve_rec(&self, input_path: &[String], depth: usize) -> Result<String> {
+    if depth > self.nodes.len() {
+      anyhow::bail!("Cycle of follows found while resolving '{}'", input_path.join("/"));
+    }
+    let mut key = self.root.clone();
+    for input in input_path {
+      key = match self.node(&key)?.inputs.get(input) {
+        Some(Input::Node(node)) => node.clone(),
+        Some(Input::Follows(path)) => self.resolve_rec(path, depth + 1)?,
+        None => anyhow::bail!("Input '{}' does not exist", input_path.join("/"))
+      };
+    }
+    Ok(key)
+  }
+
+  /// Removes the lock of an input from its parent node, so that nix locks it again from its original reference.
+  pub fn remove_input(&mut self, input_path: &[String]) -> Result<()> {
+    let (name, parent) = input_path
+      .split_last()
+      .ok_or_else(|| anyhow::format_err!("Empty input path"))?;
+    let parent = self.resolve(parent)?;
+    let removed = self.nodes
+      .get_mut(&parent)
+      .and_then(|node| node.inputs.remove(name));
+    if removed.is_none() {
+      anyhow::bail!("Input '{}' does not exist", input_path.join("/"));
+    }
+    Ok(())
+  }
+
+  /// Makes the input at `input_path` follow the input at `target`, removing the nodes that are no longer reachable.
+  pub fn set_follows(&mut self, input_path: &[String], target: &[String]) -> Result<()> {
+    let (name, parent) = input_path
+      .split_last()
+      .ok_or_else(|| anyhow::format_err!("Empty input path"))?;
+    let parent = self.resolve(parent)?;
+    let input = self.nodes
+      .get_mut(&parent)
+      .and_then(|node| node.inputs.get_mut(name))
+      .ok_or_else(|| anyhow::format_err!("Input '{}' does not exist", input_path.join("/")))?;
+    *input = Input::Follows(target.to_vec());
+    self.resolve(input_path)?;
+    self.remove_unreachable();
+    Ok(())
+  }
+
+  fn remove_unreachable(&mut self) {
+    let mut reachable = HashSet::new();
+    let mut pending = vec![self.root.clone()];
+    while let Some(key) = pending.pop() {
+      if let (true, Some(node)) = (reachable.insert(key.clone()), self.nodes.get(&key)) {
+        pending.extend(node.inputs.values().filter_map(|input| match input {
+          Input::Node(node) => Some(node.clone()),
+          Input::Follows(_) => None
+        }));
+      }
+    }
+    self.nodes.retain(|key, _| reachable.contains(key));
+  }
+
+  /// Every input path reachable from the root, with the key of the node it resolves to.
+  /// Inputs that `follow` another input are listed, but not descended into.
+  pub fn input_paths(&self) -> Result<Vec<(Vec<String>, String)>> {
+    let mut paths = Vec::new();
+    let mut pending: Vec<(Vec<String>, &Node)> = vec![(Vec::new(), self.root_node())];
+    while let Some((prefix, node)) = pending.pop() {
+      for (name, input) in node.inputs.iter().rev() {
+        let mut path = prefix.clone();
+        path.push(name.clone());
+        let key = self.resolve(&path)?;
+        if let Input::Node(_) = input {
+          // input paths can only grow until they loop back to an already visited node.
+          if prefix.len() < self.nodes.len() {
+            pending.push((path.clone(), self.node(&key)?));
+          }
+        }
+        paths.push((path, key));
+      }
+    }
+    paths.sort();
+    Ok(paths)
+  }
+
+  /// Every input reachable from the root, keyed by input path, with what it resolves to.
+  fn locked_inputs(&self) -> Result<BTreeMap<String, LockedInput>> {
+    self.input_paths()?
+      .into_iter()
+      .map(|(path, key)| {
+        let (name, parent) = path
+          .split_last()
+          .ok_or_else(|| anyhow::format_err!("Empty input path"))?;
+        let input = match self.node(&self.resolve(parent)?)?.inputs.get(name) {
+          Some(Input::Follows(target)) => LockedInput::Follows(target.clone()),
+          _ => LockedInput::Locked(self.node(&key)?.locked.clone().unwrap_or_default())
+        };
+        Ok((path.join("/"), input))
+      })
+      .collect()
+  }
+
+  /// Inputs that were added, removed or updated in `new` with respect to this lock file.
+  pub fn diff(&self, new: &LockFile) -> Result<LockFileDiff> {
+    LockFileDiff::new(Some(self), Some(new))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// `home-manager` follows the root `nixpkgs`, and `utils` has inputs of its own.
+  const FOLLOWS: &str = r#"{
+  "nodes": {
+    "home-manager": {
+      "inputs": {
+        "nixpkgs": [
+          "nixpkgs"
+        ]
+      },
+      "locked": {
+        "lastModified": 1733572789,
+        "narHash": "sha256-zjO6m5BqxXIyjrnUziAzk4+T4VleqjstNudSqWcpsHI=",
+        "owner": "nix-community",
+        "repo": "home-manager",
+        "rev": "c10c2bc61c8d9a9e3e8c8d51a2d9c0ff3e29a1a5",
+        "type": "github"
+      },
+      "original": {
+        "owner": "nix-community",
+        "repo": "home-manager",
+        "type": "github"
+      }
+    },
+    "nixpkgs": {
+      "locked": {
+        "lastModified": 1733392399,
+        "narHash": "sha256-kEsTJTUQfQFIJOcLYFt/RvNxIK653ZkTBIs4DG+cBns=",
+        "owner": "NixOS",
+        "repo": "nixpkgs",
+        "rev": "d0797a04b81caeae77bcff10a9dde78bc17f5661",
+        "type": "github"
+      },
+      "original": {
+        "id": "nixpkgs",
+        "ref": "nixos-unstable",
+        "type": "indirect"
+      }
+    },
+    "root": {
+      "inputs": {
+        "home-manager": "home-manager",
+        "nixpkgs": "nixpkgs"
+      }
+    }
+  },
+  "root": "root",
+  "version": 7
+}
+"#;
+
+  /// `src` is declared with `flake = false`.
+  const NON_FLAKE: &str = r#"{
+  "nodes": {
+    "root": {
+      "inputs": {
+        "src": "src"
+      }
+    },
+    "src": {
+      "flake": false,
+      "locked": {
+        "lastModified": 1729000000,
+        "narHash": "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+        "type": "tarball",
+        "url": "https://example.org/src-1.0.tar.gz"
+      },
+      "original": {
+        "type": "tarball",
+        "url": "https://example.org/src-1.0.tar.gz"
+      }
+    }
+  },
+  "root": "root",
+  "version": 7
+}
+"#;
+
+  /// `utils` depends on `systems`, which is locked as a node of its own with a `_2` suffix.
+  const NESTED: &str = r#"{
+  "nodes": {
+    "root": {
+      "inputs": {
+        "systems": "systems",
+        "utils": "utils"
+      }
+    },
+    "systems": {
+      "locked": {
+        "lastModified": 1681028828,
+        "narHash": "sha256-Vy1rq5AaRuLzOxct8nz4T6wlgyUR7zLU309k9mBC768=",
+        "owner": "nix-systems",
+        "repo": "default",
+        "rev": "da67096a3b9bf56a91d16901293e51ba5b49a27e",
+        "type": "github"
+      },
+      "original": {
+        "owner": "nix-systems",
+        "repo": "default",
+        "type": "github"
+      }
+    },
+    "systems_2": {
+      "locked": {
+        "lastModified": 1681028828,
+        "narHash": "sha256-Vy1rq5AaRuLzOxct8nz4T6wlgyUR7zLU309k9mBC768=",
+        "owner": "nix-systems",
+        "repo": "default-linux",
+        "rev": "31732fcf5e8fea42e59c2488ad31a0e651500f68",
+        "type": "github"
+      },
+      "original": {
+        "owner": "nix-systems",
+        "repo": "default-linux",
+        "type": "github"
+      }
+    },
+    "utils": {
+      "inputs": {
+        "systems": "systems_2"
+      },
+      "locked": {
+        "lastModified": 1731533236,
+        "narHash": "sha256-l0KFg5HjrsfsO/JpG+r7fRrqm12kzFHyUHqHCVpMMbI=",
+        "owner": "numtide",
+        "repo": "flake-utils",
+        "rev": "11707dc2f618dd54ca8739b309ec4fc024de578b",
+        "type": "github"
+      },
+      "original": {
+        "owner": "numtide",
+        "repo": "flake-utils",
+        "type": "github"
+      }
+    }
+  },
+  "root": "root",
+  "version": 7
+}
+"#;
+
+  #[test]
+  fn round_trips_byte_for_byte() {
+    for fixture in [FOLLOWS, NON_FLAKE, NESTED] {
+      let lock = LockFile::parse(fixture).unwrap();
+      assert_eq!(lock.to_json_string().unwrap(), fixture);
+    }
+  }
+
+  #[test]
+  fn parses_follows() {
+    let lock = LockFile::parse(FOLLOWS).unwrap();
+    let home_manager = lock.node("home-manager").unwrap();
+    assert_eq!(home_manager.inputs["nixpkgs"], Input::Follows(vec!["nixpkgs".to_string()]));
+    assert_eq!(lock.resolve(&parse_input_path("home-manager/nixpkgs")).unwrap(), "nixpkgs");
+  }
+
+  #[test]
+  fn parses_non_flake_inputs() {
+    let lock = LockFile::parse(NON_FLAKE).unwrap();
+    assert!(!lock.node("src").unwrap().flake);
+    assert!(lock.root_node().flake);
+  }
+
+  #[test]
+  fn follows_replace_the_locked_node() {
+    let mut lock = LockFile::parse(NESTED).unwrap();
+    lock.set_follows(&parse_input_path("utils/systems"), &parse_input_path("systems")).unwrap();
+    assert_eq!(lock.node("utils").unwrap().inputs["systems"], Input::Follows(vec!["systems".to_string()]));
+    assert_eq!(lock.resolve(&parse_input_path("utils/systems")).unwrap(), "systems");
+    assert!(!lock.nodes.contains_key("systems_2"));
+    assert!(lock.set_follows(&parse_input_path("utils/missing"), &parse_input_path("systems")).is_err());
+  }
+
+  #[test]
+  fn resolves_nested_inputs() {
+    let lock = LockFile::parse(NESTED).unwrap();
+    let paths: Vec<(String, String)> = lock.input_paths()
+      .unwrap()
+      .into_iter()
+      .map(|(path, key)| (path.join("/"), key))
+      .collect();
+    assert_eq!(paths, vec![
+      ("systems".to_string(), "systems".to_string()),
+      ("utils".to_string(), "utils".to_string()),
+      ("utils/systems".to_string(), "systems_2".to_string()),
+    ]);
+  }
+}
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use anyhow::Result;
+use serde_json::Value;
+use crate::term::{force, NixResult, NixTerm};
+use super::lock::{Input, LockFile, LockedInput};
+use crate::eval::NixEvalState;
+use super::{FlakeRefAttrs, LockedFlake};
+
+/// Reads the description and lock file of a flake from its source tree.
+const READ_FLAKE_SOURCE: &str = r#"
+flake: dir:
+let
+  root = flake.sourceInfo.outPath + (if dir == "" then "" else "/" + dir);
+  lockPath = root + "/flake.lock";
+in {
+  description = (import (root + "/flake.nix")).description or null;
+  lockFile = if builtins.pathExists lockPath then builtins.readFile lockPath else null;
+}
+"#;
+
+/// An input of the flake, with its own inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputTree {
+  pub input: LockedInput,
+  /// URL form of the locked reference, `None` for inputs that follow another one.
+  pub url: Option<String>,
+  /// Inputs of this input, empty for inputs that follow another one.
+  pub inputs: BTreeMap<String, InputTree>
+}
+
+/// Information about a locked flake, like the one shown by `nix flake metadata`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlakeMetadata {
+  pub description: Option<String>,
+  pub original: FlakeRefAttrs,
+  pub original_url: String,
+  /// Reference after resolving it through the flake registries, see [`NixEvalState::flake_registries`][crate::eval::NixEvalState::flake_registries].
+  pub resolved: FlakeRefAttrs,
+  pub resolved_url: String,
+  pub locked: FlakeRefAttrs,
+  pub locked_url: String,
+  pub last_modified: Option<i64>,
+  pub revision: Option<String>,
+  pub nar_hash: Option<String>,
+  /// Store path of the source tree.
+  pub path: String,
+  /// Lock file computed while locking (see [`LockedFlake::lock_file`]), or the one in the source of the flake
+  /// when it can't be observed.
+  pub lock_file: Option<LockFile>,
+  pub inputs: BTreeMap<String, InputTree>
+}
+
+/// Files read from the source tree of a locked flake.
+pub(super) struct FlakeSource {
+  pub(super) description: Option<String>,
+  pub(super) lock_file: Option<LockFile>
+}
+
+fn optional_attr<'state>(term: &NixTerm<'state>, name: &str) -> NixResult<Option<NixTerm<'state>>> {
+  if term.names()?.any(|n| n == name) {
+    Ok(Some(term.get(name)?))
+  } else {
+    Ok(None)
+  }
+}
+
+/// Attribute `name` of a `sourceInfo`, as it appears in a locked reference, `None` if it isn't set.
+fn source_attr(source_info: &NixTerm, name: &str) -> Result<Option<Value>> {
+  let Some(term) = optional_attr(source_info, name)? else {
+    return Ok(None);
+  };
+  Ok(Some(match force(term)? {
+    NixTerm::Int(i) => Value::from(i),
+    NixTerm::Bool(b) => Value::from(b),
+    NixTerm::String(s) => Value::from(s),
+    _ => anyhow::bail!("Attribute '{name}' of the source info should be a string, an integer or a boolean")
+  }))
+}
+
+fn input_tree(state: &NixEvalState, lock_file: &LockFile, key: &str, ancestors: &mut HashSet<String>) -> Result<BTreeMap<String, InputTree>> {
+  let mut inputs = BTreeMap::new();
+  if !ancestors.insert(key.to_string()) {
+    return Ok(inputs);
+  }
+  for (name, input) in lock_file.node(key)?.inputs.iter() {
+    let tree = match input {
+      Input::Follows(path) => InputTree { input: LockedInput::Follows(path.clone()), url: None, inputs: BTreeMap::new() },
+      Input::Node(node) => {
+        let locked = lock_file.node(node)?.locked.clone().unwrap_or_default();
+        InputTree {
+          url: Some(state.flake_ref_to_string(&locked)?),
+          input: LockedInput::Locked(locked),
+          inputs: input_tree(state, lock_file, node, ancestors)?
+        }
+      }
+    };
+    inputs.insert(name.clone(), tree);
+  }
+  ancestors.remove(key);
+  Ok(inputs)
+}
+
+impl InputTree {
+  fn to_json(&self) -> Value {
+    let mut json = match &self.input {
+      LockedInput::Locked(attrs) => serde_json::json!({ "url": self.url, "locked": Value::from(attrs) }),
+      LockedInput::Follows(path) => serde_json::json!({ "follows": path })
+    };
+    if !self.inputs.is_empty() {
+      let inputs = self.inputs.iter().map(|(name, tree)| (name.clone(), tree.to_json())).collect();
+      json["inputs"] = Value::Object(inputs);
+    }
+    json
+  }
+}
+
+impl FlakeMetadata {
+  /// Same layout as the output of `nix flake metadata --json`, plus the input tree in `inputs`.
+  pub fn to_json(&self) -> Value {
+    let mut json = serde_json::json!({
+      "description": self.description,
+      "originalUrl": self.original_url,
+      "original": Value::from(&self.original),
+      "resolvedUrl": self.resolved_url,
+      "resolved": Value::from(&self.resolved),
+      "url": self.locked_url,
+      "locked": Value::from(&self.locked),
+      "path": self.path,
+      "locks": self.lock_file.as_ref().map(LockFile::to_json),
+      "inputs": self.inputs.iter().map(|(name, tree)| (name.clone(), tree.to_json())).collect::<serde_json::Map<_, _>>()
+    });
+    if let Some(last_modified) = self.last_modified {
+      json["lastModified"] = Value::from(last_modified);
+    }
+    if let Some(revision) = &self.revision {
+      json["revision"] = Value::from(revision.as_str());
+    }
+    json
+  }
+}
+
+impl<'state> LockedFlake<'state> {
+  /// Reads `flake.nix` and `flake.lock` from the store path the flake was fetched to.
+  pub(super) fn read_source(&self) -> Result<FlakeSource> {
+    let read_source = self.state
+      .eval_expr(READ_FLAKE_SOURCE, Path::new("/"))?
+      .call_with(self.outputs()?)?
+      .call_with(self.flake_ref.attrs(self.state)?.dir().unwrap_or(""))?;
+    let description = match read_source.get("description")? {
+      NixTerm::String(description) => Some(description),
+      _ => None
+    };
+    let lock_file = match read_source.get("lockFile")? {
+      NixTerm::String(contents) => Some(LockFile::parse(&contents)?),
+      _ => None
+    };
+    Ok(FlakeSource { description, lock_file })
+  }
+
+  /// Collects the metadata of the flake from its `sourceInfo`, the flake registries and its lock file.
+  ///
+  /// The C API doesn't expose the locked reference, so it is built from the resolved one and the attributes
+  /// of the `sourceInfo` that the fetcher of its type locks it with, which are known for `github`, `gitlab`,
+  /// `sourcehut`, `git`, `hg`, `tarball`, `file` and `path` flakes. Other kinds of flakes return an error.
+  ///
+  /// When the lock file computed while locking can't be observed (see [`LockedFlake::lock_file`]), the one in the
+  /// source of the flake is used, which lacks the inputs that were only locked in memory.
+  pub fn metadata(&self) -> Result<FlakeMetadata> {
+    let outputs = self.outputs()?;
+    let source_info = outputs.get("sourceInfo")?;
+    let path = source_info.get("outPath")?.as_string()?;
+    let last_modified = optional_attr(&source_info, "lastModified")?.map(|t| t.as_int()).transpose()?;
+    let revision = optional_attr(&source_info, "rev")?.map(|t| t.as_string()).transpose()?;
+    let nar_hash = optional_attr(&source_info, "narHash")?.map(|t| t.as_string()).transpose()?;
+
+    let original = self.flake_ref.attrs(self.state)?;
+    let resolved = self.state.flake_registries().resolve(&original)?;
+    let mut locked = resolved.clone();
+    let source_attrs: &[&str] = match resolved.ref_type() {
+      Some("github" | "gitlab" | "sourcehut") => {
+        // forges are locked to a revision, which replaces the branch or tag.
+        locked.attrs.remove("ref");
+        &["rev", "narHash", "lastModified"]
+      }
+      // dirty git trees have a `dirtyRev` instead of a `rev`.
+      Some("git") => &["rev", "revCount", "lastModified", "narHash", "dirtyRev", "dirtyShortRev"],
+      Some("hg") => &["rev", "revCount", "narHash"],
+      Some("tarball" | "file") => &["rev", "lastModified", "narHash"],
+      Some("path") => &["lastModified", "narHash"],
+      other => anyhow::bail!(
+        "Cannot tell the locked reference of '{}', since the Nix C API doesn't expose it for {} flakes",
+        self.state.flake_ref_to_string(&resolved)?, other.unwrap_or("untyped")
+      )
+    };
+    for attr in source_attrs {
+      if let Some(value) = source_attr(&source_info, attr)? {
+        locked = locked.with(attr, value);
+      }
+    }
+
+    let source = self.read_source()?;
+    let lock_file = match &self.lock_result {
+      Some(result) => result.new.clone(),
+      None => source.lock_file
+    };
+    let description = source.description;
+    let inputs = match &lock_file {
+      Some(lock_file) => input_tree(self.state, lock_file, &lock_file.root, &mut HashSet::new())?,
+      None => BTreeMap::new()
+    };
+    Ok(FlakeMetadata {
+      description,
+      original_url: self.state.flake_ref_to_string(&original)?,
+      original,
+      resolved_url: self.state.flake_ref_to_string(&resolved)?,
+      resolved,
+      locked_url: self.state.flake_ref_to_string(&locked)?,
+      locked,
+      last_modified,
+      revision,
+      nar_hash,
+      path,
+      lock_file,
+      inputs
+    })
+  }
+}
@@ -0,0 +1,548 @@
+use std::{ffi::CString, path::{Path, PathBuf}, ptr::{null_mut, NonNull}, sync::Arc};
+use anyhow::Result;
+use nix::NixPath;
+
+use crate::bindings::{fetchers_settings, fetchers_settings_free, fetchers_settings_new, flake_lock, flake_lock_flags, flake_lock_flags_add_input_override, flake_lock_flags_free, flake_lock_flags_new, flake_lock_flags_set_mode_check, flake_lock_flags_set_mode_virtual, flake_lock_flags_set_mode_write_as_needed, flake_reference, flake_reference_and_fragment_from_string, flake_reference_free, flake_reference_parse_flags, flake_reference_parse_flags_new, flake_reference_parse_flags_set_base_directory, flake_settings, flake_settings_free, flake_settings_new, locked_flake, locked_flake_free, locked_flake_get_output_attrs, setting_set};
+use crate::{eval::{NixEvalState, RawValue}, store::NixContext, term::{NixTerm, ToNix}, utils::{callback_get_result_string, callback_get_result_string_data}};
+
+mod attrs;
+mod check;
+mod inventory;
+pub mod lock;
+mod metadata;
+mod overrides;
+pub mod registry;
+pub use attrs::FlakeRefAttrs;
+pub use check::{CheckReport, CheckResult, CheckStatus};
+pub use inventory::{InventoryEntry, OutputKind};
+pub use metadata::{FlakeMetadata, InputTree};
+pub use overrides::{InputOverride, InputOverrides};
+use overrides::ResolvedOverride;
+use lock::{LockFile, LockFileDiff};
+
+/// Pointer to a nix settings object that is shared between clones,
+/// and freed when the last one is dropped.
+pub(crate) struct SharedPtr<T> {
+  ptr: NonNull<T>,
+  free: unsafe extern "C" fn(*mut T)
+}
+
+impl<T> SharedPtr<T> {
+  fn new(ptr: NonNull<T>, free: unsafe extern "C" fn(*mut T)) -> Arc<Self> {
+    Arc::new(SharedPtr { ptr, free })
+  }
+
+  pub(crate) fn as_ptr(&self) -> *mut T {
+    self.ptr.as_ptr()
+  }
+}
+
+impl<T> Drop for SharedPtr<T> {
+  fn drop(&mut self) {
+    unsafe {
+      (self.free)(self.ptr.as_ptr());
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct FetchersSettings {
+  ptr: Arc<SharedPtr<fetchers_settings>>
+}
+
+impl FetchersSettings {
+  pub fn new() -> Result<Self> {
+    let ptr = NixContext::non_null(|ctx| unsafe {
+      fetchers_settings_new(ctx.ptr())
+    })?;
+    Ok(FetchersSettings { ptr: SharedPtr::new(ptr, fetchers_settings_free) })
+  }
+}
+
+pub struct FlakeRefSettings {
+  ptr: NonNull<flake_reference_parse_flags>,
+  basedir: Option<PathBuf>,
+  pub settings: FlakeSettings,
+}
+
+impl FlakeRefSettings {
+  pub fn new(settings: FlakeSettings) -> Result<Self> {
+    let ptr = NixContext::non_null(|ctx| unsafe {
+      flake_reference_parse_flags_new(ctx.ptr(), settings.settings_ptr.as_ptr())
+    })?;
+    Ok(FlakeRefSettings { ptr, basedir: None, settings })
+  }
+
+  pub fn set_basedir(&mut self, dir: &Path) -> Result<()> {
+    let dir_len = dir.len();
+    let dir_bytes  = dir.as_os_str().as_encoded_bytes().as_ptr();
+    NixContext::checking(|ctx| unsafe {
+      flake_reference_parse_flags_set_base_directory(
+        ctx.ptr(),
+        self.ptr.as_mut(),
+        dir_bytes as *const i8,
+        dir_len);
+    })?;
+    self.basedir = Some(dir.to_path_buf());
+    Ok(())
+  }
+
+  pub fn parse(self, uri: &str) -> Result<FlakeRef> {
+    let mut fragment: Result<String> = Err(anyhow::anyhow!("Nix C API didn't return a string."));
+    let mut ptr: *mut flake_reference = null_mut();
+    NixContext::checking(|ctx| unsafe {
+      flake_reference_and_fragment_from_string(
+        ctx.ptr(),
+        self.settings.fetchers_settings.ptr.as_ptr(),
+        self.settings.settings_ptr.as_ptr(),
+        self.ptr.as_ptr(),
+        uri.as_ptr() as *const i8,
+        uri.len(),
+        &mut ptr,
+        Some(callback_get_result_string),
+        callback_get_result_string_data(&mut fragment))
+    })?;
+    let ref_ptr = NonNull::new(ptr)
+      .ok_or(anyhow::format_err!("flake_reference_and_fragment_from_string returned null"))?;
+    let uri = self.absolute_uri(uri.split_once('#').map_or(uri, |(uri, _)| uri));
+    Ok(FlakeRef {
+      settings: self,
+      fragment: fragment?,
+      uri,
+      ref_ptr
+    })
+  }
+
+  /// Resolves relative paths (`.`, `./foo` or `path:./foo`) against the base directory,
+  /// since `builtins.parseFlakeRef` only accepts absolute ones.
+  fn absolute_uri(&self, uri: &str) -> String {
+    let (scheme, path) = match uri.strip_prefix("path:") {
+      Some(path) => ("path:", path),
+      None => ("", uri)
+    };
+    match &self.basedir {
+      Some(basedir) if path.starts_with('.') => format!("{scheme}{}", basedir.join(path).display()),
+      _ => uri.to_string()
+    }
+  }
+
+  /// Builds a reference from its attributes, like the ones returned by [`FlakeRef::attrs`].
+  pub fn parse_attrs(self, state: &NixEvalState, attrs: &FlakeRefAttrs) -> Result<FlakeRef> {
+    self.parse(&state.flake_ref_to_string(attrs)?)
+  }
+}
+
+pub struct FlakeRef {
+  ref_ptr: NonNull<flake_reference>,
+  pub settings: FlakeRefSettings,
+  pub fragment: String,
+  /// The reference without its fragment, with relative paths made absolute.
+  uri: String
+}
+
+impl FlakeRef {
+  /// Attributes of the reference, such as its type, owner, repo or rev, as parsed by nix.
+  /// Relative paths are resolved against the base directory of the [`FlakeRefSettings`].
+  pub fn attrs(&self, state: &NixEvalState) -> Result<FlakeRefAttrs> {
+    state.parse_flake_ref(&self.uri)
+  }
+
+  /// Renders the reference in URL form, without its fragment, like `builtins.flakeRefToString`.
+  pub fn to_url(&self, state: &NixEvalState) -> Result<String> {
+    state.flake_ref_to_string(&self.attrs(state)?)
+  }
+
+  /// Location of the `flake.lock` file of flakes in the local filesystem
+  /// (`path` references and `git+file:` urls), `None` for any other kind of reference.
+  pub fn local_lock_file(&self, state: &NixEvalState) -> Result<Option<PathBuf>> {
+    let attrs = self.attrs(state)?;
+    let mut path = match (attrs.ref_type(), attrs.path(), attrs.url()) {
+      (Some("path"), Some(path), _) => PathBuf::from(path),
+      (Some("git"), _, Some(url)) => match url.strip_prefix("file://") {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(None)
+      },
+      _ => return Ok(None)
+    };
+    if let Some(dir) = attrs.dir() {
+      path.push(dir);
+    }
+    path.push("flake.lock");
+    Ok(Some(path))
+  }
+}
+
+fn read_lock_file(path: Option<&Path>) -> Result<Option<LockFile>> {
+  match path {
+    Some(path) if path.exists() => Ok(Some(LockFile::read(path)?)),
+    _ => Ok(None)
+  }
+}
+
+impl Drop for FlakeRef {
+  fn drop(&mut self) {
+    unsafe {
+      flake_reference_free(self.ref_ptr.as_mut())
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct FlakeSettings {
+  pub(crate) settings_ptr: Arc<SharedPtr<flake_settings>>,
+  pub(crate) fetchers_settings: FetchersSettings
+}
+
+impl FlakeSettings {
+  pub fn new(fetchers_settings: FetchersSettings) -> Result<Self> {
+    let settings_ptr = NixContext::non_null(|ctx| unsafe {
+
+      flake_settings_new(ctx.ptr())
+    })?;
+    // TODO: is this really necessary? doesn't make much sense to me
+    NixContext::checking(|ctx| unsafe {
+      let key = "experimental-features".to_string();
+      let val = "flakes".to_string();
+      setting_set(ctx.ptr(), key.as_ptr() as *const i8, val.as_ptr() as *const i8)
+    })?;
+    Ok(FlakeSettings { settings_ptr: SharedPtr::new(settings_ptr, flake_settings_free), fetchers_settings })
+  }
+}
+
+/// How the lock file of a flake is handled when locking it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+  /// Fail if the lock file is missing or out of date.
+  Check,
+  /// Update the lock file in memory only, without writing it.
+  Virtual,
+  /// Update the lock file and write it to disk if it changed.
+  WriteAsNeeded
+}
+
+pub struct FlakeLockFlags {
+  ptr: NonNull<flake_lock_flags>,
+  mode: LockMode,
+  input_updates: Vec<Vec<String>>,
+  recreate_lock_file: bool,
+  overrides: InputOverrides
+}
+
+impl FlakeLockFlags {
+  /// Creates lock flags in [`LockMode::Virtual`] mode, the default of the C API.
+  pub fn new(flake_settings: &FlakeSettings) -> Result<Self> {
+    let ptr = NixContext::non_null(|ctx| unsafe {
+      flake_lock_flags_new(ctx.ptr(), flake_settings.settings_ptr.as_ptr())
+    })?;
+    Ok(FlakeLockFlags {
+      ptr,
+      mode: LockMode::Virtual,
+      input_updates: Vec::new(),
+      recreate_lock_file: false,
+      overrides: InputOverrides::default()
+    })
+  }
+
+  pub fn mode(&self) -> LockMode {
+    self.mode
+  }
+
+  pub fn set_mode(&mut self, mode: LockMode) -> Result<()> {
+    NixContext::checking(|ctx| unsafe {
+      match mode {
+        LockMode::Check => flake_lock_flags_set_mode_check(ctx.ptr(), self.ptr.as_ptr()),
+        LockMode::Virtual => flake_lock_flags_set_mode_virtual(ctx.ptr(), self.ptr.as_ptr()),
+        LockMode::WriteAsNeeded => flake_lock_flags_set_mode_write_as_needed(ctx.ptr(), self.ptr.as_ptr()),
+      };
+    })?;
+    self.mode = mode;
+    Ok(())
+  }
+
+  /// Locks the input at `input_path` (like `nixpkgs` or `foo/nixpkgs`) again, ignoring its current lock.
+  ///
+  /// Since the C API has no equivalent of `--update-input`, this is done by removing the input
+  /// from the flake's `flake.lock` before locking, so it only works for local flakes in [`LockMode::WriteAsNeeded`] mode.
+  /// The original lock file is written back if locking fails.
+  pub fn update_input(&mut self, input_path: &str) {
+    self.input_updates.push(lock::parse_input_path(input_path));
+  }
+
+  /// Ignores the existing lock file, locking every input again, with the same restrictions as [`FlakeLockFlags::update_input`].
+  pub fn recreate_lock_file(&mut self, recreate: bool) {
+    self.recreate_lock_file = recreate;
+  }
+
+  /// Removes the locks that should be recomputed from the lock file of `flake_ref`,
+  /// returning a backup of the lock file if it was modified.
+  fn apply_updates(&self, lock_path: Option<&Path>, lock_file: Option<&LockFile>) -> Result<Option<LockFileBackup>> {
+    if self.input_updates.is_empty() && !self.recreate_lock_file {
+      return Ok(None);
+    }
+    if self.mode != LockMode::WriteAsNeeded {
+      anyhow::bail!("Updating inputs requires the lock mode to be {:?}", LockMode::WriteAsNeeded);
+    }
+    let path = lock_path
+      .ok_or_else(|| anyhow::format_err!("Inputs can only be updated for flakes in the local filesystem"))?
+      .to_path_buf();
+    let Some(lock_file) = lock_file else {
+      // there is nothing to update, every input will be locked anyway.
+      return Ok(None);
+    };
+    let mut updated = lock_file.clone();
+    for input_path in self.input_updates.iter() {
+      updated.remove_input(input_path)?;
+    }
+    let backup = LockFileBackup::new(&path)?;
+    if self.recreate_lock_file {
+      std::fs::remove_file(&backup.path)?;
+    } else {
+      updated.write(&backup.path)?;
+    }
+    Ok(Some(backup))
+  }
+
+  pub fn add_input_override(&mut self, input_path: &str, flake_ref: &FlakeRef) -> Result<()> {
+    let input_path = CString::new(input_path)?;
+    NixContext::checking(|ctx| unsafe {
+      flake_lock_flags_add_input_override(ctx.ptr(), self.ptr.as_ptr(), input_path.as_ptr(), flake_ref.ref_ptr.as_ptr());
+    })
+  }
+
+  /// Sets overrides that are validated and added when locking, replacing the previous ones.
+  /// See [`InputOverrides::follows`] for how `follows` are emulated.
+  pub fn set_overrides(&mut self, overrides: InputOverrides) {
+    self.overrides = overrides;
+  }
+
+  fn apply_overrides(&mut self, state: &NixEvalState, flake_ref: &FlakeRef, lock_file: Option<&LockFile>) -> Result<()> {
+    for (input_path, resolved) in self.overrides.resolve(lock_file)? {
+      let uri = match resolved {
+        ResolvedOverride::Uri(uri) => uri,
+        ResolvedOverride::Locked(attrs) => state.flake_ref_to_string(&attrs)?
+      };
+      let mut ref_settings = FlakeRefSettings::new(flake_ref.settings.settings.clone())?;
+      if let Some(basedir) = &flake_ref.settings.basedir {
+        ref_settings.set_basedir(basedir)?;
+      }
+      let override_ref = ref_settings.parse(&uri)?;
+      self.add_input_override(&input_path.join("/"), &override_ref)?;
+    }
+    Ok(())
+  }
+
+  /// Replaces the nodes that nix locked for inputs with a `follows` override by `follows` edges in the written lock file.
+  fn record_follows(&self, lock_path: &Path) -> Result<()> {
+    if self.overrides.follows_targets().next().is_none() || !lock_path.exists() {
+      return Ok(());
+    }
+    let mut lock_file = LockFile::read(lock_path)?;
+    for (input_path, target) in self.overrides.follows_targets() {
+      lock_file.set_follows(input_path, target)?;
+    }
+    lock_file.write(lock_path)
+  }
+
+}
+
+impl Drop for FlakeLockFlags {
+  fn drop(&mut self) {
+    unsafe {
+      flake_lock_flags_free(self.ptr.as_ptr());
+    }
+  }
+}
+
+
+/// Original contents of a `flake.lock` that is modified while locking, `None` if it didn't exist.
+struct LockFileBackup {
+  path: PathBuf,
+  contents: Option<Vec<u8>>
+}
+
+impl LockFileBackup {
+  fn new(path: &Path) -> Result<Self> {
+    let contents = match std::fs::read(path) {
+      Ok(contents) => Some(contents),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e.into())
+    };
+    Ok(LockFileBackup { path: path.to_path_buf(), contents })
+  }
+
+  fn restore(self) -> Result<()> {
+    let restored = match self.contents {
+      Some(contents) => std::fs::write(&self.path, contents),
+      None => std::fs::remove_file(&self.path).or_else(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e)
+      })
+    };
+    restored.map_err(|e| anyhow::format_err!("Could not restore '{}': {e}", self.path.display()))
+  }
+
+  /// Restores the lock file, if it was backed up, after locking failed with `error`.
+  fn restore_after(backup: Option<Self>, error: anyhow::Error) -> anyhow::Error {
+    match backup.map(LockFileBackup::restore) {
+      Some(Err(restore_error)) => restore_error,
+      _ => error
+    }
+  }
+}
+
+/// Lock file of a flake before and after locking it, `None` when the flake has no lock file.
+struct LockResult {
+  old: Option<LockFile>,
+  new: Option<LockFile>
+}
+
+pub struct LockedFlake<'state> {
+  ptr: NonNull<locked_flake>,
+  state: &'state NixEvalState,
+  pub flags: FlakeLockFlags,
+  pub flake_ref: FlakeRef,
+  /// `None` when the lock file computed by nix can't be observed, see [`LockedFlake::lock_file`].
+  lock_result: Option<LockResult>
+}
+
+impl<'state> LockedFlake<'state> {
+  /// Lock file that nix computed while locking, `None` if the flake has no inputs to lock.
+  ///
+  /// The C API doesn't expose the lock file, so it is read back from the flake's source, which is only
+  /// accurate in [`LockMode::Check`] mode, where it can't change, or in [`LockMode::WriteAsNeeded`] mode for
+  /// flakes in the local filesystem, where it is written. In any other case this returns an error.
+  pub fn lock_file(&self) -> Result<Option<&LockFile>> {
+    Ok(self.lock_result()?.new.as_ref())
+  }
+
+  /// Inputs that changed in the lock file while locking, with the same restrictions as [`LockedFlake::lock_file`].
+  pub fn lock_file_diff(&self) -> Result<LockFileDiff> {
+    let result = self.lock_result()?;
+    LockFileDiff::new(result.old.as_ref(), result.new.as_ref())
+  }
+
+  fn lock_result(&self) -> Result<&LockResult> {
+    self.lock_result.as_ref().ok_or_else(|| anyhow::format_err!(
+      "The lock file of '{}' computed in {:?} mode is not exposed by the Nix C API, lock it in {:?} mode, or in {:?} mode if it is a local flake",
+      self.flake_ref.uri, self.flags.mode, LockMode::Check, LockMode::WriteAsNeeded
+    ))
+  }
+
+  pub fn outputs(&self) -> Result<NixTerm<'state>> {
+    let value = NixContext::non_null(|ctx| unsafe {
+      locked_flake_get_output_attrs(
+        ctx.ptr(),
+        self.flake_ref.settings.settings.settings_ptr.as_ptr(),
+        self.state.state_ptr(),
+        self.ptr.as_ptr())
+    })?;
+    let raw_value = RawValue {
+      _state: self.state,
+      value
+    };
+    raw_value.to_nix(self.state)
+      .map_err(|e| e.into())
+  }
+}
+
+/// Splits an attribute path like `packages.x86_64-linux."hello.world"` into its components.
+pub(crate) fn parse_attr_path(path: &str) -> Result<Vec<String>> {
+  let mut attrs = Vec::new();
+  let mut current = String::new();
+  let mut chars = path.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '.' => attrs.push(std::mem::take(&mut current)),
+      '"' => loop {
+        match chars.next() {
+          Some('"') => break,
+          Some(c) => current.push(c),
+          None => anyhow::bail!("Missing closing quote in attribute path '{path}'")
+        }
+      },
+      c => current.push(c)
+    }
+  }
+  if !path.is_empty() {
+    attrs.push(current);
+  }
+  Ok(attrs)
+}
+
+impl<'state> Drop for LockedFlake<'state> {
+  fn drop(&mut self) {
+    unsafe {
+      locked_flake_free(self.ptr.as_ptr());
+    }
+  }
+}
+
+impl NixEvalState {
+
+  pub fn flake_settings(&self) -> Result<&FlakeSettings> {
+    self.settings.flake_settings
+      .as_ref()
+      .ok_or(anyhow::format_err!("NixEvalState was not initialized with flakes enabled."))
+  }
+  
+  pub fn lock_flake<'state>(&'state self, flake_ref: FlakeRef, mut lock_flags: FlakeLockFlags) -> Result<LockedFlake<'state>> {
+    let lock_path = flake_ref.local_lock_file(self)?;
+    let old_lock_file = read_lock_file(lock_path.as_deref())?;
+    lock_flags.apply_overrides(self, &flake_ref, old_lock_file.as_ref())?;
+    let mut backup = lock_flags.apply_updates(lock_path.as_deref(), old_lock_file.as_ref())?;
+    // recording follows rewrites the lock file written by nix, which is undone if it fails.
+    if let (None, LockMode::WriteAsNeeded, Some(lock_path)) = (&backup, lock_flags.mode, &lock_path) {
+      if lock_flags.overrides.follows_targets().next().is_some() {
+        backup = Some(LockFileBackup::new(lock_path)?);
+      }
+    }
+    let locked = NixContext::non_null(|ctx| unsafe {
+      flake_lock(
+        ctx.ptr(),
+        flake_ref.settings.settings.fetchers_settings.ptr.as_ptr(),
+        flake_ref.settings.settings.settings_ptr.as_ptr(),
+        self.state_ptr(),
+        lock_flags.ptr.as_ptr(),
+        flake_ref.ref_ptr.as_ptr())
+    });
+    let ptr = locked.map_err(|e| LockFileBackup::restore_after(backup.take(), e))?;
+    let mut locked_flake = LockedFlake { ptr, flags: lock_flags, flake_ref, state: self, lock_result: None };
+    if let (LockMode::WriteAsNeeded, Some(lock_path)) = (locked_flake.flags.mode, &lock_path) {
+      locked_flake.flags
+        .record_follows(lock_path)
+        .map_err(|e| LockFileBackup::restore_after(backup.take(), e))?;
+    }
+    locked_flake.lock_result = match (locked_flake.flags.mode, lock_path.is_some()) {
+      (LockMode::Check | LockMode::WriteAsNeeded, true) => Some(LockResult {
+        old: old_lock_file,
+        new: read_lock_file(lock_path.as_deref())?
+      }),
+      // locking can't change the lock file, so the one in the fetched source is the result.
+      (LockMode::Check, false) => {
+        let lock_file = locked_flake.read_source()?.lock_file;
+        Some(LockResult { old: lock_file.clone(), new: lock_file })
+      }
+      _ => None
+    };
+    Ok(locked_flake)
+  }
+
+  /// Locks the flake at `uri` in memory, using the evaluator's flake settings, and returns its outputs.
+  /// If the uri has a fragment (`path:.#packages.x86_64-linux.hello`), it is used as an attribute path into the outputs.
+  pub fn eval_flake<'state>(&'state self, uri: &str) -> Result<NixTerm<'state>> {
+    let settings = self.flake_settings()?;
+    let mut ref_settings = FlakeRefSettings::new(settings.clone())?;
+    ref_settings.set_basedir(&std::env::current_dir()?)?;
+    let flake_ref = ref_settings.parse(uri)?;
+    let attr_path = parse_attr_path(&flake_ref.fragment)?;
+    let mut lock_flags = FlakeLockFlags::new(settings)?;
+    lock_flags.set_mode(LockMode::Virtual)?;
+    let outputs = self.lock_flake(flake_ref, lock_flags)?.outputs()?;
+    if attr_path.is_empty() {
+      Ok(outputs)
+    } else {
+      Ok(outputs.get_attrs(&attr_path)?)
+    }
+  }
+
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use super::lock::{parse_input_path, LockFile};
+use super::FlakeRefAttrs;
+
+/// How an input is overridden when locking a flake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputOverride {
+  /// Use another flake reference for the input, like `--override-input`.
+  Ref(String),
+  /// Use the same source as another input, given by its input path.
+  Follows(Vec<String>)
+}
+
+/// Flake reference that an overridden input resolves to.
+pub(crate) enum ResolvedOverride {
+  Uri(String),
+  /// Locked reference of the input that is followed, taken from the lock file.
+  Locked(FlakeRefAttrs)
+}
+
+/// Set of input overrides, keyed by input path, to be applied with [`FlakeLockFlags::set_overrides`][super::FlakeLockFlags::set_overrides].
+///
+/// ```ignore
+/// let overrides = InputOverrides::new()
+///   .override_input("nixpkgs", "path:/tmp/nixpkgs")?
+///   .follows("foo/nixpkgs", "nixpkgs")?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputOverrides {
+  pub overrides: BTreeMap<Vec<String>, InputOverride>
+}
+
+fn validate_input_path(input_path: &str) -> Result<Vec<String>> {
+  if input_path.split('/').any(str::is_empty) {
+    anyhow::bail!("Invalid input path '{input_path}'");
+  }
+  Ok(parse_input_path(input_path))
+}
+
+impl InputOverrides {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Overrides the input at `input_path` (like `nixpkgs` or `foo/nixpkgs`) with the flake reference `uri`.
+  pub fn override_input(self, input_path: &str, uri: &str) -> Result<Self> {
+    self.insert(validate_input_path(input_path)?, InputOverride::Ref(uri.to_string()))
+  }
+
+  /// Makes the input at `input_path` follow the input at `target`, which is another path starting at the root flake.
+  ///
+  /// The C API can only override inputs with flake references, so the input is overridden with the locked
+  /// reference of `target`, which must either be overridden too or already be locked in the flake's lock file.
+  /// When the lock file is written ([`LockMode::WriteAsNeeded`][super::LockMode::WriteAsNeeded]), it then records
+  /// `input_path` as following `target`; otherwise both inputs only share the same source.
+  pub fn follows(self, input_path: &str, target: &str) -> Result<Self> {
+    self.insert(validate_input_path(input_path)?, InputOverride::Follows(validate_input_path(target)?))
+  }
+
+  /// Adds an override written like the arguments of `--override-input`, that is, `nixpkgs path:/tmp/nixpkgs`,
+  /// or a follows declaration like `foo/nixpkgs follows nixpkgs`.
+  pub fn with_spec(self, spec: &str) -> Result<Self> {
+    match spec.split_whitespace().collect::<Vec<_>>()[..] {
+      [input_path, "follows", target] => self.follows(input_path, target),
+      [input_path, uri] => self.override_input(input_path, uri),
+      _ => anyhow::bail!("Invalid input override '{spec}', expected '<input> <flake ref>' or '<input> follows <input>'")
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.overrides.is_empty()
+  }
+
+  /// Inputs that follow another one, with the input path they follow.
+  pub(crate) fn follows_targets(&self) -> impl Iterator<Item=(&[String], &[String])> {
+    self.overrides
+      .iter()
+      .filter_map(|(input_path, input_override)| match input_override {
+        InputOverride::Follows(target) => Some((input_path.as_slice(), target.as_slice())),
+        InputOverride::Ref(_) => None
+      })
+  }
+
+  fn insert(mut self, input_path: Vec<String>, input_override: InputOverride) -> Result<Self> {
+    if let Some(existing) = self.overrides.get(&input_path) {
+      if existing != &input_override {
+        anyhow::bail!("Input '{}' is overridden twice", input_path.join("/"));
+      }
+    }
+    self.overrides.insert(input_path.clone(), input_override);
+    // catch cycles as soon as they are introduced.
+    self.resolve_follows(&[input_path], None)?;
+    Ok(self)
+  }
+
+  /// Resolves each override into the flake reference the input should use, following `follows`
+  /// declarations through other overrides and, when they point to a non-overridden input, through `lock_file`.
+  fn resolve_follows(&self, input_paths: &[Vec<String>], lock_file: Option<&LockFile>) -> Result<Vec<(Vec<String>, Option<ResolvedOverride>)>> {
+    input_paths
+      .iter()
+      .map(|input_path| {
+        let mut current = input_path;
+        for _ in 0..=self.overrides.len() {
+          match self.overrides.get(current) {
+            Some(InputOverride::Ref(uri)) => return Ok((input_path.clone(), Some(ResolvedOverride::Uri(uri.clone())))),
+            Some(InputOverride::Follows(target)) => current = target,
+            None => {
+              let Some(lock_file) = lock_file else {
+                return Ok((input_path.clone(), None));
+              };
+              let node = lock_file.node(&lock_file.resolve(current)?)?;
+              let locked = node.locked.as_ref().ok_or_else(|| {
+                anyhow::format_err!("Input '{}' follows '{}', which is not locked", input_path.join("/"), current.join("/"))
+              })?;
+              return Ok((input_path.clone(), Some(ResolvedOverride::Locked(locked.clone()))));
+            }
+          }
+        }
+        anyhow::bail!("Input '{}' follows itself", input_path.join("/"))
+      })
+      .collect()
+  }
+
+  /// Checks that the overridden inputs belong to flakes that exist in `lock_file`,
+  /// and returns the flake reference each input is overridden with.
+  pub(crate) fn resolve(&self, lock_file: Option<&LockFile>) -> Result<Vec<(Vec<String>, ResolvedOverride)>> {
+    if let Some(lock_file) = lock_file {
+      for input_path in self.overrides.keys() {
+        let parent = &input_path[..input_path.len() - 1];
+        lock_file
+          .resolve(parent)
+          .map_err(|e| anyhow::format_err!("Cannot override input '{}': {e}", input_path.join("/")))?;
+      }
+    }
+    let input_paths: Vec<_> = self.overrides.keys().cloned().collect();
+    self.resolve_follows(&input_paths, lock_file)?
+      .into_iter()
+      .map(|(input_path, uri)| match uri {
+        Some(uri) => Ok((input_path, uri)),
+        None => anyhow::bail!("Input '{}' follows an input that is not locked yet, lock the flake without this override first", input_path.join("/"))
+      })
+      .collect()
+  }
+}
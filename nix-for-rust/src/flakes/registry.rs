@@ -0,0 +1,368 @@
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use serde_json::{Map, Value};
+use crate::eval::NixEvalState;
+use super::{FlakeRef, FlakeRefAttrs, FlakeRefSettings, LockedFlake};
+
+/// Version of the registry files that can be read and written.
+const REGISTRY_VERSION: u64 = 2;
+
+/// Maximum number of registry lookups when resolving a reference, to catch cycles.
+const MAX_INDIRECTIONS: usize = 100;
+
+/// Entry of a flake registry, mapping references (usually indirect ones like `nixpkgs`) to other references.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistryEntry {
+  pub from: FlakeRefAttrs,
+  pub to: FlakeRefAttrs,
+  /// Only match references that are exactly `from`, instead of also matching any ref or rev of it.
+  pub exact: bool
+}
+
+/// A single flake registry file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Registry {
+  pub entries: Vec<RegistryEntry>
+}
+
+/// The registries used to resolve indirect references, in lookup order.
+///
+/// Nix fetches the global registry from the network by default, so it is only read here from a local file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Registries {
+  pub user: Option<PathBuf>,
+  pub system: Option<PathBuf>,
+  pub global: Option<PathBuf>
+}
+
+fn parse_attrs(value: Option<&Value>, what: &str) -> Result<FlakeRefAttrs> {
+  match value {
+    Some(Value::Object(attrs)) => Ok(FlakeRefAttrs::from(attrs.clone())),
+    _ => anyhow::bail!("Expected registry entry to have an attribute set in '{what}'")
+  }
+}
+
+impl RegistryEntry {
+  /// Whether this entry applies to `attrs`.
+  fn matches(&self, attrs: &FlakeRefAttrs) -> bool {
+    let without_dir = |attrs: &FlakeRefAttrs| {
+      let mut attrs = attrs.attrs.clone();
+      attrs.remove("dir");
+      attrs
+    };
+    let (from, attrs) = (without_dir(&self.from), without_dir(attrs));
+    if self.exact {
+      from == attrs
+    } else {
+      from.iter().all(|(key, value)| attrs.get(key) == Some(value))
+    }
+  }
+
+  /// Applies the entry to `attrs`, keeping the dir (and for non-exact entries, the ref and rev) that the entry doesn't set.
+  fn apply(&self, attrs: &FlakeRefAttrs) -> FlakeRefAttrs {
+    let mut resolved = self.to.clone();
+    let kept: &[&str] = if self.exact { &["dir"] } else { &["ref", "rev", "dir"] };
+    for &attr in kept {
+      if let (Some(value), false) = (attrs.get(attr), resolved.attrs.contains_key(attr)) {
+        resolved = resolved.with(attr, value.clone());
+      }
+    }
+    resolved
+  }
+}
+
+impl Registry {
+  pub fn parse(contents: &str) -> Result<Self> {
+    let json: Value = serde_json::from_str(contents)?;
+    let version = json.get("version").and_then(Value::as_u64);
+    if version != Some(REGISTRY_VERSION) {
+      anyhow::bail!("Unsupported flake registry version {version:?}");
+    }
+    let entries = json
+      .get("flakes")
+      .and_then(Value::as_array)
+      .ok_or_else(|| anyhow::format_err!("Flake registry has no 'flakes' list"))?
+      .iter()
+      .map(|entry| Ok(RegistryEntry {
+        from: parse_attrs(entry.get("from"), "from")?,
+        to: parse_attrs(entry.get("to"), "to")?,
+        exact: entry.get("exact").and_then(Value::as_bool).unwrap_or(false)
+      }))
+      .collect::<Result<_>>()?;
+    Ok(Registry { entries })
+  }
+
+  /// Reads the registry at `path`, which is empty if the file doesn't exist.
+  pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+    match std::fs::read_to_string(path) {
+      Ok(contents) => Self::parse(&contents),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Registry::default()),
+      Err(e) => Err(e.into())
+    }
+  }
+
+  pub fn to_json(&self) -> Value {
+    let flakes = self.entries
+      .iter()
+      .map(|entry| {
+        let mut json = Map::new();
+        json.insert("from".to_string(), Value::from(&entry.from));
+        json.insert("to".to_string(), Value::from(&entry.to));
+        if entry.exact {
+          json.insert("exact".to_string(), Value::Bool(true));
+        }
+        Value::Object(json)
+      })
+      .collect();
+    serde_json::json!({ "version": REGISTRY_VERSION, "flakes": Value::Array(flakes) })
+  }
+
+  pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    let mut contents = serde_json::to_string_pretty(&self.to_json())?;
+    contents.push('\n');
+    std::fs::write(path, contents)?;
+    Ok(())
+  }
+
+  /// Adds an entry, replacing the ones with the same `from`.
+  pub fn add(&mut self, from: FlakeRefAttrs, to: FlakeRefAttrs, exact: bool) {
+    self.remove(&from);
+    self.entries.push(RegistryEntry { from, to, exact });
+  }
+
+  pub fn remove(&mut self, from: &FlakeRefAttrs) {
+    self.entries.retain(|entry| &entry.from != from);
+  }
+
+  /// Pins the indirect reference `id` to `locked`, like `nix registry pin`.
+  /// Pinning to another indirect reference is refused, since it would be looked up again.
+  pub fn pin(&mut self, id: &str, locked: FlakeRefAttrs) -> Result<()> {
+    if locked.ref_type() == Some("indirect") {
+      anyhow::bail!("Cannot pin '{id}' to the indirect reference 'flake:{}'", locked.id().unwrap_or_default());
+    }
+    self.add(FlakeRefAttrs::indirect(id), locked, false);
+    Ok(())
+  }
+
+  /// Pins the indirect reference `id` to the locked source of `flake`, see [`LockedFlake::metadata`].
+  pub fn pin_locked_flake(&mut self, id: &str, flake: &LockedFlake) -> Result<()> {
+    self.pin(id, flake.metadata()?.locked)
+  }
+
+  pub fn unpin(&mut self, id: &str) {
+    self.remove(&FlakeRefAttrs::indirect(id));
+  }
+
+  /// First entry that applies to `attrs`, resolved.
+  pub fn lookup(&self, attrs: &FlakeRefAttrs) -> Option<FlakeRefAttrs> {
+    self.entries
+      .iter()
+      .find(|entry| entry.matches(attrs))
+      .map(|entry| entry.apply(attrs))
+  }
+}
+
+impl Registries {
+  /// The user registry in `$XDG_CONFIG_HOME/nix/registry.json` and the system one in `/etc/nix/registry.json`.
+  pub fn default_locations() -> Self {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    Registries {
+      user: config_home.map(|dir| dir.join("nix/registry.json")),
+      system: Some(PathBuf::from("/etc/nix/registry.json")),
+      global: None
+    }
+  }
+
+  pub fn with_user(mut self, path: &Path) -> Self {
+    self.user = Some(path.to_path_buf());
+    self
+  }
+
+  pub fn with_system(mut self, path: &Path) -> Self {
+    self.system = Some(path.to_path_buf());
+    self
+  }
+
+  /// Uses a local file in place of the global registry that nix downloads.
+  pub fn with_global(mut self, path: &Path) -> Self {
+    self.global = Some(path.to_path_buf());
+    self
+  }
+
+  /// Reads every configured registry, in lookup order.
+  pub fn read(&self) -> Result<Vec<Registry>> {
+    [&self.user, &self.system, &self.global]
+      .into_iter()
+      .flatten()
+      .map(Registry::read)
+      .collect()
+  }
+
+  /// Resolves an indirect reference through the registries, returning other kinds of references unchanged.
+  pub fn resolve(&self, attrs: &FlakeRefAttrs) -> Result<FlakeRefAttrs> {
+    let registries = self.read()?;
+    let mut resolved = attrs.clone();
+    for _ in 0..MAX_INDIRECTIONS {
+      if resolved.ref_type() != Some("indirect") {
+        return Ok(resolved);
+      }
+      resolved = registries
+        .iter()
+        .find_map(|registry| registry.lookup(&resolved))
+        .ok_or_else(|| anyhow::format_err!("Cannot find flake 'flake:{}' in the flake registries", resolved.id().unwrap_or_default()))?;
+    }
+    anyhow::bail!("Too many levels of indirection while resolving 'flake:{}'", attrs.id().unwrap_or_default())
+  }
+
+  /// Resolves `flake_ref` through the registries, keeping its fragment.
+  pub fn resolve_ref(&self, state: &NixEvalState, flake_ref: FlakeRef) -> Result<FlakeRef> {
+    let resolved = self.resolve(&flake_ref.attrs(state)?)?;
+    let mut ref_settings = FlakeRefSettings::new(flake_ref.settings.settings.clone())?;
+    if let Some(basedir) = &flake_ref.settings.basedir {
+      ref_settings.set_basedir(basedir)?;
+    }
+    let mut resolved_ref = ref_settings.parse_attrs(state, &resolved)?;
+    resolved_ref.fragment = flake_ref.fragment.clone();
+    Ok(resolved_ref)
+  }
+}
+
+impl NixEvalState {
+  /// Default registries, using the `flake-registry` setting as the global registry when it is a local file.
+  pub fn flake_registries(&self) -> Registries {
+    let global = self.settings
+      .get_setting("flake-registry")
+      .map(|registry| registry.strip_prefix("file://").map(String::from).unwrap_or(registry))
+      .filter(|registry| registry.starts_with('/'))
+      .map(PathBuf::from);
+    Registries { global, ..Registries::default_locations() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const REV: &str = "d0797a04b81caeae77bcff10a9dde78bc17f5661";
+
+  #[test]
+  fn pins_are_looked_up_with_their_ref_and_rev() {
+    let mut registry = Registry::default();
+    registry.pin("nixpkgs", FlakeRefAttrs::github("NixOS", "nixpkgs").with_rev(REV)).unwrap();
+    let resolved = registry.lookup(&FlakeRefAttrs::indirect("nixpkgs").with_dir("lib")).unwrap();
+    assert_eq!(resolved, FlakeRefAttrs::github("NixOS", "nixpkgs").with_rev(REV).with_dir("lib"));
+  }
+
+  #[test]
+  fn pinning_to_an_indirect_reference_is_refused() {
+    let mut registry = Registry::default();
+    assert!(registry.pin("nixpkgs", FlakeRefAttrs::indirect("nixpkgs").with_rev(REV)).is_err());
+    assert!(registry.entries.is_empty());
+  }
+
+  /// Registry in which each `(from, to)` pair maps an indirect reference to another reference.
+  fn registry(entries: &[(&str, FlakeRefAttrs)]) -> Registry {
+    let mut registry = Registry::default();
+    for (from, to) in entries {
+      registry.add(FlakeRefAttrs::indirect(from), to.clone(), false);
+    }
+    registry
+  }
+
+  /// User, system and global registries written to temporary files.
+  fn registries(dir: &Path, [user, system, global]: [Registry; 3]) -> Result<Registries> {
+    let paths = ["user/registry.json", "system/registry.json", "global/registry.json"].map(|path| dir.join(path));
+    for (registry, path) in [user, system, global].iter().zip(&paths) {
+      registry.write(path)?;
+    }
+    let [user, system, global] = &paths;
+    Ok(Registries::default().with_user(user).with_system(system).with_global(global))
+  }
+
+  #[test]
+  fn registries_round_trip() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("nested/registry.json");
+    assert_eq!(Registry::read(&path)?, Registry::default());
+    let mut registry = registry(&[("nixpkgs", FlakeRefAttrs::github("NixOS", "nixpkgs").with_ref("nixos-unstable"))]);
+    registry.add(FlakeRefAttrs::indirect("pinned").with_rev(REV), FlakeRefAttrs::github("NixOS", "nixpkgs").with_rev(REV), true);
+    registry.write(&path)?;
+    assert_eq!(Registry::read(&path)?, registry);
+
+    let written: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    assert_eq!(written, serde_json::json!({
+      "version": 2,
+      "flakes": [
+        {
+          "from": { "type": "indirect", "id": "nixpkgs" },
+          "to": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "ref": "nixos-unstable" }
+        },
+        {
+          "from": { "type": "indirect", "id": "pinned", "rev": REV },
+          "to": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": REV },
+          "exact": true
+        }
+      ]
+    }));
+    assert!(Registry::parse(r#"{ "version": 1, "flakes": [] }"#).is_err());
+    assert!(Registry::parse(r#"{ "version": 2, "flakes": [{ "from": "nixpkgs", "to": {} }] }"#).is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn user_registry_takes_precedence() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let user = FlakeRefAttrs::local(Path::new("/user"));
+    let system = FlakeRefAttrs::local(Path::new("/system"));
+    let global = FlakeRefAttrs::github("NixOS", "nixpkgs");
+    let registries = registries(dir.path(), [
+      registry(&[("a", user.clone())]),
+      registry(&[("a", system.clone()), ("b", system.clone())]),
+      registry(&[("a", global.clone()), ("b", global.clone()), ("c", global.clone())])
+    ])?;
+    assert_eq!(registries.resolve(&FlakeRefAttrs::indirect("a"))?, user);
+    assert_eq!(registries.resolve(&FlakeRefAttrs::indirect("b"))?, system);
+    assert_eq!(registries.resolve(&FlakeRefAttrs::indirect("c"))?, global);
+    assert_eq!(registries.resolve(&global)?, global);
+    let missing = registries.resolve(&FlakeRefAttrs::indirect("d")).unwrap_err();
+    assert_eq!(missing.to_string(), "Cannot find flake 'flake:d' in the flake registries");
+    Ok(())
+  }
+
+  #[test]
+  fn indirect_references_are_resolved_until_they_are_direct() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let nixpkgs = FlakeRefAttrs::github("NixOS", "nixpkgs");
+    let registries = registries(dir.path(), [
+      registry(&[("a", FlakeRefAttrs::indirect("b"))]),
+      Registry::default(),
+      registry(&[("b", nixpkgs.clone()), ("c", FlakeRefAttrs::indirect("d")), ("d", FlakeRefAttrs::indirect("c"))])
+    ])?;
+    let resolved = registries.resolve(&FlakeRefAttrs::indirect("a").with_ref("nixos-unstable").with_dir("lib"))?;
+    assert_eq!(resolved, nixpkgs.with_ref("nixos-unstable").with_dir("lib"));
+    let cycle = registries.resolve(&FlakeRefAttrs::indirect("c")).unwrap_err();
+    assert_eq!(cycle.to_string(), "Too many levels of indirection while resolving 'flake:c'");
+    Ok(())
+  }
+
+  #[test]
+  fn exact_entries_only_match_the_same_reference() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let latest = FlakeRefAttrs::github("NixOS", "nixpkgs");
+    let pinned = latest.clone().with_rev(REV);
+    let mut user = Registry::default();
+    user.add(FlakeRefAttrs::indirect("nixpkgs"), pinned.clone(), true);
+    let registries = registries(dir.path(), [user, Registry::default(), registry(&[("nixpkgs", latest.clone())])])?;
+    assert_eq!(registries.resolve(&FlakeRefAttrs::indirect("nixpkgs"))?, pinned);
+    // only the dir is kept by exact entries.
+    assert_eq!(registries.resolve(&FlakeRefAttrs::indirect("nixpkgs").with_dir("lib"))?, pinned.with_dir("lib"));
+    let branch = FlakeRefAttrs::indirect("nixpkgs").with_ref("nixos-unstable");
+    assert_eq!(registries.resolve(&branch)?, latest.with_ref("nixos-unstable"));
+    Ok(())
+  }
+}
@@ -24,7 +24,7 @@ pub mod flakes;
 mod bindings;
 mod utils;
 #[cfg(feature="eval-cache")]
-mod eval_cache;
+pub mod eval_cache;
 #[cfg(feature="derivation")]
 pub mod derivation;
 
@@ -1,8 +1,169 @@
 use nix_for_rust::settings::NixSettings;
 use nix_for_rust::flakes::{FetchersSettings, FlakeLockFlags, FlakeRefSettings, FlakeSettings};
 use nix_for_rust::term::Repr;
+#[cfg(feature="eval-cache")]
+use nix_for_rust::eval_cache::{CacheEntry, CacheLocation, EvalCache, EvalCacheSettings, Invalidation};
+#[cfg(feature="eval-cache")]
+use std::{path::PathBuf, time::Duration};
 
-pub fn main() -> anyhow::Result<()> {
+#[cfg(feature="derivation")]
+fn diff_derivations(old: &str, new: &str) -> anyhow::Result<()> {
+  let nix = NixSettings::default().with_default_store()?;
+  let old = nix.store.parse_derivation(old)?;
+  let new = nix.store.parse_derivation(new)?;
+  let diff = nix_for_rust::derivation::diff(&old, &new, &nix.store)?;
+  print!("{diff}");
+  Ok(())
+}
+
+/// How long ago the unix time `time` was, for humans.
+#[cfg(feature="eval-cache")]
+fn ago(time: i64) -> String {
+  let now = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|d| d.as_secs() as i64)
+    .unwrap_or(0);
+  match (now - time).max(0) {
+    secs if secs < 60 => format!("{secs}s ago"),
+    secs if secs < 3600 => format!("{}min ago", secs / 60),
+    secs if secs < 86400 => format!("{}h ago", secs / 3600),
+    secs => format!("{}d ago", secs / 86400)
+  }
+}
+
+/// Splits `value` into its leading number and the unit after it, which is multiplied by the factor of the unit.
+#[cfg(feature="eval-cache")]
+fn parse_with_unit(value: &str, units: &[(&str, u64)]) -> anyhow::Result<u64> {
+  let (amount, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+  let factor = units
+    .iter()
+    .find_map(|(name, factor)| (*name == unit).then_some(*factor))
+    .ok_or_else(|| anyhow::format_err!("Unknown unit '{unit}' in '{value}'"))?;
+  Ok(amount.parse::<u64>()? * factor)
+}
+
+/// Reads the options of the cache subcommands, returning the settings they use and the remaining arguments.
+///
+/// `--max-age` is in seconds, or in `min`, `h` or `d`, and `--max-size` in bytes, or in `K`, `M` or `G`.
+#[cfg(feature="eval-cache")]
+fn cache_settings<'a>(args: &[&'a str]) -> anyhow::Result<(NixSettings, Vec<&'a str>)> {
+  let mut cache = EvalCacheSettings::default();
+  let mut rest = Vec::new();
+  let mut args = args.iter().copied();
+  while let Some(arg) = args.next() {
+    let mut value = || args.next().ok_or_else(|| anyhow::format_err!("{arg} expects a value"));
+    cache = match arg {
+      "--cache" => cache.with_location(CacheLocation::File(PathBuf::from(value()?))),
+      "--max-age" => {
+        let units = [("", 1), ("s", 1), ("min", 60), ("h", 3600), ("d", 86400)];
+        cache.with_max_age(Duration::from_secs(parse_with_unit(value()?, &units)?))
+      }
+      "--max-size" => {
+        let units = [("", 1), ("K", 1 << 10), ("M", 1 << 20), ("G", 1 << 30)];
+        cache.with_max_size(parse_with_unit(value()?, &units)?)
+      }
+      _ => {
+        rest.push(arg);
+        cache
+      }
+    };
+  }
+  Ok((NixSettings::default().with_eval_cache(cache), rest))
+}
+
+#[cfg(feature="eval-cache")]
+fn print_entry_header(entry: &CacheEntry) {
+  println!(
+    "{} {} (recorded {}, last used {}, {} inputs)",
+    entry.file.display(),
+    entry.accessor_path.join("."),
+    ago(entry.created_at),
+    ago(entry.last_used),
+    entry.inputs.len()
+  );
+}
+
+#[cfg(feature="eval-cache")]
+fn cache_list(settings: &NixSettings) -> anyhow::Result<()> {
+  let cache = EvalCache::open(&settings.eval_cache)?;
+  for entry in cache.entries()? {
+    print_entry_header(&entry);
+  }
+  Ok(())
+}
+
+#[cfg(feature="eval-cache")]
+fn cache_show(settings: &NixSettings, file: &str, attr: &str) -> anyhow::Result<()> {
+  let cache = EvalCache::open(&settings.eval_cache)?;
+  for entry in cache.attr_entries(file, attr.split('.').filter(|a| !a.is_empty()))? {
+    print_entry_header(&entry);
+    println!("  output: {}", entry.output);
+    for input in &entry.inputs {
+      println!("  {} of {} {}", input.kind.as_str(), input.path.display(), input.hash.as_deref().unwrap_or("<no hash>"));
+    }
+    for (name, value) in &entry.settings {
+      println!("  setting {name} = {value}");
+    }
+    for (name, value) in &entry.env {
+      println!("  env {name} = {}", value.as_deref().unwrap_or("<unset>"));
+    }
+  }
+  Ok(())
+}
+
+#[cfg(feature="eval-cache")]
+fn cache_why_invalid(settings: NixSettings, file: &str, attr: &str) -> anyhow::Result<()> {
+  let nix = settings.with_default_store()?;
+  let cache = EvalCache::open(&nix.settings.eval_cache)?;
+  let entries = cache.attr_entries(file, attr.split('.').filter(|a| !a.is_empty()))?;
+  if entries.is_empty() {
+    println!("no entry for {attr} in {file}");
+  }
+  for entry in entries {
+    print_entry_header(&entry);
+    let reasons = cache.why_invalid(&nix, &entry)?;
+    if reasons.is_empty() {
+      println!("  still valid");
+    }
+    for reason in reasons {
+      match reason {
+        Invalidation::FileChanged => println!("  {} changed", entry.file.display()),
+        Invalidation::InputChanged { path, kind } => println!("  {} of {} changed", kind.as_str(), path.display()),
+        Invalidation::InputsChanged => println!("  one of the {} input files changed", entry.inputs.len()),
+        Invalidation::Setting { name, recorded, current } =>
+          println!("  setting {name} changed from {recorded:?} to {current:?}"),
+        Invalidation::Env { name, recorded, current } =>
+          println!("  environment variable {name} changed from {recorded:?} to {current:?}")
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(feature="eval-cache")]
+fn cache_prune(settings: &NixSettings) -> anyhow::Result<()> {
+  let cache = EvalCache::open(&settings.eval_cache)?;
+  let removed = cache.prune()?;
+  println!("removed {removed} entries");
+  Ok(())
+}
+
+#[cfg(feature="eval-cache")]
+fn cache(args: &[&str]) -> anyhow::Result<()> {
+  let (settings, args) = cache_settings(args)?;
+  match args.as_slice() {
+    ["list"] => cache_list(&settings),
+    ["show", file, attr] => cache_show(&settings, file, attr),
+    ["why-invalid", file, attr] => cache_why_invalid(settings, file, attr),
+    ["prune"] => cache_prune(&settings),
+    _ => anyhow::bail!(
+      "usage: nix-for-rust cache [--cache <path>] [--max-age <age>] [--max-size <size>] \
+       (list | show <file> <attr> | why-invalid <file> <attr> | prune)"
+    )
+  }
+}
+
+fn flake_outputs() -> anyhow::Result<()> {
   let mut settings = FlakeSettings::new(FetchersSettings::new()?)?;
 
   let mut flags = FlakeRefSettings::new(settings)?;
@@ -14,9 +175,22 @@ pub fn main() -> anyhow::Result<()> {
     .with_default_store()?;
 
   let mut settings = FlakeSettings::new(FetchersSettings::new()?)?;
-  
+
   let locked = nix.lock_flake(flake_ref, FlakeLockFlags::new(&settings)?)?;
-  
+
   println!("{}", locked.outputs()?.repr()?);
   Ok(())
 }
+
+pub fn main() -> anyhow::Result<()> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+    #[cfg(feature="derivation")]
+    ["diff", old, new] => diff_derivations(old, new),
+    #[cfg(feature="derivation")]
+    ["diff", ..] => anyhow::bail!("usage: nix-for-rust diff <old.drv> <new.drv>"),
+    #[cfg(feature="eval-cache")]
+    ["cache", args @ ..] => cache(args),
+    _ => flake_outputs()
+  }
+}
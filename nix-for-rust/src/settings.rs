@@ -4,7 +4,9 @@ use nix::sys::resource::{Resource, setrlimit, getrlimit};
 
 use crate::eval::{NixEvalState, NixEvalStateBuilder};
 use crate::bindings::{flake_settings_add_to_eval_state_builder, libexpr_init, libstore_init_no_load_config, setting_set};
-use crate::flakes::FlakeSettings;
+use crate::flakes::{FetchersSettings, FlakeSettings};
+#[cfg(feature = "eval-cache")]
+use crate::eval_cache::EvalCacheSettings;
 use crate::store::{NixContext, NixStore};
 
 pub struct NixSettings {
@@ -12,7 +14,9 @@ pub struct NixSettings {
   pub store_params: HashMap<String, String>,
   pub lookup_path: Vec<String>,
   pub flake_settings: Option<FlakeSettings>,
-  pub stack_size: u64
+  pub stack_size: u64,
+  #[cfg(feature = "eval-cache")]
+  pub eval_cache: EvalCacheSettings
 }
 
 fn set_stack_size(new_max: u64) -> nix::Result<()> {
@@ -28,6 +32,8 @@ impl Default for NixSettings {
       store_params: HashMap::default(),
       lookup_path: Vec::default(),
       flake_settings: None,
+      #[cfg(feature = "eval-cache")]
+      eval_cache: EvalCacheSettings::default()
     }
   }
 }
@@ -67,8 +73,26 @@ impl NixSettings {
     self
   }
 
-  pub fn with_store(self, store_path: &str) -> Result<NixEvalState> {
+  #[cfg(feature = "eval-cache")]
+  pub fn with_eval_cache(mut self, settings: EvalCacheSettings) -> Self {
+    self.eval_cache = settings;
+    self
+  }
+
+  /// Whether the `flakes` experimental feature was enabled through the settings.
+  fn flakes_enabled(&self) -> bool {
+    ["experimental-features", "extra-experimental-features"]
+      .iter()
+      .filter_map(|key| self.settings.get(*key))
+      .any(|features| features.split_whitespace().any(|f| f == "flakes"))
+  }
+
+  pub fn with_store(mut self, store_path: &str) -> Result<NixEvalState> {
     set_stack_size(self.stack_size)?;
+
+    if self.flake_settings.is_none() && self.flakes_enabled() {
+      self.flake_settings = Some(FlakeSettings::new(FetchersSettings::new()?)?);
+    }
     
     let ctx = NixContext::default();
     unsafe {
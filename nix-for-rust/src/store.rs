@@ -1,7 +1,7 @@
 use crate::error::{handle_nix_error, NixError};
 use crate::term::NixEvalError;
 use crate::utils::{callback_get_result_string, callback_get_result_string_data, read_into_hashmap};
-use crate::bindings::{c_context, c_context_create, err, err_code, libstore_init_no_load_config, store_copy_closure, store_free, store_get_storedir, store_get_version, store_is_valid_path, store_open, store_parse_path, store_path_free, store_path_name, store_real_path, store_realise, Store, StorePath};
+use crate::bindings::{c_context, c_context_create, err, err_code, libstore_init_no_load_config, store_copy_closure, store_free, store_get_storedir, store_get_uri, store_get_version, store_is_valid_path, store_open, store_parse_path, store_path_free, store_path_name, store_real_path, store_realise, Store, StorePath};
 use std::collections::HashMap;
 use std::ffi::{c_void, CString};
 use std::os::raw::c_char;
@@ -110,6 +110,23 @@ impl NixStore {
     version_string
   }
 
+  pub fn uri(&self) -> Result<String> {
+    let mut uri : Result<String> = Err(anyhow::anyhow!("Nix C API didn't return a string."));
+    unsafe { store_get_uri(self.ctx.ptr(), self.store_ptr(), Some(callback_get_result_string), callback_get_result_string_data(&mut uri)) };
+    self.ctx.check_call()?;
+    uri
+  }
+
+  /// Location of the store path in the filesystem, which differs from the path itself for chroot stores.
+  pub fn real_path(&self, path: &NixStorePath) -> Result<PathBuf> {
+    let mut real_path : Result<String> = Err(anyhow::anyhow!("Nix C API didn't return a string."));
+    unsafe {
+      store_real_path(self.ctx.ptr(), self.store_ptr(), path.as_ptr(), Some(callback_get_result_string), callback_get_result_string_data(&mut real_path))
+    };
+    self.ctx.check_call()?;
+    real_path.map(PathBuf::from)
+  }
+
   pub fn parse_path(&self, path: &str) -> Result<NixStorePath> {
     let c_path = CString::new(path)?;
     let path_ptr = unsafe {
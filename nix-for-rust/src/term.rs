@@ -288,6 +288,14 @@ impl<'state> NixThunk<'state> {
   }
 }
 
+/// Forces `term` if it is a thunk, returning any other term unchanged.
+pub(crate) fn force(term: NixTerm) -> NixResult<NixTerm> {
+  match term {
+    NixTerm::Thunk(thunk) => thunk.force(),
+    term => Ok(term)
+  }
+}
+
 impl<'state> NixFunction<'state> {
   /// Calls the nix function with the argument converted to nix.
   pub fn call_with<T: ToNix<'state>>(&self, arg: T) -> NixResult<NixTerm<'state>> {
@@ -0,0 +1,41 @@
+//! Helpers for the flake tests, which use local flakes and a chroot store in a temporary directory.
+#![allow(dead_code)]
+
+use std::path::Path;
+use nix_for_rust::eval::NixEvalState;
+use nix_for_rust::flakes::{FlakeLockFlags, FlakeRef, FlakeRefSettings, LockMode};
+use nix_for_rust::settings::NixSettings;
+
+pub fn state(root: &Path) -> anyhow::Result<NixEvalState> {
+  NixSettings::default()
+    .with_setting("experimental-features", "flakes")
+    .with_store(&format!("local?root={}", root.join("store").display()))
+}
+
+pub fn parse(state: &NixEvalState, basedir: &Path, uri: &str) -> anyhow::Result<FlakeRef> {
+  let mut settings = FlakeRefSettings::new(state.flake_settings()?.clone())?;
+  settings.set_basedir(basedir)?;
+  settings.parse(uri)
+}
+
+pub fn path_ref(state: &NixEvalState, dir: &Path) -> anyhow::Result<FlakeRef> {
+  parse(state, dir, &format!("path:{}", dir.display()))
+}
+
+pub fn lock_flags(state: &NixEvalState) -> anyhow::Result<FlakeLockFlags> {
+  let mut flags = FlakeLockFlags::new(state.flake_settings()?)?;
+  flags.set_mode(LockMode::WriteAsNeeded)?;
+  Ok(flags)
+}
+
+/// Writes a flake to `dir`, with `inputs` given as pairs of names and flake directories,
+/// and outputs computed by `outputs`, a nix function of the inputs.
+pub fn write_flake(dir: &Path, inputs: &[(&str, &Path)], outputs: &str) -> anyhow::Result<()> {
+  std::fs::create_dir_all(dir)?;
+  let inputs: String = inputs
+    .iter()
+    .map(|(name, path)| format!("inputs.{name}.url = \"path:{}\"; ", path.display()))
+    .collect();
+  std::fs::write(dir.join("flake.nix"), format!("{{ {inputs}outputs = {outputs}; }}"))?;
+  Ok(())
+}
@@ -0,0 +1,83 @@
+//! Walks the derivations in `fixtures/derivations` from a chroot store, where `hello` depends on `fetched`.
+
+use std::path::Path;
+use nix_for_rust::derivation::BuildStatus;
+use nix_for_rust::eval::NixEvalState;
+use nix_for_rust::settings::NixSettings;
+
+const HELLO: &str = "/nix/store/19950ar7hhsc27rrqaq7wv1jfinrfx0f-hello.drv";
+const FETCHED: &str = "/nix/store/s3722ls89xliynw3a6z7ynkhg42rpgpn-fetched.drv";
+
+fn state(root: &Path) -> anyhow::Result<NixEvalState> {
+  let store_dir = root.join("nix/store");
+  std::fs::create_dir_all(&store_dir)?;
+  let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/derivations");
+  for drv in [HELLO, FETCHED] {
+    let name = Path::new(drv).file_name().unwrap();
+    std::fs::copy(fixtures.join(name), store_dir.join(name))?;
+  }
+  NixSettings::default().with_store(&format!("local?root={}", root.display()))
+}
+
+/// Adds the output of `fetched`, a flat fixed-output derivation of `hello\n`, to the store.
+fn add_fetched_output(state: &NixEvalState, root: &Path) -> anyhow::Result<()> {
+  let file = root.join("hello");
+  std::fs::write(&file, "hello\n")?;
+  let expr = format!(r#"toString (builtins.path {{
+    path = {};
+    name = "fetched";
+    recursive = false;
+    sha256 = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+  }})"#, file.display());
+  let path = state.eval_string(&expr, root.to_path_buf())?.as_string()?;
+  assert_eq!(path, "/nix/store/pi0hrnj8zhpb7hbxf7iyp184v2hc9zra-fetched");
+  Ok(())
+}
+
+#[test]
+fn walks_input_derivations() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let state = state(root.path())?;
+  let graph = state.store.derivation_graph(HELLO)?;
+  assert_eq!(graph.root, HELLO);
+  assert_eq!(graph.nodes.keys().collect::<Vec<_>>(), vec![HELLO, FETCHED]);
+  let hello = &graph.nodes[HELLO];
+  assert_eq!(hello.name, "hello");
+  assert_eq!(hello.status, BuildStatus::Missing);
+  assert_eq!(hello.inputs[FETCHED].iter().collect::<Vec<_>>(), vec!["out"]);
+  assert_eq!(
+    graph.nodes[FETCHED].outputs["out"].path.as_deref(),
+    Some("/nix/store/pi0hrnj8zhpb7hbxf7iyp184v2hc9zra-fetched")
+  );
+  assert_eq!(graph.to_dot(), format!(concat!(
+    "digraph derivations {{\n",
+    "  node [shape=box];\n",
+    "  \"{hello}\" [label=\"hello\", color=red];\n",
+    "  \"{fetched}\" [label=\"fetched\", color=red];\n",
+    "  \"{hello}\" -> \"{fetched}\" [label=\"out\"];\n",
+    "}}\n"
+  ), hello = HELLO, fetched = FETCHED));
+  Ok(())
+}
+
+#[test]
+fn builds_inputs_first() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let state = state(root.path())?;
+  let plan = state.store.build_plan(HELLO)?;
+  assert_eq!(plan.to_build, vec![FETCHED, HELLO]);
+  assert!(plan.already_valid.is_empty());
+  Ok(())
+}
+
+#[test]
+fn valid_inputs_are_not_built() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let state = state(root.path())?;
+  add_fetched_output(&state, root.path())?;
+  let plan = state.store.build_plan(HELLO)?;
+  assert_eq!(plan.to_build, vec![HELLO]);
+  assert_eq!(plan.already_valid, vec![FETCHED]);
+  assert_eq!(plan.graph.nodes[FETCHED].status, BuildStatus::Valid);
+  Ok(())
+}
@@ -0,0 +1,190 @@
+//! Checks that cached evaluations are invalidated when the files they accessed change,
+//! for every kind of access the tracers record.
+
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use nix_for_rust::eval_cache::{CachedValue, CacheLocation, EvalCache, EvalCacheSettings, EvaluationError, TracerBackend};
+use nix_for_rust::term::ToNix;
+use nix_for_rust::settings::NixSettings;
+
+const TRACERS: [TracerBackend; 2] = [TracerBackend::Ptrace, TracerBackend::Seccomp];
+
+fn cache_settings(tracer: TracerBackend) -> EvalCacheSettings {
+  EvalCacheSettings::new()
+    .with_tracer(tracer)
+    .with_location(CacheLocation::InMemory)
+}
+
+/// Evaluates `file`, which must return a string, through the cache.
+fn eval(file: &Path, tracer: TracerBackend) -> anyhow::Result<String> {
+  let state = NixSettings::default()
+    .with_eval_cache(cache_settings(tracer))
+    .with_store("dummy://")?;
+  let result = state.eval_attr_from_file(file, Vec::<String>::new())?.as_string()?;
+  Ok(result)
+}
+
+/// Ids of the cache entries of `file`, which change whenever it is evaluated again.
+fn entry_ids(file: &Path, tracer: TracerBackend) -> anyhow::Result<Vec<i64>> {
+  let entries = EvalCache::open(&cache_settings(tracer))?.attr_entries(file, Vec::<String>::new())?;
+  Ok(entries.iter().map(|entry| entry.id).collect())
+}
+
+/// Evaluates `expr` in a fresh directory prepared by `setup`, then checks that the cached result
+/// is reused, and that it is invalidated after `mutate` changes that directory.
+fn assert_invalidated<S, M>(expr: &str, setup: S, mutate: M, before: &str, after: &str) -> anyhow::Result<()>
+where S: Fn(&Path) -> std::io::Result<()>, M: Fn(&Path) -> std::io::Result<()> {
+  for tracer in TRACERS {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("default.nix");
+    std::fs::write(&file, expr)?;
+    setup(dir.path())?;
+    assert_eq!(eval(&file, tracer)?, before, "{tracer:?}");
+    let cached = entry_ids(&file, tracer)?;
+    assert_eq!(cached.len(), 1, "{tracer:?}");
+    assert_eq!(eval(&file, tracer)?, before, "{tracer:?}");
+    assert_eq!(entry_ids(&file, tracer)?, cached, "{tracer:?} didn't reuse the cached result");
+    mutate(dir.path())?;
+    assert_eq!(eval(&file, tracer)?, after, "{tracer:?}");
+  }
+  Ok(())
+}
+
+#[test]
+fn path_exists() -> anyhow::Result<()> {
+  assert_invalidated(
+    "builtins.toJSON (builtins.pathExists ./flag)",
+    |_| Ok(()),
+    |dir| std::fs::write(dir.join("flag"), ""),
+    "false",
+    "true"
+  )
+}
+
+#[test]
+fn readlink() -> anyhow::Result<()> {
+  assert_invalidated(
+    "import ./link.nix",
+    |dir| {
+      std::fs::write(dir.join("a.nix"), "\"a\"")?;
+      std::fs::write(dir.join("b.nix"), "\"b\"")?;
+      symlink("a.nix", dir.join("link.nix"))
+    },
+    |dir| {
+      std::fs::remove_file(dir.join("link.nix"))?;
+      symlink("b.nix", dir.join("link.nix"))
+    },
+    "a",
+    "b"
+  )
+}
+
+#[test]
+fn read_dir() -> anyhow::Result<()> {
+  assert_invalidated(
+    "builtins.concatStringsSep \",\" (builtins.attrNames (builtins.readDir ./dir))",
+    |dir| {
+      std::fs::create_dir(dir.join("dir"))?;
+      std::fs::write(dir.join("dir/a"), "")
+    },
+    |dir| std::fs::write(dir.join("dir/b"), ""),
+    "a",
+    "a,b"
+  )
+}
+
+/// Files are opened and closed one after the other, so their descriptors are reused,
+/// and the directory listed last must not be mistaken for one of the files.
+#[test]
+fn reused_file_descriptors() -> anyhow::Result<()> {
+  let expr = r#"
+    let
+      first = builtins.readFile ./first;
+      second = builtins.readFile ./second;
+      entries = builtins.attrNames (builtins.readDir ./dir);
+    in builtins.seq first (builtins.seq second (builtins.concatStringsSep "," ([ first second ] ++ entries)))
+  "#;
+  let setup = |dir: &Path| {
+    std::fs::write(dir.join("first"), "1")?;
+    std::fs::write(dir.join("second"), "2")?;
+    std::fs::create_dir(dir.join("dir"))?;
+    std::fs::write(dir.join("dir/a"), "")
+  };
+  assert_invalidated(expr, setup, |dir| std::fs::write(dir.join("second"), "22"), "1,2,a", "1,22,a")?;
+  assert_invalidated(expr, setup, |dir| std::fs::write(dir.join("dir/b"), ""), "1,2,a", "1,2,a,b")
+}
+
+/// `getEnv` doesn't do any syscall, and here it is only used in an imported file, through an alias,
+/// so the evaluation depends on the whole environment. Changing it would race with the tests that fork,
+/// so only the recorded variables are checked.
+#[test]
+fn environment_variables_of_imported_files() -> anyhow::Result<()> {
+  for tracer in TRACERS {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("default.nix");
+    std::fs::write(&file, "import ./env.nix")?;
+    std::fs::write(dir.path().join("env.nix"), "let get = builtins.getEnv; in get \"NIX_FOR_RUST_UNSET_VARIABLE\"")?;
+    assert_eq!(eval(&file, tracer)?, "", "{tracer:?}");
+    let entries = EvalCache::open(&cache_settings(tracer))?.attr_entries(&file, Vec::<String>::new())?;
+    let env_vars: Vec<Vec<&String>> = entries.iter().map(|entry| entry.env.keys().collect()).collect();
+    assert_eq!(env_vars, [["*"]], "{tracer:?}");
+  }
+  Ok(())
+}
+
+/// Cached derivations are restored with the context needed to build them.
+#[test]
+fn derivations_are_restored_with_their_context() -> anyhow::Result<()> {
+  let drv_path = "/nix/store/0c9qhz9dhl6ldr2k1i8m0rv5s2a9b6w2-hello.drv";
+  let cached = CachedValue::Derivation {
+    name: "hello".to_string(),
+    drv_path: drv_path.to_string(),
+    out_path: "/nix/store/a9b6w20c9qhz9dhl6ldr2k1i8m0rv5s2-hello".to_string(),
+    output_name: "out".to_string()
+  };
+  let state = NixSettings::default().with_store("dummy://")?;
+  let restored = cached.to_nix(&state)?;
+  let describe = state.eval_string(
+    "drv: builtins.toJSON [ drv.type drv.name (builtins.getContext drv.drvPath) (builtins.getContext drv.outPath) ]",
+    std::env::current_dir()?
+  )?;
+  let expected = format!(r#"["derivation","hello",{{"{drv_path}":{{"allOutputs":true}}}},{{"{drv_path}":{{"outputs":["out"]}}}}]"#);
+  assert_eq!(describe.call_with(restored)?.as_string()?, expected);
+  Ok(())
+}
+
+/// Evaluates `file` through the cache, returning the error of the evaluation.
+fn eval_error(file: &Path, cache_failures: bool) -> anyhow::Result<EvaluationError> {
+  let state = NixSettings::default()
+    .with_eval_cache(cache_settings(TracerBackend::Ptrace).with_cache_failures(cache_failures))
+    .with_store("dummy://")?;
+  let error = match state.eval_attr_from_file(file, ["value"]) {
+    Ok(_) => anyhow::bail!("evaluating {file:?} should fail"),
+    Err(e) => e
+  };
+  Ok(error.downcast::<EvaluationError>()?)
+}
+
+#[test]
+fn deterministic_failures_are_cached() -> anyhow::Result<()> {
+  let dir = tempfile::tempdir()?;
+  let file = dir.path().join("default.nix");
+  std::fs::write(&file, "{ value = throw \"boom\"; }")?;
+  let attr_entries = || -> anyhow::Result<Vec<i64>> {
+    let entries = EvalCache::open(&cache_settings(TracerBackend::Ptrace))?.attr_entries(&file, ["value"])?;
+    Ok(entries.iter().map(|entry| entry.id).collect())
+  };
+
+  let error = eval_error(&file, false)?;
+  assert_eq!(error.name.as_deref(), Some("nix::ThrownError"));
+  assert!(error.message.contains("boom"), "{error:?}");
+  assert!(error.deterministic);
+  assert!(attr_entries()?.is_empty(), "failures are only cached when enabled");
+
+  assert_eq!(eval_error(&file, true)?, error);
+  let cached = attr_entries()?;
+  assert_eq!(cached.len(), 1);
+  assert_eq!(eval_error(&file, true)?, error);
+  assert_eq!(attr_entries()?, cached, "the cached failure wasn't returned");
+  Ok(())
+}
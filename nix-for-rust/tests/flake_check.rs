@@ -0,0 +1,109 @@
+//! Inventory and checks of the outputs of a local flake.
+
+mod common;
+
+use std::path::Path;
+use nix_for_rust::eval::NixEvalState;
+use nix_for_rust::flakes::{CheckStatus, LockedFlake, OutputKind};
+use common::{lock_flags, path_ref, state, write_flake};
+
+const OUTPUTS: &str = r#"_: let
+    drv = name: attrs: derivation ({ inherit name; system = "x86_64-linux"; builder = "/bin/sh"; } // attrs);
+  in {
+    packages.x86_64-linux = {
+      hello = drv "hello" {} // { meta.description = "Says hello"; };
+      broken = throw "broken package";
+      uninstantiable = drv "uninstantiable" { env = throw "broken attribute"; };
+      notDerivation = { name = "hello"; };
+    };
+    checks.x86_64-linux.hello = drv "hello-check" {};
+    formatter.x86_64-linux = drv "formatter" {};
+    apps.x86_64-linux = {
+      default = { type = "app"; program = "/bin/sh"; };
+      noProgram = { type = "app"; };
+    };
+    overlays.default = final: prev: {};
+    templates.default = { path = ./.; description = "A template"; };
+    unknown = 1;
+  }"#;
+
+fn locked_flake<'state>(state: &'state NixEvalState, dir: &Path) -> anyhow::Result<LockedFlake<'state>> {
+  write_flake(dir, &[], OUTPUTS)?;
+  state.lock_flake(path_ref(state, dir)?, lock_flags(state)?)
+}
+
+#[test]
+fn inventory_lists_outputs_of_the_standard_schema() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let state = state(root.path())?;
+  let inventory = locked_flake(&state, &root.path().join("flake"))?.inventory()?;
+  let attr_paths: Vec<_> = inventory.iter().map(|entry| entry.attr_path.join(".")).collect();
+  assert_eq!(attr_paths, [
+    "apps.x86_64-linux.default",
+    "apps.x86_64-linux.noProgram",
+    "checks.x86_64-linux.hello",
+    "formatter.x86_64-linux",
+    "overlays.default",
+    "packages.x86_64-linux.broken",
+    "packages.x86_64-linux.hello",
+    "packages.x86_64-linux.notDerivation",
+    "packages.x86_64-linux.uninstantiable",
+    "templates.default"
+  ]);
+
+  let entry = |attr_path: &str| inventory.iter().find(|entry| entry.attr_path.join(".") == attr_path).unwrap();
+  let hello = entry("packages.x86_64-linux.hello");
+  assert_eq!(hello.kind, OutputKind::Package);
+  assert_eq!(hello.name, "hello");
+  assert_eq!(hello.system.as_deref(), Some("x86_64-linux"));
+  assert_eq!(hello.derivation_name.as_deref(), Some("hello"));
+  assert_eq!(hello.description.as_deref(), Some("Says hello"));
+  assert_eq!(hello.error, None);
+
+  let formatter = entry("formatter.x86_64-linux");
+  assert_eq!((formatter.kind, formatter.name.as_str()), (OutputKind::Formatter, "x86_64-linux"));
+  assert_eq!(formatter.derivation_name.as_deref(), Some("formatter"));
+  assert_eq!(entry("templates.default").description.as_deref(), Some("A template"));
+  assert_eq!(entry("packages.x86_64-linux.notDerivation").derivation_name, None);
+  assert!(entry("packages.x86_64-linux.broken").error.as_ref().is_some_and(|e| e.contains("broken package")));
+  // overlays are only listed, so they can't fail.
+  assert_eq!(entry("overlays.default").error, None);
+  Ok(())
+}
+
+#[test]
+fn check_instantiates_derivations() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let state = state(root.path())?;
+  let report = locked_flake(&state, &root.path().join("flake"))?.check(None)?;
+  let status = |attr_path: &str| {
+    report.results
+      .iter()
+      .find(|result| result.attr_path.join(".") == attr_path)
+      .map(|result| result.status.clone())
+      .unwrap()
+  };
+  let failure = |attr_path: &str| match status(attr_path) {
+    CheckStatus::Failed(error) => error,
+    status => panic!("expected {attr_path} to fail, got {status:?}")
+  };
+
+  for valid in [
+    "apps.x86_64-linux.default",
+    "checks.x86_64-linux.hello",
+    "formatter.x86_64-linux",
+    "overlays.default",
+    "packages.x86_64-linux.hello",
+    "templates.default"
+  ] {
+    assert_eq!(status(valid), CheckStatus::Valid, "{valid}");
+  }
+  assert!(failure("packages.x86_64-linux.broken").contains("broken package"));
+  assert!(failure("packages.x86_64-linux.uninstantiable").contains("broken attribute"));
+  assert!(failure("packages.x86_64-linux.notDerivation").contains("expected a derivation"));
+  assert!(failure("apps.x86_64-linux.noProgram").contains("program"));
+  assert_eq!(report.results.len(), 10);
+  assert_eq!(report.failures().count(), 4);
+  assert!(!report.success());
+  Ok(())
+}
@@ -0,0 +1,66 @@
+//! Locks local `path:` flakes in a temporary directory, using a chroot store.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use nix_for_rust::flakes::lock::LockFile;
+use common::{lock_flags, path_ref, state, write_flake};
+
+/// Writes a flake with a single `dep` input, and the `dep` flake itself, returning their directories.
+fn write_flakes(root: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+  let (flake, dep) = (root.join("flake"), root.join("dep"));
+  write_flake(&dep, &[], "_: { value = 1; }")?;
+  write_flake(&flake, &[("dep", &dep)], "{ dep, ... }: { value = dep.value; }")?;
+  Ok((flake, dep))
+}
+
+#[test]
+fn writes_the_lock_file() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let (flake, _) = write_flakes(root.path())?;
+  let state = state(root.path())?;
+
+  let locked = state.lock_flake(path_ref(&state, &flake)?, lock_flags(&state)?)?;
+  let lock_file = LockFile::read(flake.join("flake.lock"))?;
+  assert_eq!(locked.lock_file()?, Some(&lock_file));
+  assert!(lock_file.resolve(&["dep".to_string()]).is_ok());
+  assert!(locked.lock_file_diff()?.changes.contains_key("dep"));
+  assert_eq!(locked.outputs()?.get("value")?.as_int()?, 1);
+  Ok(())
+}
+
+#[test]
+fn updates_an_input() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let (flake, dep) = write_flakes(root.path())?;
+  let state = state(root.path())?;
+  state.lock_flake(path_ref(&state, &flake)?, lock_flags(&state)?)?;
+
+  write_flake(&dep, &[], "_: { value = 2; }")?;
+  let mut flags = lock_flags(&state)?;
+  flags.update_input("dep");
+  let locked = state.lock_flake(path_ref(&state, &flake)?, flags)?;
+  assert!(locked.lock_file_diff()?.changes.contains_key("dep"));
+  assert_eq!(locked.outputs()?.get("value")?.as_int()?, 2);
+  Ok(())
+}
+
+#[test]
+fn restores_the_lock_file_when_locking_fails() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let (flake, _) = write_flakes(root.path())?;
+  let state = state(root.path())?;
+  state.lock_flake(path_ref(&state, &flake)?, lock_flags(&state)?)?;
+  let lock_path = flake.join("flake.lock");
+  let original = std::fs::read(&lock_path)?;
+
+  std::fs::write(flake.join("flake.nix"), "{ inputs.dep.url = ")?;
+  for recreate in [false, true] {
+    let mut flags = lock_flags(&state)?;
+    flags.update_input("dep");
+    flags.recreate_lock_file(recreate);
+    assert!(state.lock_flake(path_ref(&state, &flake)?, flags).is_err());
+    assert_eq!(std::fs::read(&lock_path)?, original);
+  }
+  Ok(())
+}
@@ -0,0 +1,106 @@
+//! Metadata of local flakes, and of flakes resolved through a local flake registry.
+
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+use serde_json::Value;
+use nix_for_rust::eval::NixEvalState;
+use nix_for_rust::flakes::lock::LockFile;
+use nix_for_rust::flakes::registry::Registry;
+use nix_for_rust::flakes::{FlakeLockFlags, FlakeMetadata, FlakeRefAttrs, LockMode};
+use nix_for_rust::settings::NixSettings;
+use common::{lock_flags, parse, path_ref, state, write_flake};
+
+/// Registry id that is unlikely to be in the user's own registry.
+const ID: &str = "nix-for-rust-test-flake";
+
+#[test]
+fn indirect_flakes_are_resolved_and_locked_through_the_registry() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let flake = root.path().join("flake");
+  write_flake(&flake, &[], "_: { value = 1; }")?;
+  let registry_path = root.path().join("registry.json");
+  let mut registry = Registry::default();
+  registry.add(FlakeRefAttrs::indirect(ID), FlakeRefAttrs::local(&flake), false);
+  registry.write(&registry_path)?;
+  let state = NixSettings::default()
+    .with_setting("experimental-features", "flakes")
+    .with_setting("flake-registry", &registry_path.to_string_lossy())
+    .with_store(&format!("local?root={}", root.path().join("store").display()))?;
+
+  let mut flags = FlakeLockFlags::new(state.flake_settings()?)?;
+  flags.set_mode(LockMode::Check)?;
+  let locked = state.lock_flake(parse(&state, root.path(), &format!("flake:{ID}"))?, flags)?;
+  let metadata = locked.metadata()?;
+  assert_eq!(metadata.original, FlakeRefAttrs::indirect(ID));
+  assert_eq!(metadata.resolved, FlakeRefAttrs::local(&flake));
+  assert_eq!(metadata.locked.ref_type(), Some("path"));
+  assert_eq!(metadata.locked.path(), Some(flake.to_string_lossy().as_ref()));
+  assert_eq!(metadata.locked.nar_hash(), metadata.nar_hash.as_deref());
+  assert!(metadata.locked.nar_hash().is_some());
+  Ok(())
+}
+
+/// Runs git in `dir`, returning its trimmed output.
+fn git(dir: &Path, args: &[&str]) -> anyhow::Result<String> {
+  let output = Command::new("git")
+    .arg("-C")
+    .arg(dir)
+    .args(["-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "commit.gpgsign=false"])
+    .args(args)
+    .output()?;
+  anyhow::ensure!(output.status.success(), "git {args:?} failed: {}", String::from_utf8_lossy(&output.stderr));
+  Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Locks the flake in `dir` in the default, virtual, mode, in which the lock result can't be observed.
+fn virtual_metadata(state: &NixEvalState, dir: &Path) -> anyhow::Result<FlakeMetadata> {
+  let flags = FlakeLockFlags::new(state.flake_settings()?)?;
+  state.lock_flake(parse(state, dir, ".")?, flags)?.metadata()
+}
+
+#[test]
+fn local_flakes_in_git_repositories_are_locked_to_a_revision() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let (repo, dep) = (root.path().join("repo"), root.path().join("dep"));
+  write_flake(&dep, &[], "_: { value = 1; }")?;
+  write_flake(&repo, &[("dep", &dep)], "{ dep, ... }: { value = dep.value; }")?;
+  let state = state(root.path())?;
+  // writes the lock file, which is committed along with the flake.
+  state.lock_flake(path_ref(&state, &repo)?, lock_flags(&state)?)?;
+  git(&repo, &["init", "--quiet"])?;
+  git(&repo, &["add", "."])?;
+  git(&repo, &["commit", "--quiet", "--message", "init"])?;
+  let rev = git(&repo, &["rev-parse", "HEAD"])?;
+
+  let metadata = virtual_metadata(&state, &repo)?;
+  assert_eq!(metadata.locked.ref_type(), Some("git"));
+  assert_eq!(metadata.locked.rev(), Some(rev.as_str()));
+  assert_eq!(metadata.locked.get("revCount"), Some(&Value::from(1)));
+  assert_eq!(metadata.locked.nar_hash(), metadata.nar_hash.as_deref());
+  assert!(metadata.locked.last_modified().is_some());
+  assert!(metadata.locked_url.starts_with("git+file://"), "{}", metadata.locked_url);
+  assert_eq!(metadata.lock_file, Some(LockFile::read(repo.join("flake.lock"))?));
+  assert!(metadata.inputs.contains_key("dep"));
+
+  write_flake(&repo, &[("dep", &dep)], "{ dep, ... }: { value = dep.value + 1; }")?;
+  let dirty = virtual_metadata(&state, &repo)?;
+  assert_eq!(dirty.locked.rev(), None);
+  assert_eq!(dirty.locked.get_str("dirtyRev"), Some(format!("{rev}-dirty").as_str()));
+  Ok(())
+}
+
+#[test]
+fn flakes_without_a_lock_file_have_no_inputs() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let flake = root.path().join("flake");
+  write_flake(&flake, &[], "_: { value = 1; }")?;
+  let state = state(root.path())?;
+
+  let metadata = virtual_metadata(&state, &flake)?;
+  assert_eq!(metadata.locked.ref_type(), Some("path"));
+  assert_eq!(metadata.lock_file, None);
+  assert!(metadata.inputs.is_empty());
+  Ok(())
+}
@@ -0,0 +1,93 @@
+//! Input overrides and `follows` on local `path:` flakes.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use nix_for_rust::flakes::InputOverrides;
+use nix_for_rust::flakes::lock::{parse_input_path, Input, LockFile};
+use common::{lock_flags, path_ref, state, write_flake};
+
+/// A flake with a `nixpkgs` input and a `foo` input that has its own, different, `nixpkgs` input.
+/// Its `value` output is the `value` of `foo`'s `nixpkgs`.
+fn write_flakes(root: &Path) -> anyhow::Result<PathBuf> {
+  let (flake, foo) = (root.join("flake"), root.join("foo"));
+  let (nixpkgs, foo_nixpkgs) = (root.join("nixpkgs"), root.join("foo-nixpkgs"));
+  write_flake(&nixpkgs, &[], "_: { value = \"root\"; }")?;
+  write_flake(&foo_nixpkgs, &[], "_: { value = \"foo\"; }")?;
+  write_flake(&foo, &[("nixpkgs", &foo_nixpkgs)], "{ nixpkgs, ... }: { value = nixpkgs.value; }")?;
+  write_flake(&flake, &[("nixpkgs", &nixpkgs), ("foo", &foo)], "{ foo, ... }: { value = foo.value; }")?;
+  Ok(flake)
+}
+
+#[test]
+fn overrides_an_input() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let flake = write_flakes(root.path())?;
+  let other = root.path().join("other");
+  write_flake(&other, &[], "_: { value = \"other\"; }")?;
+  let state = state(root.path())?;
+
+  let mut flags = lock_flags(&state)?;
+  flags.set_overrides(InputOverrides::new().override_input("foo/nixpkgs", &format!("path:{}", other.display()))?);
+  let locked = state.lock_flake(path_ref(&state, &flake)?, flags)?;
+  assert_eq!(locked.outputs()?.get("value")?.as_string()?, "other");
+  Ok(())
+}
+
+#[test]
+fn follows_are_recorded_in_the_lock_file() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let flake = write_flakes(root.path())?;
+  let state = state(root.path())?;
+  state.lock_flake(path_ref(&state, &flake)?, lock_flags(&state)?)?;
+
+  let mut flags = lock_flags(&state)?;
+  flags.set_overrides(InputOverrides::new().follows("foo/nixpkgs", "nixpkgs")?);
+  let locked = state.lock_flake(path_ref(&state, &flake)?, flags)?;
+  assert_eq!(locked.outputs()?.get("value")?.as_string()?, "root");
+
+  let lock_file = LockFile::read(flake.join("flake.lock"))?;
+  let foo = lock_file.node(&lock_file.resolve(&parse_input_path("foo"))?)?;
+  assert_eq!(foo.inputs["nixpkgs"], Input::Follows(parse_input_path("nixpkgs")));
+  assert_eq!(lock_file.resolve(&parse_input_path("foo/nixpkgs"))?, lock_file.resolve(&parse_input_path("nixpkgs"))?);
+  // the node that was locked for foo's own nixpkgs is gone.
+  assert_eq!(lock_file.nodes.len(), 3);
+  assert_eq!(locked.lock_file()?, Some(&lock_file));
+  Ok(())
+}
+
+#[test]
+fn follows_require_a_locked_target() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let flake = write_flakes(root.path())?;
+  let state = state(root.path())?;
+
+  let mut flags = lock_flags(&state)?;
+  flags.set_overrides(InputOverrides::new().follows("foo/nixpkgs", "nixpkgs")?);
+  let error = state.lock_flake(path_ref(&state, &flake)?, flags).err().expect("locking should fail");
+  assert!(error.to_string().contains("not locked yet"), "{error}");
+  assert!(!flake.join("flake.lock").exists());
+  Ok(())
+}
+
+#[test]
+fn lock_file_is_restored_when_recording_follows_fails() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let flake = write_flakes(root.path())?;
+  let state = state(root.path())?;
+  state.lock_flake(path_ref(&state, &flake)?, lock_flags(&state)?)?;
+  let original = std::fs::read(flake.join("flake.lock"))?;
+
+  // nix ignores overrides of inputs that don't exist, but they can't be recorded as follows.
+  for update in [true, false] {
+    let mut flags = lock_flags(&state)?;
+    if update {
+      flags.update_input("nixpkgs");
+    }
+    flags.set_overrides(InputOverrides::new().follows("foo/missing", "nixpkgs")?);
+    let error = state.lock_flake(path_ref(&state, &flake)?, flags).err().expect("locking should fail");
+    assert!(error.to_string().contains("foo/missing"), "{error}");
+    assert_eq!(std::fs::read(flake.join("flake.lock"))?, original, "update: {update}");
+  }
+  Ok(())
+}
@@ -0,0 +1,36 @@
+//! Flake reference attributes, as parsed by nix.
+
+mod common;
+
+use common::{parse, state};
+
+#[test]
+fn local_flakes_in_a_git_repository_are_git_refs() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let repo = root.path().join("repo");
+  std::fs::create_dir_all(repo.join(".git"))?;
+  std::fs::create_dir_all(repo.join("sub"))?;
+  std::fs::write(repo.join("sub/flake.nix"), "{ outputs = _: { }; }")?;
+  let state = state(root.path())?;
+
+  let attrs = parse(&state, &repo.join("sub"), ".#hello")?.attrs(&state)?;
+  assert_eq!(attrs.ref_type(), Some("git"));
+  assert_eq!(attrs.url(), Some(format!("file://{}", repo.display()).as_str()));
+  assert_eq!(attrs.dir(), Some("sub"));
+  Ok(())
+}
+
+#[test]
+fn github_refs_keep_their_query() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let state = state(root.path())?;
+
+  let flake_ref = parse(&state, root.path(), "github:NixOS/nixpkgs?ref=nixos-unstable&dir=lib#lib")?;
+  let attrs = flake_ref.attrs(&state)?;
+  assert_eq!(attrs.ref_type(), Some("github"));
+  assert_eq!((attrs.owner(), attrs.repo()), (Some("NixOS"), Some("nixpkgs")));
+  assert_eq!((attrs.git_ref(), attrs.dir()), (Some("nixos-unstable"), Some("lib")));
+  assert_eq!(flake_ref.fragment, "lib");
+  assert_eq!(flake_ref.to_url(&state)?, "github:NixOS/nixpkgs/nixos-unstable?dir=lib");
+  Ok(())
+}
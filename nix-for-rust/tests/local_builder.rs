@@ -0,0 +1,36 @@
+//! Builds a derivation with [`LocalBuilder`] in a chroot store, whose paths
+//! don't exist at their logical location on the host.
+
+use nix_for_rust::derivation::LocalBuilder;
+use nix_for_rust::settings::NixSettings;
+
+const GREETING: &str = r#"
+derivation {
+  name = "greeting";
+  system = builtins.currentSystem;
+  builder = "/bin/sh";
+  args = [ (builtins.toFile "builder.sh" "read line < $src; echo \"$line, world\" > $out") ];
+  src = builtins.toFile "greeting.txt" "hello\n";
+}
+"#;
+
+#[test]
+fn builds_in_a_chroot_store() -> anyhow::Result<()> {
+  let root = tempfile::tempdir()?;
+  let state = NixSettings::default()
+    .with_store(&format!("local?root={}", root.path().display()))?;
+  let drv_path = state
+    .eval_string(GREETING, root.path().to_path_buf())?
+    .get("drvPath")?
+    .as_string()?;
+  let drv = state.store.parse_derivation(&drv_path)?;
+
+  let result = LocalBuilder::new(&state.store).build(&drv)?;
+  assert!(result.success(), "build failed:\n{}", result.log);
+
+  let out = state.store.parse_path(&result.outputs["out"])?;
+  assert!(state.store.is_valid_path(&out)?);
+  let contents = std::fs::read_to_string(state.store.real_path(&out)?)?;
+  assert_eq!(contents, "hello, world\n");
+  Ok(())
+}